@@ -1,5 +1,5 @@
 use crate::config::ConfigError;
-use rustls::{Certificate, RootCertStore};
+use rustls::{Certificate, PrivateKey, RootCertStore};
 use rustls_pemfile::Item;
 use std::{
     fs::{self, File},
@@ -48,6 +48,43 @@ pub fn load_certificates(mut files: Vec<String>) -> Result<RootCertStore, Config
     Ok(certs)
 }
 
+pub fn load_certificate_chain(path: &str) -> Result<Vec<Certificate>, ConfigError> {
+    let mut file =
+        BufReader::new(File::open(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?);
+    let mut certs = Vec::new();
+
+    while let Ok(Some(item)) = rustls_pemfile::read_one(&mut file) {
+        if let Item::X509Certificate(cert) = item {
+            certs.push(Certificate(cert));
+        }
+    }
+
+    if certs.is_empty() {
+        certs.push(Certificate(
+            fs::read(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?,
+        ));
+    }
+
+    Ok(certs)
+}
+
+pub fn load_private_key(path: &str) -> Result<PrivateKey, ConfigError> {
+    let mut file =
+        BufReader::new(File::open(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?);
+    let mut priv_key = None;
+
+    while let Ok(Some(item)) = rustls_pemfile::read_one(&mut file) {
+        if let Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) = item {
+            priv_key = Some(key);
+        }
+    }
+
+    priv_key
+        .map(Ok)
+        .unwrap_or_else(|| fs::read(path).map_err(|err| ConfigError::Io(path.to_owned(), err)))
+        .map(PrivateKey)
+}
+
 use rustls::client::{ServerCertVerified, ServerCertVerifier};
 
 pub struct SkipVerify;
@@ -1,6 +1,7 @@
 use crate::{
     certificate,
-    relay::{ServerAddr, UdpRelayMode},
+    logging::LogFormat,
+    relay::{Credential, ServerAddr, UdpRelayMode},
 };
 use getopts::{Fail, Options};
 use log::{LevelFilter, ParseLevelError};
@@ -8,7 +9,7 @@ use quinn::{
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
     ClientConfig, TransportConfig,
 };
-use rustls::{version::TLS13, ClientConfig as RustlsClientConfig};
+use rustls::{version::TLS13, ClientConfig as RustlsClientConfig, Error as RustlsError};
 use serde::{de::Error as DeError, Deserialize, Deserializer};
 use serde_json::Error as JsonError;
 use socks5_server::{
@@ -26,12 +27,13 @@ use std::{
     sync::Arc,
 };
 use thiserror::Error;
+use uuid::{Error as UuidError, Uuid};
 use webpki::Error as WebpkiError;
 
 pub struct Config {
     pub client_config: ClientConfig,
     pub server_addr: ServerAddr,
-    pub token_digest: [u8; 32],
+    pub credential: Credential,
     pub udp_relay_mode: UdpRelayMode<(), ()>,
     pub heartbeat_interval: u64,
     pub reduce_rtt: bool,
@@ -40,6 +42,7 @@ pub struct Config {
     pub local_addr: SocketAddr,
     pub socks5_auth: Arc<dyn Auth + Send + Sync>,
     pub log_level: LevelFilter,
+    pub log_format: LogFormat,
 }
 
 impl Config {
@@ -47,23 +50,41 @@ impl Config {
         let raw = RawConfig::parse(args)?;
 
         unsafe { crate::FAST = raw.relay.fast_connect };
+        let has_client_certificate = raw.relay.client_certificate.is_some();
 
         let client_config = {
+            let client_auth = match (raw.relay.client_certificate, raw.relay.client_key) {
+                (Some(cert), Some(key)) => Some((
+                    certificate::load_certificate_chain(&cert)?,
+                    certificate::load_private_key(&key)?,
+                )),
+                (None, None) => None,
+                _ => return Err(ConfigError::ClientCertificate),
+            };
+
             let mut crypto = if raw.relay.insecure {
                 eprintln!("warning: insecure enabled");
-                RustlsClientConfig::builder()
+                let builder = RustlsClientConfig::builder()
                     .with_safe_defaults()
-                    .with_custom_certificate_verifier(Arc::new(certificate::SkipVerify))
-                    .with_no_client_auth()
+                    .with_custom_certificate_verifier(Arc::new(certificate::SkipVerify));
+
+                match client_auth {
+                    Some((certs, key)) => builder.with_single_cert(certs, key)?,
+                    None => builder.with_no_client_auth(),
+                }
             } else {
                 let certs = certificate::load_certificates(raw.relay.certificates)?;
-                RustlsClientConfig::builder()
+                let builder = RustlsClientConfig::builder()
                     .with_safe_default_cipher_suites()
                     .with_safe_default_kx_groups()
                     .with_protocol_versions(&[&TLS13])
                     .unwrap()
-                    .with_root_certificates(certs)
-                    .with_no_client_auth()
+                    .with_root_certificates(certs);
+
+                match client_auth {
+                    Some((certs, key)) => builder.with_single_cert(certs, key)?,
+                    None => builder.with_no_client_auth(),
+                }
             };
 
             crypto.alpn_protocols = raw
@@ -112,7 +133,16 @@ impl Config {
             }
         };
 
-        let token_digest = *blake3::hash(&raw.relay.token.unwrap().into_bytes()).as_bytes();
+        let credential = match (raw.relay.token, raw.relay.uuid, raw.relay.password) {
+            (Some(token), None, None) => Credential::V4 {
+                token_digest: *blake3::hash(&token.into_bytes()).as_bytes(),
+                session_bound: raw.relay.session_bound_authentication,
+            },
+            (None, Some(uuid), Some(password)) => Credential::V5 { uuid, password },
+            (None, None, None) if has_client_certificate => Credential::Certificate,
+            _ => return Err(ConfigError::Credential),
+        };
+
         let udp_relay_mode = raw.relay.udp_relay_mode;
         let heartbeat_interval = raw.relay.heartbeat_interval;
         let reduce_rtt = raw.relay.reduce_rtt;
@@ -131,11 +161,12 @@ impl Config {
         };
 
         let log_level = raw.log_level;
+        let log_format = raw.log_format;
 
         Ok(Self {
             client_config,
             server_addr,
-            token_digest,
+            credential,
             udp_relay_mode,
             heartbeat_interval,
             reduce_rtt,
@@ -144,6 +175,7 @@ impl Config {
             local_addr,
             socks5_auth,
             log_level,
+            log_format,
         })
     }
 }
@@ -156,6 +188,12 @@ struct RawConfig {
 
     #[serde(default = "default::log_level")]
     log_level: LevelFilter,
+
+    #[serde(
+        default = "default::log_format",
+        deserialize_with = "deserialize_from_str"
+    )]
+    log_format: LogFormat,
 }
 
 #[derive(Deserialize)]
@@ -164,11 +202,19 @@ struct RawRelayConfig {
     server: Option<String>,
     port: Option<u16>,
     token: Option<String>,
+    uuid: Option<Uuid>,
+    password: Option<String>,
     ip: Option<IpAddr>,
 
+    #[serde(default)]
+    session_bound_authentication: bool,
+
     #[serde(default = "default::certificates")]
     certificates: Vec<String>,
 
+    client_certificate: Option<String>,
+    client_key: Option<String>,
+
     #[serde(default)]
     insecure: bool,
 
@@ -224,6 +270,7 @@ impl Default for RawConfig {
             relay: RawRelayConfig::default(),
             local: RawLocalConfig::default(),
             log_level: default::log_level(),
+            log_format: default::log_format(),
         }
     }
 }
@@ -235,8 +282,13 @@ impl Default for RawRelayConfig {
             port: None,
             ip: None,
             token: None,
+            uuid: None,
+            password: None,
+            session_bound_authentication: false,
             insecure: false,
             certificates: default::certificates(),
+            client_certificate: None,
+            client_key: None,
             udp_relay_mode: default::udp_relay_mode(),
             congestion_controller: default::congestion_controller(),
             heartbeat_interval: default::heartbeat_interval(),
@@ -284,10 +336,30 @@ impl RawConfig {
         opts.optopt(
             "",
             "token",
-            "Set the token for TUIC authentication",
+            "Set the token for TUIC authentication. The client speaks TUIC v4 with a token",
             "TOKEN",
         );
 
+        opts.optopt(
+            "",
+            "uuid",
+            "Set the UUID for TUIC authentication. The client speaks TUIC v5 with a UUID and a password",
+            "UUID",
+        );
+
+        opts.optopt(
+            "",
+            "password",
+            "Set the password for TUIC authentication",
+            "PASSWORD",
+        );
+
+        opts.optflag(
+            "",
+            "session-bound-authentication",
+            "Bind the TUIC v4 token digest to the TLS session, so a captured digest can not be replayed. The server must support it",
+        );
+
         opts.optopt(
             "",
             "server-ip",
@@ -302,6 +374,20 @@ impl RawConfig {
             "CERTIFICATE",
         );
 
+        opts.optopt(
+            "",
+            "client-certificate",
+            "Set the X.509 certificate chain to authenticate to the server with. The token, or the UUID and the password can be omitted if the server maps the certificate to a user",
+            "CLIENT_CERTIFICATE",
+        );
+
+        opts.optopt(
+            "",
+            "client-key",
+            "Set the private key of the client certificate",
+            "CLIENT_KEY",
+        );
+
         opts.optflag("", "insecure", "Skip certificate verification");
 
         opts.optopt(
@@ -392,6 +478,13 @@ impl RawConfig {
 
         #[cfg(unix)]
         opts.optflag("d", "daemon", "Daemonize");
+        opts.optopt(
+            "",
+            "log-format",
+            r#"Set the log format. Available: "text", "json". Default: "text""#,
+            "LOG_FORMAT",
+        );
+
         opts.optflag("v", "version", "Print the version");
         opts.optflag("h", "help", "Print this help menu");
 
@@ -425,6 +518,8 @@ impl RawConfig {
         let server = matches.opt_str("server");
         let server_port = matches.opt_str("server-port").map(|port| port.parse());
         let token = matches.opt_str("token");
+        let uuid = matches.opt_str("uuid").map(|uuid| uuid.parse());
+        let password = matches.opt_str("password");
         let local_port = matches.opt_str("local-port").map(|port| port.parse());
 
         let mut raw = if let Some(path) = matches.opt_str("config") {
@@ -443,11 +538,9 @@ impl RawConfig {
                     .ok_or(ConfigError::MissingOption("server port"))?,
             );
 
-            raw.relay.token = Some(
-                token
-                    .or(raw.relay.token)
-                    .ok_or(ConfigError::MissingOption("token"))?,
-            );
+            raw.relay.token = token.or(raw.relay.token);
+            raw.relay.uuid = uuid.transpose()?.or(raw.relay.uuid);
+            raw.relay.password = password.or(raw.relay.password);
 
             raw.local.port = Some(
                 local_port
@@ -461,7 +554,9 @@ impl RawConfig {
             let relay = RawRelayConfig {
                 server: Some(server.ok_or(ConfigError::MissingOption("server address"))?),
                 port: Some(server_port.ok_or(ConfigError::MissingOption("server port"))??),
-                token: Some(token.ok_or(ConfigError::MissingOption("token"))?),
+                token,
+                uuid: uuid.transpose()?,
+                password,
                 ..Default::default()
             };
 
@@ -487,6 +582,14 @@ impl RawConfig {
             raw.relay.certificates = certificates;
         }
 
+        if let Some(path) = matches.opt_str("client-certificate") {
+            raw.relay.client_certificate = Some(path);
+        }
+
+        if let Some(path) = matches.opt_str("client-key") {
+            raw.relay.client_key = Some(path);
+        }
+
         if matches.opt_present("insecure") {
             raw.relay.insecure = true;
         }
@@ -509,6 +612,8 @@ impl RawConfig {
             raw.relay.alpn = alpn;
         }
 
+        raw.relay.session_bound_authentication |=
+            matches.opt_present("session-bound-authentication");
         raw.relay.disable_sni |= matches.opt_present("disable-sni");
         raw.relay.reduce_rtt |= matches.opt_present("reduce-rtt");
         raw.relay.fast_connect |= matches.opt_present("fast-connect");
@@ -532,6 +637,10 @@ impl RawConfig {
             raw.log_level = log_level.parse()?;
         };
 
+        if let Some(log_format) = matches.opt_str("log-format") {
+            raw.log_format = log_format.parse()?;
+        };
+
         Ok(raw)
     }
 
@@ -578,6 +687,20 @@ impl FromStr for UdpRelayMode<(), ()> {
     }
 }
 
+impl FromStr for LogFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("text") {
+            Ok(Self::Text)
+        } else if s.eq_ignore_ascii_case("json") {
+            Ok(Self::Json)
+        } else {
+            Err(ConfigError::InvalidLogFormat)
+        }
+    }
+}
+
 fn deserialize_from_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     T: FromStr,
@@ -634,6 +757,10 @@ mod default {
     pub(super) const fn log_level() -> LevelFilter {
         LevelFilter::Info
     }
+
+    pub(super) const fn log_format() -> LogFormat {
+        LogFormat::Text
+    }
 }
 
 #[derive(Error, Debug)]
@@ -660,6 +787,8 @@ pub enum ConfigError {
     InvalidCongestionController,
     #[error("Invalid udp relay mode")]
     InvalidUdpRelayMode,
+    #[error("Invalid log format")]
+    InvalidLogFormat,
     #[error("Failed to load the certificate: {0}")]
     Certificate(#[from] WebpkiError),
     #[error("Could not load platform certs: {0}")]
@@ -668,4 +797,12 @@ pub enum ConfigError {
     LocalAuthentication,
     #[error(transparent)]
     ParseLogLevel(#[from] ParseLevelError),
+    #[error(transparent)]
+    ParseUuid(#[from] UuidError),
+    #[error("Either a token, a UUID and a password, or a client certificate must be set for TUIC authentication")]
+    Credential,
+    #[error("The client certificate and its private key must be set together")]
+    ClientCertificate,
+    #[error("Failed to load the client certificate / private key: {0}")]
+    Rustls(#[from] RustlsError),
 }
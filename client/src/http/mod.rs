@@ -8,7 +8,10 @@ use http::header::{HeaderMap, HeaderName, HeaderValue};
 use http::request::{Parts, Request};
 use http::{Method, Version};
 
+use tuic_protocol::Reply;
+
 use crate::relay::Address as ProxyAddress;
+use crate::relay::BiStream;
 use crate::relay::Request as ProxyRequest;
 use crate::FAST;
 
@@ -51,10 +54,10 @@ pub async fn handle(stream: &mut TcpStream, req_tx: Sender<ProxyRequest>) -> Res
         &addr
     );
 
-    let (req, rx) = ProxyRequest::new_connect(addr, unsafe { FAST });
-    let _ = req_tx.send(req).await;
-
-    let mut quic_stream = rx.await.map_err(map_io_err)?;
+    let mut quic_stream = match connect(req_tx, addr).await {
+        Ok(quic_stream) => quic_stream,
+        Err(reply) => return write_error_response(stream, reply).await,
+    };
     quic_stream.write_all(&buf[..m]).await?;
     realm_io::bidi_copy(stream, &mut quic_stream).await?;
 
@@ -72,10 +75,10 @@ async fn handle_connect(
         &addr
     );
 
-    let (req, rx) = ProxyRequest::new_connect(addr, unsafe { FAST });
-    let _ = req_tx.send(req).await;
-
-    let mut quic_stream = rx.await.map_err(map_io_err)?;
+    let mut quic_stream = match connect(req_tx, addr).await {
+        Ok(quic_stream) => quic_stream,
+        Err(reply) => return write_error_response(stream, reply).await,
+    };
     stream
         .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
         .await?;
@@ -83,6 +86,37 @@ async fn handle_connect(
     realm_io::bidi_copy(stream, &mut quic_stream).await
 }
 
+/// `None` means the request never reached the server
+async fn connect(
+    req_tx: Sender<ProxyRequest>,
+    addr: ProxyAddress,
+) -> std::result::Result<BiStream, Option<Reply>> {
+    let (req, rx) = ProxyRequest::new_connect(addr, unsafe { FAST });
+    let _ = req_tx.send(req).await;
+
+    match rx.await {
+        Ok(Ok(quic_stream)) => Ok(quic_stream),
+        Ok(Err(reply)) => Err(Some(reply)),
+        Err(_) => Err(None),
+    }
+}
+
+async fn write_error_response(stream: &mut TcpStream, reply: Option<Reply>) -> Result<()> {
+    let (status, reason) = match reply {
+        Some(Reply::TimedOut) => ("504 Gateway Timeout", Reply::TimedOut.to_string()),
+        Some(reply) => ("502 Bad Gateway", reply.to_string()),
+        None => ("503 Service Unavailable", "relay unavailable".to_owned()),
+    };
+
+    let resp = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{reason}\n",
+        reason.len() + 1
+    );
+
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await
+}
+
 fn rm_proxy_hdrs(headers: &mut HeaderMap) {
     headers.remove("proxy-connection");
     headers.remove("proxy-authenticate");
@@ -5,6 +5,7 @@ use mimalloc::MiMalloc;
 mod certificate;
 mod config;
 mod http;
+mod logging;
 mod relay;
 mod socks5;
 
@@ -28,12 +29,7 @@ fn main() {
         }
     };
 
-    env_logger::builder()
-        .filter_level(config.log_level)
-        .format_level(true)
-        .format_target(false)
-        .format_module_path(false)
-        .init();
+    logging::init(config.log_level, config.log_format);
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -46,7 +42,7 @@ async fn run(config: Config) {
     let (relay, req_tx) = relay::init(
         config.client_config,
         config.server_addr,
-        config.token_digest,
+        config.credential,
         config.heartbeat_interval,
         config.reduce_rtt,
         config.udp_relay_mode,
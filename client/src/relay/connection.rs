@@ -2,7 +2,7 @@ use super::{
     incoming::{self, Sender as IncomingSender},
     request::Wait as WaitRequest,
     stream::{BiStream, IncomingUniStreams, RecvStream, Register as StreamRegister, SendStream},
-    Address, ServerAddr, UdpRelayMode,
+    Address, Credential, ServerAddr, UdpRelayMode,
 };
 use bytes::Bytes;
 use parking_lot::Mutex;
@@ -15,7 +15,7 @@ use std::{
     ops::{Deref, DerefMut},
     pin::Pin,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU16, Ordering},
         Arc,
     },
     task::{Context, Poll, Waker},
@@ -26,7 +26,10 @@ use tokio::{
     sync::{mpsc::Sender as MpscSender, Mutex as AsyncMutex, OwnedMutexGuard},
     time,
 };
-use tuic_protocol::Command;
+use tracing::{Instrument, Span};
+use tuic_protocol::{
+    v5, Command, SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN, SESSION_BOUND_AUTH_LABEL,
+};
 
 fn my_client(addr: SocketAddr) -> Result<Endpoint> {
     let socket = std::net::UdpSocket::bind(addr)?;
@@ -104,12 +107,16 @@ pub async fn manage_connection(
             break new_conn;
         };
 
-        log::debug!("[relay] [connection] [establish]");
+        new_conn
+            .span()
+            .in_scope(|| log::debug!("[relay] [connection] [establish]"));
 
         // wait for the connection to be closed, lock the mutex
         new_conn.wait_close().await;
 
-        log::debug!("[relay] [connection] [disconnect]");
+        new_conn
+            .span()
+            .in_scope(|| log::debug!("[relay] [connection] [disconnect]"));
         lock = Some(conn.clone().lock_owned().await);
     }
 }
@@ -120,8 +127,13 @@ pub struct Connection {
     udp_sessions: Arc<UdpSessionMap>,
     stream_reg: Arc<StreamRegister>,
     udp_relay_mode: UdpRelayMode<(), ()>,
+    is_v5: bool,
+    next_pkt_id: Arc<AtomicU16>,
+    reassembler: Arc<Mutex<v5::Reassembler>>,
     is_closed: IsClosed,
     default_max_udp_relay_packet_size: usize,
+    /// The parent of the spans of the requests relayed on the connection
+    span: Span,
 }
 
 impl Connection {
@@ -178,42 +190,105 @@ impl Connection {
             conn.await?
         };
 
-        let conn = Self::new(connection.clone(), config).await;
+        let conn = Self::new(connection.clone(), addr, config).await;
         let uni_streams =
             IncomingUniStreams::new(connection.clone(), conn.stream_reg.get_registry());
 
         Ok((conn, connection, uni_streams))
     }
 
-    async fn new(conn: QuinnConnection, config: &ConnectionConfig) -> Self {
+    async fn new(conn: QuinnConnection, addr: SocketAddr, config: &ConnectionConfig) -> Self {
+        let span = tracing::info_span!(
+            parent: None,
+            "connection",
+            connection_id = conn.stable_id(),
+            remote_addr = %addr,
+        );
+
         let conn = Self {
             controller: conn,
             udp_sessions: Arc::new(UdpSessionMap::new()),
             stream_reg: Arc::new(StreamRegister::new()),
             udp_relay_mode: config.udp_relay_mode,
+            is_v5: matches!(config.credential, Credential::V5 { .. }),
+            next_pkt_id: Arc::new(AtomicU16::new(0)),
+            reassembler: Arc::new(Mutex::new(v5::Reassembler::new(
+                MAX_PARTIAL_PACKETS,
+                u16::MAX as usize,
+                MAX_PARTIAL_PACKET_BYTES,
+                PARTIAL_PACKET_TIMEOUT,
+            ))),
             is_closed: IsClosed::new(),
             default_max_udp_relay_packet_size: config.max_udp_relay_packet_size,
+            span,
         };
 
-        // send auth
-        tokio::spawn(Self::send_authentication(conn.clone(), config.token_digest));
+        // send auth, unless the client certificate authenticates the connection
+        if !matches!(config.credential, Credential::Certificate) {
+            tokio::spawn(
+                Self::send_authentication(conn.clone(), config.credential.clone())
+                    .instrument(conn.span.clone()),
+            );
+        }
 
         // heartbeat
-        tokio::spawn(Self::heartbeat(conn.clone(), config.heartbeat_interval));
+        tokio::spawn(
+            Self::heartbeat(conn.clone(), config.heartbeat_interval).instrument(conn.span.clone()),
+        );
 
         conn
     }
 
-    async fn send_authentication(self, token_digest: [u8; 32]) {
-        async fn send_token(conn: &Connection, token_digest: [u8; 32]) -> Result<()> {
+    async fn send_authentication(self, credential: Credential) {
+        async fn send_token(conn: &Connection, credential: Credential) -> Result<()> {
             let mut send = conn.get_send_stream().await?;
-            let cmd = Command::new_authenticate(token_digest);
-            cmd.write_to(&mut send).await?;
+
+            match credential {
+                Credential::V4 {
+                    token_digest,
+                    session_bound: false,
+                } => {
+                    let cmd = Command::new_authenticate(token_digest);
+                    cmd.write_to(&mut send).await?;
+                }
+                Credential::V4 {
+                    token_digest,
+                    session_bound: true,
+                } => {
+                    let mut keying_material = [0; SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN];
+
+                    conn.controller
+                        .export_keying_material(&mut keying_material, SESSION_BOUND_AUTH_LABEL, &[])
+                        .map_err(|_| {
+                            Error::new(ErrorKind::Other, "Failed to export keying material")
+                        })?;
+
+                    let digest =
+                        tuic_protocol::session_bound_digest(&token_digest, &keying_material);
+                    let cmd = Command::new_authenticate(digest);
+                    cmd.write_to(&mut send).await?;
+                }
+                Credential::V5 { uuid, password } => {
+                    // the token is bound to the TLS session, so it can not be replayed on another connection
+                    let mut token = [0; 32];
+
+                    conn.controller
+                        .export_keying_material(&mut token, uuid.as_bytes(), password.as_bytes())
+                        .map_err(|_| {
+                            Error::new(ErrorKind::Other, "Failed to export keying material")
+                        })?;
+
+                    let cmd = v5::Command::new_authenticate(*uuid.as_bytes(), token);
+                    cmd.write_to(&mut send).await?;
+                }
+                Credential::Certificate => unreachable!(),
+            }
+
             send.finish().await?;
             Ok(())
         }
 
-        match send_token(&self, token_digest).await {
+        match send_token(&self, credential).await {
             Ok(()) => log::debug!("[relay] [connection] [authentication]"),
             Err(err) => log::warn!("[relay] [connection] [authentication] {err}"),
         }
@@ -221,6 +296,13 @@ impl Connection {
 
     async fn heartbeat(self, heartbeat_interval: u64) {
         async fn send_heartbeat(conn: &Connection) -> Result<()> {
+            if conn.is_v5 {
+                let cmd = v5::Command::new_heartbeat();
+                let mut buf = Vec::with_capacity(cmd.serialized_len());
+                cmd.write_to_buf(&mut buf)?;
+                return conn.send_datagram(Bytes::from(buf));
+            }
+
             let mut send = conn.get_send_stream().await?;
             let cmd = Command::new_heartbeat();
             cmd.write_to(&mut send).await?;
@@ -273,6 +355,26 @@ impl Connection {
         self.udp_relay_mode
     }
 
+    pub fn is_v5(&self) -> bool {
+        self.is_v5
+    }
+
+    pub fn next_pkt_id(&self) -> u16 {
+        self.next_pkt_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        self.controller.max_datagram_size()
+    }
+
+    pub fn reassembler(&self) -> &Mutex<v5::Reassembler> {
+        self.reassembler.deref()
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
     pub fn update_max_udp_relay_packet_size(&self) {
         let size = match self.udp_relay_mode {
             UdpRelayMode::Native(()) => match self.controller.max_datagram_size() {
@@ -308,7 +410,7 @@ impl Connection {
 pub struct ConnectionConfig {
     quinn_config: ClientConfig,
     server_addr: ServerAddr,
-    token_digest: [u8; 32],
+    credential: Credential,
     udp_relay_mode: UdpRelayMode<(), ()>,
     heartbeat_interval: u64,
     reduce_rtt: bool,
@@ -319,7 +421,7 @@ impl ConnectionConfig {
     pub fn new(
         quinn_config: ClientConfig,
         server_addr: ServerAddr,
-        token_digest: [u8; 32],
+        credential: Credential,
         udp_relay_mode: UdpRelayMode<(), ()>,
         heartbeat_interval: u64,
         reduce_rtt: bool,
@@ -328,7 +430,7 @@ impl ConnectionConfig {
         Self {
             quinn_config,
             server_addr,
-            token_digest,
+            credential,
             udp_relay_mode,
             heartbeat_interval,
             reduce_rtt,
@@ -337,6 +439,10 @@ impl ConnectionConfig {
     }
 }
 
+const MAX_PARTIAL_PACKETS: usize = 256;
+const MAX_PARTIAL_PACKET_BYTES: usize = 4 * 1024 * 1024;
+const PARTIAL_PACKET_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct UdpSessionMap(Mutex<HashMap<u32, MpscSender<(Bytes, Address)>>>);
 
 impl UdpSessionMap {
@@ -13,7 +13,8 @@ use tokio::{
     io::AsyncReadExt,
     sync::oneshot::{self, error::RecvError, Receiver as OneshotReceiver, Sender as OneshotSender},
 };
-use tuic_protocol::Command as TuicCommand;
+use tracing::Instrument;
+use tuic_protocol::{v5, AnyCommand, Command as TuicCommand, Decoded};
 
 pub async fn listen_incoming(
     mut next_incoming_rx: UdpRelayMode<Receiver<Datagrams>, Receiver<IncomingUniStreams>>,
@@ -47,7 +48,8 @@ pub async fn listen_incoming(
                 };
 
                 // process datagram
-                tokio::spawn(conn.clone().process_incoming_datagram(pkt));
+                let span = tracing::info_span!(parent: conn.span(), "datagram");
+                tokio::spawn(conn.clone().process_incoming_datagram(pkt).instrument(span));
             },
             UdpRelayMode::Quic(mut uni) => loop {
                 let recv = match uni.next().await {
@@ -57,15 +59,20 @@ pub async fn listen_incoming(
                 };
 
                 // process uni stream
-                tokio::spawn(conn.clone().process_incoming_uni_stream(recv));
+                let span = tracing::info_span!(parent: conn.span(), "stream");
+                tokio::spawn(
+                    conn.clone()
+                        .process_incoming_uni_stream(recv)
+                        .instrument(span),
+                );
             },
         };
 
-        match err {
+        conn.span().in_scope(|| match err {
             ConnectionError::LocallyClosed => log::debug!("[relay] [connection] Locally closed"),
             ConnectionError::TimedOut => log::debug!("[relay] [connection] Timeout"),
             err => log::error!("[relay] [connection] {err}"),
-        }
+        });
 
         conn.set_closed();
     }
@@ -73,20 +80,48 @@ pub async fn listen_incoming(
 
 impl Connection {
     async fn process_incoming_datagram(self, pkt: Bytes) {
-        async fn parse_header(pkt: Bytes) -> Result<(u32, Bytes, Address)> {
-            let cmd = TuicCommand::read_from(&mut pkt.as_ref()).await?;
-            let cmd_len = cmd.serialized_len();
+        fn parse_header(conn: &Connection, pkt: Bytes) -> Result<Option<(u32, Bytes, Address)>> {
+            let (cmd, cmd_len) = match AnyCommand::decode(&pkt)? {
+                Decoded::Complete(cmd, len) => (cmd, len),
+                Decoded::Incomplete(_) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+            };
 
             match cmd {
-                TuicCommand::Packet {
+                AnyCommand::V4(TuicCommand::Packet {
                     assoc_id,
                     len,
                     addr,
-                } => Ok((
+                }) => {
+                    if pkt.len() < cmd_len + len as usize {
+                        return Err(Error::from(ErrorKind::UnexpectedEof));
+                    }
+
+                    Ok(Some((
+                        assoc_id,
+                        pkt.slice(cmd_len..cmd_len + len as usize),
+                        Address::from(addr),
+                    )))
+                }
+                AnyCommand::V5(v5::Command::Packet {
                     assoc_id,
-                    pkt.slice(cmd_len..cmd_len + len as usize),
-                    Address::from(addr),
-                )),
+                    pkt_id,
+                    frag_total,
+                    frag_id,
+                    size,
+                    addr,
+                }) => {
+                    if pkt.len() < cmd_len + size as usize {
+                        return Err(Error::from(ErrorKind::UnexpectedEof));
+                    }
+
+                    let frag = pkt.slice(cmd_len..cmd_len + size as usize);
+                    let pkt = conn
+                        .reassembler()
+                        .lock()
+                        .insert(assoc_id, pkt_id, frag_total, frag_id, addr, frag)?;
+
+                    Ok(pkt.map(|(addr, pkt)| (assoc_id as u32, pkt, Address::from(addr))))
+                }
                 _ => Err(Error::new(
                     ErrorKind::InvalidData,
                     "[relay] [connection] Unexpected incoming datagram",
@@ -94,26 +129,49 @@ impl Connection {
             }
         }
 
-        match parse_header(pkt).await {
-            Ok((assoc_id, pkt, addr)) => self.handle_packet_from(assoc_id, pkt, addr).await,
+        match parse_header(&self, pkt) {
+            Ok(Some((assoc_id, pkt, addr))) => self.handle_packet_from(assoc_id, pkt, addr).await,
+            Ok(None) => {}
             Err(err) => log::warn!("[relay] [connection] {err}"),
         }
     }
 
     async fn process_incoming_uni_stream(self, recv: RecvStream) {
-        async fn parse_header(mut recv: RecvStream) -> Result<(u32, Bytes, Address)> {
-            let cmd = TuicCommand::read_from(&mut recv).await?;
+        async fn parse_header(
+            conn: &Connection,
+            mut recv: RecvStream,
+        ) -> Result<Option<(u32, Bytes, Address)>> {
+            let cmd = AnyCommand::read_from(&mut recv).await?;
 
             match cmd {
-                TuicCommand::Packet {
+                AnyCommand::V4(TuicCommand::Packet {
                     assoc_id,
                     len,
                     addr,
-                } => {
+                }) => {
                     let mut buf = vec![0; len as usize];
                     recv.read_exact(&mut buf).await?;
                     let pkt = Bytes::from(buf);
-                    Ok((assoc_id, pkt, Address::from(addr)))
+                    Ok(Some((assoc_id, pkt, Address::from(addr))))
+                }
+                AnyCommand::V5(v5::Command::Packet {
+                    assoc_id,
+                    pkt_id,
+                    frag_total,
+                    frag_id,
+                    size,
+                    addr,
+                }) => {
+                    let mut buf = vec![0; size as usize];
+                    recv.read_exact(&mut buf).await?;
+                    let frag = Bytes::from(buf);
+
+                    let pkt = conn
+                        .reassembler()
+                        .lock()
+                        .insert(assoc_id, pkt_id, frag_total, frag_id, addr, frag)?;
+
+                    Ok(pkt.map(|(addr, pkt)| (assoc_id as u32, pkt, Address::from(addr))))
                 }
                 _ => Err(Error::new(
                     ErrorKind::InvalidData,
@@ -122,8 +180,9 @@ impl Connection {
             }
         }
 
-        match parse_header(recv).await {
-            Ok((assoc_id, pkt, addr)) => self.handle_packet_from(assoc_id, pkt, addr).await,
+        match parse_header(&self, recv).await {
+            Ok(Some((assoc_id, pkt, addr))) => self.handle_packet_from(assoc_id, pkt, addr).await,
+            Ok(None) => {}
             Err(err) => log::warn!("[relay] [connection] {err}"),
         }
     }
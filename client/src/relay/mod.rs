@@ -4,14 +4,18 @@ use std::{
     fmt::{Display, Formatter, Result as FmtResult},
     future::Future,
     net::SocketAddr,
-    sync::{atomic::AtomicUsize, Arc},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 use tokio::sync::{
     mpsc::{self, Sender},
     Mutex as AsyncMutex,
 };
+use uuid::Uuid;
 
-pub use self::{address::Address, connection::Connection, request::Request};
+pub use self::{address::Address, connection::Connection, request::Request, stream::BiStream};
 
 mod address;
 mod connection;
@@ -26,7 +30,7 @@ pub static MAX_UDP_RELAY_PACKET_SIZE: AtomicUsize = AtomicUsize::new(1500);
 pub async fn init(
     quinn_config: ClientConfig,
     server_addr: ServerAddr,
-    token_digest: [u8; 32],
+    credential: Credential,
     heartbeat_interval: u64,
     reduce_rtt: bool,
     udp_relay_mode: UdpRelayMode<(), ()>,
@@ -35,10 +39,15 @@ pub async fn init(
 ) -> (impl Future<Output = ()>, Sender<Request>) {
     let (req_tx, req_rx) = mpsc::channel(1);
 
+    // TUIC v5 associate IDs are 16 bits long
+    if let Credential::V5 { .. } = credential {
+        request::ASSOC_ID_MASK.store(u16::MAX as u32, Ordering::Release);
+    }
+
     let config = ConnectionConfig::new(
         quinn_config,
         server_addr.clone(),
-        token_digest,
+        credential,
         udp_relay_mode,
         heartbeat_interval,
         reduce_rtt,
@@ -93,6 +102,21 @@ impl Display for ServerAddr {
     }
 }
 
+/// The credential used for authentication, which also decides the TUIC version spoken
+#[derive(Clone)]
+pub enum Credential {
+    V4 {
+        token_digest: [u8; 32],
+        session_bound: bool,
+    },
+    V5 {
+        uuid: Uuid,
+        password: String,
+    },
+    /// Authenticated by the client certificate in the TLS handshake, speaking TUIC v4
+    Certificate,
+}
+
 #[derive(Clone, Copy)]
 pub enum UdpRelayMode<N, Q> {
     Native(N),
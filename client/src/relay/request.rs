@@ -7,7 +7,10 @@ use std::{
     fmt::{Display, Formatter, Result as FmtResult},
     future::Future,
     pin::Pin,
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Weak,
+    },
     task::{Context, Poll, Waker},
     time::Duration,
 };
@@ -19,6 +22,8 @@ use tokio::{
     },
     time,
 };
+use tracing::{Instrument, Span};
+use tuic_protocol::Reply;
 
 pub fn listen_requests(
     conn: Arc<AsyncMutex<Option<Connection>>>,
@@ -49,30 +54,34 @@ async fn process_request(
         let conn = lock.as_ref().unwrap().clone(); // safety: there must be a connection if the lock is aquirable
         drop(lock);
 
-        match req {
-            Request::Connect { addr, tx, fast } => {
-                conn.clone().handle_connect(addr, tx, fast).await
-            }
-            Request::Associate {
-                assoc_id,
-                mut pkt_send_rx,
-                pkt_recv_tx,
-            } => {
-                conn.udp_sessions().insert(assoc_id, pkt_recv_tx);
-                while let Some((pkt, addr)) = pkt_send_rx.recv().await {
-                    tokio::spawn(conn.clone().handle_packet_to(
-                        assoc_id,
-                        pkt,
-                        addr,
-                        conn.udp_relay_mode(),
-                    ));
+        let span = req.span(conn.span());
+        let task = async move {
+            match req {
+                Request::Connect { addr, tx, fast } => {
+                    conn.clone().handle_connect(addr, tx, fast).await
+                }
+                Request::Associate {
+                    assoc_id,
+                    mut pkt_send_rx,
+                    pkt_recv_tx,
+                } => {
+                    conn.udp_sessions().insert(assoc_id, pkt_recv_tx);
+                    while let Some((pkt, addr)) = pkt_send_rx.recv().await {
+                        tokio::spawn(
+                            conn.clone()
+                                .handle_packet_to(assoc_id, pkt, addr, conn.udp_relay_mode())
+                                .in_current_span(),
+                        );
+                    }
+
+                    log::info!("[relay] [task] [dissociate] [{assoc_id}]");
+                    conn.clone().udp_sessions().remove(&assoc_id);
+                    conn.handle_dissociate(assoc_id).await;
                 }
-
-                log::info!("[relay] [task] [dissociate] [{assoc_id}]");
-                conn.clone().udp_sessions().remove(&assoc_id);
-                conn.handle_dissociate(assoc_id).await;
             }
-        }
+        };
+
+        task.instrument(span).await;
     } else {
         log::warn!("[relay] [task] {req} [timeout]");
     }
@@ -91,8 +100,8 @@ pub enum Request {
     },
 }
 
-type ConnectResponseSender = OneshotSender<BiStream>;
-type ConnectResponseReceiver = OneshotReceiver<BiStream>;
+type ConnectResponseSender = OneshotSender<Result<BiStream, Reply>>;
+type ConnectResponseReceiver = OneshotReceiver<Result<BiStream, Reply>>;
 type AssociateSendPacketSender = MpscSender<(Bytes, Address)>;
 type AssociateSendPacketReceiver = MpscReceiver<(Bytes, Address)>;
 type AssociateRecvPacketSender = MpscSender<(Bytes, Address)>;
@@ -105,7 +114,7 @@ impl Request {
     }
 
     pub fn new_associate() -> (Self, AssociateSendPacketSender, AssociateRecvPacketReceiver) {
-        let assoc_id = get_random_u32();
+        let assoc_id = get_random_u32() & ASSOC_ID_MASK.load(Ordering::Acquire);
         let (pkt_send_tx, pkt_send_rx) = mpsc::channel(1);
         let (pkt_recv_tx, pkt_recv_rx) = mpsc::channel(1);
 
@@ -119,6 +128,24 @@ impl Request {
             pkt_recv_rx,
         )
     }
+
+    /// The span of the request, a child of the span of the connection relaying it
+    fn span(&self, conn: &Span) -> Span {
+        match self {
+            Request::Connect { addr, fast, .. } => tracing::info_span!(
+                parent: conn,
+                "request",
+                method = if *fast { "connect2" } else { "connect" },
+                destination = %addr,
+            ),
+            Request::Associate { assoc_id, .. } => tracing::info_span!(
+                parent: conn,
+                "request",
+                method = "associate",
+                assoc_id,
+            ),
+        }
+    }
 }
 
 impl Display for Request {
@@ -134,6 +161,8 @@ impl Display for Request {
     }
 }
 
+pub static ASSOC_ID_MASK: AtomicU32 = AtomicU32::new(u32::MAX);
+
 static RNG: Lazy<Mutex<StdRng>> = Lazy::new(|| Mutex::new(StdRng::from_entropy()));
 
 fn get_random_u32() -> u32 {
@@ -1,19 +1,31 @@
 use super::{stream::BiStream, Address, Connection, UdpRelayMode};
 use bytes::{Bytes, BytesMut};
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use tokio::{io::AsyncWriteExt, sync::oneshot::Sender as OneshotSender};
-use tuic_protocol::{Address as TuicAddress, Command as TuicCommand};
+use tuic_protocol::{v5, Address as TuicAddress, Command as TuicCommand, Reply};
 
 impl Connection {
-    pub async fn handle_connect(self, addr: Address, tx: OneshotSender<BiStream>, fast: bool) {
+    pub async fn handle_connect(
+        self,
+        addr: Address,
+        tx: OneshotSender<std::result::Result<BiStream, Reply>>,
+        fast: bool,
+    ) {
         async fn negotiate_connect(
             conn: Connection,
             addr: Address,
             fast: bool,
-        ) -> Result<Option<BiStream>> {
-            let cmd = TuicCommand::new_connect(TuicAddress::from(addr), fast);
-
+        ) -> Result<std::result::Result<BiStream, Reply>> {
             let mut stream = conn.get_bi_stream().await?;
+
+            // TUIC v5 never replies to `Connect`
+            if conn.is_v5() {
+                let cmd = v5::Command::new_connect(TuicAddress::from(addr).into());
+                cmd.write_to(&mut stream).await?;
+                return Ok(Ok(stream));
+            }
+
+            let cmd = TuicCommand::new_connect(TuicAddress::from(addr), fast);
             cmd.write_to(&mut stream).await?;
 
             if !fast {
@@ -21,18 +33,23 @@ impl Connection {
                     Ok(resp) => resp,
                     Err(err) => {
                         stream.finish().await?;
-                        return Err(err);
+                        return Err(err.into());
                     }
                 };
 
-                if let TuicCommand::Response(true) = resp {
-                    Ok(Some(stream))
-                } else {
-                    stream.finish().await?;
-                    Ok(None)
+                match resp {
+                    TuicCommand::Response(reply) if reply.is_succeeded() => Ok(Ok(stream)),
+                    TuicCommand::Response(reply) => {
+                        stream.finish().await?;
+                        Ok(Err(reply))
+                    }
+                    _ => {
+                        stream.finish().await?;
+                        Ok(Err(Reply::Failed))
+                    }
                 }
             } else {
-                Ok(Some(stream))
+                Ok(Ok(stream))
             }
         }
 
@@ -40,11 +57,14 @@ impl Connection {
         let method = if fast { "connect2" } else { "connect" };
 
         match negotiate_connect(self, addr, fast).await {
-            Ok(Some(stream)) => {
+            Ok(Ok(stream)) => {
                 log::debug!("[relay] [task] [{method}] [{display_addr}] [success]");
-                let _ = tx.send(stream);
+                let _ = tx.send(Ok(stream));
+            }
+            Ok(Err(reply)) => {
+                log::debug!("[relay] [task] [{method}] [{display_addr}] [fail] {reply}");
+                let _ = tx.send(Err(reply));
             }
-            Ok(None) => log::debug!("[relay] [task] [{method}] [{display_addr}] [fail]"),
             Err(err) => log::warn!("[relay] [task] [{method}] [{display_addr}] {err}"),
         }
     }
@@ -63,12 +83,16 @@ impl Connection {
             addr: Address,
             mode: UdpRelayMode<(), ()>,
         ) -> Result<()> {
+            if conn.is_v5() {
+                return send_packet_v5(conn, assoc_id as u16, pkt, addr, mode).await;
+            }
+
             let cmd = TuicCommand::new_packet(assoc_id, pkt.len() as u16, TuicAddress::from(addr));
 
             match mode {
                 UdpRelayMode::Native(()) => {
                     let mut buf = BytesMut::with_capacity(cmd.serialized_len());
-                    cmd.write_to_buf(&mut buf);
+                    cmd.write_to_buf(&mut buf)?;
                     buf.extend_from_slice(&pkt);
                     let pkt = buf.freeze();
                     conn.send_datagram(pkt)?;
@@ -84,39 +108,97 @@ impl Connection {
             Ok(())
         }
 
+        async fn send_packet_v5(
+            conn: Connection,
+            assoc_id: u16,
+            pkt: Bytes,
+            addr: Address,
+            mode: UdpRelayMode<(), ()>,
+        ) -> Result<()> {
+            let pkt_id = conn.next_pkt_id();
+            let addr = v5::Address::from(TuicAddress::from(addr));
+
+            match mode {
+                UdpRelayMode::Native(()) => {
+                    let max_len = conn.max_datagram_size().ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::Other,
+                            "Datagrams are not supported by the server",
+                        )
+                    })?;
+
+                    for (cmd, frag) in v5::fragment(assoc_id, pkt_id, addr, pkt, max_len)? {
+                        let mut buf = BytesMut::with_capacity(cmd.serialized_len() + frag.len());
+                        cmd.write_to_buf(&mut buf)?;
+                        buf.extend_from_slice(&frag);
+                        conn.send_datagram(buf.freeze())?;
+                    }
+                }
+                UdpRelayMode::Quic(()) => {
+                    let cmd =
+                        v5::Command::new_packet(assoc_id, pkt_id, 1, 0, pkt.len() as u16, addr);
+
+                    let mut send = conn.get_send_stream().await?;
+                    cmd.write_to(&mut send).await?;
+                    send.write_all(&pkt).await?;
+                    send.finish().await?;
+                }
+            }
+
+            Ok(())
+        }
+
         self.update_max_udp_relay_packet_size();
         let display_addr = format!("{addr}");
 
+        let destination = display_addr.as_str();
+
         match send_packet(self, assoc_id, pkt, addr, mode).await {
-            Ok(()) => log::debug!(
+            Ok(()) => tracing::debug!(
+                destination,
                 "[relay] [task] [associate] [{assoc_id}] [send] [{display_addr}] [success]"
             ),
-            Err(err) => {
-                log::warn!("[relay] [task] [associate] [{assoc_id}] [send] [{display_addr}] {err}")
-            }
+            Err(err) => tracing::warn!(
+                destination,
+                "[relay] [task] [associate] [{assoc_id}] [send] [{display_addr}] {err}"
+            ),
         }
     }
 
     pub async fn handle_packet_from(self, assoc_id: u32, pkt: Bytes, addr: Address) {
         self.update_max_udp_relay_packet_size();
         let display_addr = format!("{addr}");
+        let destination = display_addr.as_str();
 
         if let Some(recv_pkt_tx) = self.udp_sessions().get(&assoc_id) {
-            log::debug!(
+            tracing::debug!(
+                assoc_id,
+                destination,
                 "[relay] [task] [associate] [{assoc_id}] [recv] [{display_addr}] [success]"
             );
             let _ = recv_pkt_tx.send((pkt, addr)).await;
         } else {
-            log::warn!("[relay] [task] [associate] [{assoc_id}] [recv] [{display_addr}] No corresponding UDP relay session found");
+            tracing::warn!(
+                assoc_id,
+                destination,
+                "[relay] [task] [associate] [{assoc_id}] [recv] [{display_addr}] No corresponding UDP relay session found"
+            );
         }
     }
 
     pub async fn handle_dissociate(self, assoc_id: u32) {
         async fn send_dissociate(conn: Connection, assoc_id: u32) -> Result<()> {
-            let cmd = TuicCommand::new_dissociate(assoc_id);
-
             let mut send = conn.get_send_stream().await?;
-            cmd.write_to(&mut send).await?;
+
+            if conn.is_v5() {
+                conn.reassembler().lock().remove(assoc_id as u16);
+                let cmd = v5::Command::new_dissociate(assoc_id as u16);
+                cmd.write_to(&mut send).await?;
+            } else {
+                let cmd = TuicCommand::new_dissociate(assoc_id);
+                cmd.write_to(&mut send).await?;
+            }
+
             send.finish().await?;
 
             Ok(())
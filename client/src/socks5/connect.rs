@@ -4,6 +4,7 @@ use socks5_proto::{Address, Reply};
 use socks5_server::{connection::connect::NeedReply, Connect};
 use std::io::Result;
 use tokio::sync::mpsc::Sender;
+use tuic_protocol::Reply as TuicReply;
 
 pub async fn handle(
     conn: Connect<NeedReply>,
@@ -20,16 +21,23 @@ pub async fn handle(
     let (relay_req, relay_resp_rx) = RelayRequest::new_connect(target_addr, unsafe { FAST });
     let _ = req_tx.send(relay_req).await;
 
-    if let Ok(mut relay) = relay_resp_rx.await {
-        let mut conn = conn.reply(Reply::Succeeded, Address::unspecified()).await?;
-        realm_io::bidi_copy(&mut conn, &mut relay).await?;
-    } else {
-        let mut conn = conn
-            .reply(Reply::NetworkUnreachable, Address::unspecified())
-            .await?;
+    let reply = match relay_resp_rx.await {
+        Ok(Ok(mut relay)) => {
+            let mut conn = conn.reply(Reply::Succeeded, Address::unspecified()).await?;
+            realm_io::bidi_copy(&mut conn, &mut relay).await?;
+            return Ok(());
+        }
+        Ok(Err(TuicReply::DnsFailure | TuicReply::HostUnreachable)) => Reply::HostUnreachable,
+        Ok(Err(TuicReply::ConnectionRefused)) => Reply::ConnectionRefused,
+        Ok(Err(TuicReply::Blocked)) => Reply::ConnectionNotAllowed,
+        // SOCKS5 has no code for timeouts, `TTL expired` is about the IP TTL
+        Ok(Err(_)) => Reply::GeneralFailure,
+        // the request never reached the server
+        Err(_) => Reply::NetworkUnreachable,
+    };
 
-        let _ = conn.shutdown().await;
-    }
+    let mut conn = conn.reply(reply, Address::unspecified()).await?;
+    let _ = conn.shutdown().await;
 
     Ok(())
 }
@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::Builder;
+use tuic_protocol::{Address, Decoded};
+
+fuzz_target!(|data: &[u8]| {
+    let decoded = Address::decode(data);
+
+    let read = Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(Address::read_from(&mut &data[..]));
+
+    match decoded {
+        Ok(Decoded::Complete(addr, len)) => {
+            let mut buf = Vec::new();
+            addr.write_to_buf(&mut buf).unwrap();
+
+            assert_eq!(buf.len(), addr.serialized_len());
+            assert_eq!(buf, data[..len]);
+            assert_eq!(read.unwrap(), addr);
+        }
+        Ok(Decoded::Incomplete(len)) => {
+            assert!(len > data.len());
+            assert!(read.is_err());
+        }
+        Err(_) => assert!(read.is_err()),
+    }
+});
@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::Builder;
+use tuic_protocol::{Command, Decoded};
+
+fuzz_target!(|data: &[u8]| {
+    let decoded = Command::decode(data);
+
+    let read = Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(Command::read_from(&mut &data[..]));
+
+    match decoded {
+        Ok(Decoded::Complete(cmd, len)) => {
+            let mut buf = Vec::new();
+            cmd.write_to_buf(&mut buf).unwrap();
+
+            assert_eq!(buf.len(), cmd.serialized_len());
+            assert_eq!(buf, data[..len]);
+            assert_eq!(read.unwrap(), cmd);
+        }
+        Ok(Decoded::Incomplete(len)) => {
+            assert!(len > data.len());
+            assert!(read.is_err());
+        }
+        Err(_) => assert!(read.is_err()),
+    }
+});
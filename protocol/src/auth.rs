@@ -0,0 +1,19 @@
+//! Session-bound authentication
+//!
+//! A session-bound digest is derived from the token digest and keying material exported from the current TLS session, so a captured digest can not be replayed on another connection.
+
+/// The label used to export keying material for session-bound authentication
+pub const SESSION_BOUND_AUTH_LABEL: &[u8] = b"tuic session-bound authentication";
+
+/// The length of keying material exported for session-bound authentication
+pub const SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN: usize = 32;
+
+/// Derives the session-bound digest of `token_digest`
+///
+/// `keying_material` is exported from the TLS session with [`SESSION_BOUND_AUTH_LABEL`] and an empty context.
+pub fn session_bound_digest(
+    token_digest: &[u8; 32],
+    keying_material: &[u8; SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN],
+) -> [u8; 32] {
+    *blake3::keyed_hash(keying_material, token_digest).as_bytes()
+}
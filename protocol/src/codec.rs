@@ -0,0 +1,69 @@
+//! [`tokio_util::codec`] adapters for the TUIC protocol
+
+use crate::{Address, Command, Decoded, ProtocolError, Result};
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Frames `Command` headers
+///
+/// Only the header is framed. The payload following a `Packet` header is left in the buffer for the caller to consume.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CommandCodec;
+
+impl Decoder for CommandCodec {
+    type Item = Command;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        decode_with(src, Command::decode)
+    }
+}
+
+impl Encoder<Command> for CommandCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, item: Command, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(item.serialized_len());
+        item.write_to_buf(dst)?;
+        Ok(())
+    }
+}
+
+/// Frames `Address`es
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AddressCodec;
+
+impl Decoder for AddressCodec {
+    type Item = Address;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        decode_with(src, Address::decode)
+    }
+}
+
+impl Encoder<Address> for AddressCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, item: Address, dst: &mut BytesMut) -> Result<()> {
+        dst.reserve(item.serialized_len());
+        item.write_to_buf(dst)?;
+        Ok(())
+    }
+}
+
+fn decode_with<T>(
+    src: &mut BytesMut,
+    decode: fn(&[u8]) -> Result<Decoded<T>>,
+) -> Result<Option<T>> {
+    match decode(src)? {
+        Decoded::Complete(val, len) => {
+            src.advance(len);
+            Ok(Some(val))
+        }
+        Decoded::Incomplete(len) => {
+            src.reserve(len - src.len());
+            Ok(None)
+        }
+    }
+}
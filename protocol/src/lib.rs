@@ -1,16 +1,30 @@
 //! The TUIC protocol
 
-use byteorder::{BigEndian, ReadBytesExt};
-use bytes::BufMut;
+use bytes::{Buf, BufMut};
 use std::{
     fmt::{Display, Formatter, Result as FmtResult},
-    io::{Cursor, Error, ErrorKind, Result},
+    io::{Error, ErrorKind},
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::{self, Utf8Error},
 };
+use thiserror::Error as ThisError;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+pub use self::{
+    auth::{
+        session_bound_digest, SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN, SESSION_BOUND_AUTH_LABEL,
+    },
+    codec::{AddressCodec, CommandCodec},
+};
+
+mod auth;
+mod codec;
+pub mod v5;
+
 pub const TUIC_PROTOCOL_VERSION: u8 = 0x04;
 
+pub type Result<T> = std::result::Result<T, ProtocolError>;
+
 /// Command
 ///
 /// ```plain
@@ -21,9 +35,9 @@ pub const TUIC_PROTOCOL_VERSION: u8 = 0x04;
 /// +-----+------+----------+
 /// ```
 #[non_exhaustive]
-#[derive(Clone)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Command {
-    Response(bool),
+    Response(Reply),
     Authenticate {
         digest: [u8; 32],
     },
@@ -51,11 +65,8 @@ impl Command {
     const TYPE_DISSOCIATE: u8 = 0x03;
     const TYPE_HEARTBEAT: u8 = 0x04;
 
-    const RESPONSE_SUCCEEDED: u8 = 0x00;
-    const RESPONSE_FAILED: u8 = 0xff;
-
-    pub fn new_response(is_succeeded: bool) -> Self {
-        Self::Response(is_succeeded)
+    pub fn new_response(reply: Reply) -> Self {
+        Self::Response(reply)
     }
 
     pub fn new_authenticate(digest: [u8; 32]) -> Self {
@@ -82,66 +93,77 @@ impl Command {
         Self::Heartbeat
     }
 
-    pub async fn read_from<R>(r: &mut R) -> Result<Self>
-    where
-        R: AsyncRead + Unpin,
-    {
-        let ver = r.read_u8().await?;
+    /// Decodes a `Command` header from the beginning of `buf` without performing any I/O
+    ///
+    /// The payload following a `Packet` header is not consumed.
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        let ver = match buf.first() {
+            Some(ver) => *ver,
+            None => return Ok(Decoded::Incomplete(1)),
+        };
 
         if ver != TUIC_PROTOCOL_VERSION {
-            return Err(Error::new(
-                ErrorKind::Unsupported,
-                format!("Unsupported TUIC version: {ver}"),
-            ));
+            return Err(ProtocolError::UnsupportedVersion(ver));
         }
 
-        let cmd = r.read_u8().await?;
-        match cmd {
-            Self::TYPE_RESPONSE => {
-                let resp = r.read_u8().await?;
-                match resp {
-                    Self::RESPONSE_SUCCEEDED => Ok(Self::new_response(true)),
-                    Self::RESPONSE_FAILED => Ok(Self::new_response(false)),
-                    _ => Err(Error::new(
-                        ErrorKind::InvalidInput,
-                        format!("Invalid response code: {resp}"),
-                    )),
-                }
-            }
+        let cmd = match buf.get(1) {
+            Some(cmd) => *cmd,
+            None => return Ok(Decoded::Incomplete(2)),
+        };
+
+        let opt = &buf[2..];
+
+        let decoded = match cmd {
+            Self::TYPE_RESPONSE => match opt.first() {
+                Some(resp) => Decoded::Complete(Self::new_response(Reply::from_code(*resp)?), 1),
+                None => Decoded::Incomplete(1),
+            },
             Self::TYPE_AUTHENTICATE => {
-                let mut digest = [0; 32];
-                r.read_exact(&mut digest).await?;
-                Ok(Self::new_authenticate(digest))
-            }
-            Self::TYPE_CONNECT => {
-                let addr = Address::read_from(r).await?;
-                Ok(Self::new_connect(addr, false))
+                if opt.len() < 32 {
+                    Decoded::Incomplete(32)
+                } else {
+                    let mut digest = [0; 32];
+                    digest.copy_from_slice(&opt[..32]);
+                    Decoded::Complete(Self::new_authenticate(digest), 32)
+                }
             }
+            Self::TYPE_CONNECT => Address::decode(opt)?.map(|addr| Self::new_connect(addr, false)),
             Self::TYPE_FAST_CONNECT => {
-                let addr = Address::read_from(r).await?;
-                Ok(Self::new_connect(addr, true))
+                Address::decode(opt)?.map(|addr| Self::new_connect(addr, true))
             }
             Self::TYPE_PACKET => {
-                let mut buf = [0; 6];
-                r.read_exact(&mut buf).await?;
-                let mut rdr = Cursor::new(buf);
-
-                let assoc_id = ReadBytesExt::read_u32::<BigEndian>(&mut rdr).unwrap();
-                let len = ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap();
-                let addr = Address::read_from(r).await?;
+                if opt.len() < 6 {
+                    Decoded::Incomplete(6)
+                } else {
+                    let mut rdr = &opt[..6];
+                    let assoc_id = rdr.get_u32();
+                    let len = rdr.get_u16();
 
-                Ok(Self::new_packet(assoc_id, len, addr))
+                    Address::decode(&opt[6..])?
+                        .map(|addr| Self::new_packet(assoc_id, len, addr))
+                        .offset(6)
+                }
             }
             Self::TYPE_DISSOCIATE => {
-                let assoc_id = r.read_u32().await?;
-                Ok(Self::new_dissociate(assoc_id))
+                if opt.len() < 4 {
+                    Decoded::Incomplete(4)
+                } else {
+                    let assoc_id = (&opt[..4]).get_u32();
+                    Decoded::Complete(Self::new_dissociate(assoc_id), 4)
+                }
             }
-            Self::TYPE_HEARTBEAT => Ok(Self::new_heartbeat()),
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("Invalid command: {cmd}"),
-            )),
-        }
+            Self::TYPE_HEARTBEAT => Decoded::Complete(Self::new_heartbeat(), 0),
+            _ => return Err(ProtocolError::UnknownCommand(cmd)),
+        };
+
+        Ok(decoded.offset(2))
+    }
+
+    pub async fn read_from<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        read_decoded(r, Self::decode).await
     }
 
     pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
@@ -149,21 +171,25 @@ impl Command {
         W: AsyncWrite + Unpin,
     {
         let mut buf = Vec::with_capacity(self.serialized_len());
-        self.write_to_buf(&mut buf);
-        w.write_all(&buf).await
+        self.write_to_buf(&mut buf)?;
+        w.write_all(&buf).await?;
+        Ok(())
     }
 
-    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+    /// Writes the command into `buf`
+    ///
+    /// Nothing is written if the command can not be encoded.
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        if let Self::Connect { addr, .. } | Self::Packet { addr, .. } = self {
+            addr.check_encodable()?;
+        }
+
         buf.put_u8(TUIC_PROTOCOL_VERSION);
 
         match self {
-            Self::Response(is_succeeded) => {
+            Self::Response(reply) => {
                 buf.put_u8(Self::TYPE_RESPONSE);
-                if *is_succeeded {
-                    buf.put_u8(Self::RESPONSE_SUCCEEDED);
-                } else {
-                    buf.put_u8(Self::RESPONSE_FAILED);
-                }
+                buf.put_u8(reply.code());
             }
             Self::Authenticate { digest } => {
                 buf.put_u8(Self::TYPE_AUTHENTICATE);
@@ -175,7 +201,7 @@ impl Command {
                 } else {
                     Self::TYPE_CONNECT
                 });
-                addr.write_to_buf(buf);
+                addr.write_to_buf(buf)?;
             }
             Self::Packet {
                 assoc_id,
@@ -185,7 +211,7 @@ impl Command {
                 buf.put_u8(Self::TYPE_PACKET);
                 buf.put_u32(*assoc_id);
                 buf.put_u16(*len);
-                addr.write_to_buf(buf);
+                addr.write_to_buf(buf)?;
             }
             Self::Dissociate { assoc_id } => {
                 buf.put_u8(Self::TYPE_DISSOCIATE);
@@ -195,6 +221,8 @@ impl Command {
                 buf.put_u8(Self::TYPE_HEARTBEAT);
             }
         }
+
+        Ok(())
     }
 
     pub fn serialized_len(&self) -> usize {
@@ -213,6 +241,109 @@ impl Command {
     }
 }
 
+/// The reply code of a `Response`
+///
+/// Codes other than `0x00` and `0xff` tell why a `Connect` failed, and are not understood by servers and clients before they were added
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reply {
+    Succeeded,
+    /// Failed for a reason not covered by the other codes
+    Failed,
+    DnsFailure,
+    ConnectionRefused,
+    TimedOut,
+    /// Denied by the access control or the routing rules of the server
+    Blocked,
+    HostUnreachable,
+}
+
+impl Reply {
+    const SUCCEEDED: u8 = 0x00;
+    const DNS_FAILURE: u8 = 0x01;
+    const CONNECTION_REFUSED: u8 = 0x02;
+    const TIMED_OUT: u8 = 0x03;
+    const BLOCKED: u8 = 0x04;
+    const HOST_UNREACHABLE: u8 = 0x05;
+    const FAILED: u8 = 0xff;
+
+    pub fn from_code(code: u8) -> Result<Self> {
+        match code {
+            Self::SUCCEEDED => Ok(Self::Succeeded),
+            Self::DNS_FAILURE => Ok(Self::DnsFailure),
+            Self::CONNECTION_REFUSED => Ok(Self::ConnectionRefused),
+            Self::TIMED_OUT => Ok(Self::TimedOut),
+            Self::BLOCKED => Ok(Self::Blocked),
+            Self::HOST_UNREACHABLE => Ok(Self::HostUnreachable),
+            Self::FAILED => Ok(Self::Failed),
+            code => Err(ProtocolError::InvalidResponseCode(code)),
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Succeeded => Self::SUCCEEDED,
+            Self::Failed => Self::FAILED,
+            Self::DnsFailure => Self::DNS_FAILURE,
+            Self::ConnectionRefused => Self::CONNECTION_REFUSED,
+            Self::TimedOut => Self::TIMED_OUT,
+            Self::Blocked => Self::BLOCKED,
+            Self::HostUnreachable => Self::HOST_UNREACHABLE,
+        }
+    }
+
+    pub fn is_succeeded(self) -> bool {
+        self == Self::Succeeded
+    }
+}
+
+impl Display for Reply {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(match self {
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::DnsFailure => "DNS resolution failed",
+            Self::ConnectionRefused => "connection refused",
+            Self::TimedOut => "connection timed out",
+            Self::Blocked => "blocked by the server",
+            Self::HostUnreachable => "host unreachable",
+        })
+    }
+}
+
+/// A `Command` of any supported TUIC version, told apart by the `VER` byte
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AnyCommand {
+    V4(Command),
+    V5(v5::Command),
+}
+
+impl AnyCommand {
+    /// Decodes a `Command` header of any supported version from the beginning of `buf` without performing any I/O
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        match buf.first() {
+            Some(&TUIC_PROTOCOL_VERSION) => Ok(Command::decode(buf)?.map(Self::V4)),
+            Some(&v5::VERSION) => Ok(v5::Command::decode(buf)?.map(Self::V5)),
+            Some(ver) => Err(ProtocolError::UnsupportedVersion(*ver)),
+            None => Ok(Decoded::Incomplete(1)),
+        }
+    }
+
+    pub async fn read_from<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        read_decoded(r, Self::decode).await
+    }
+
+    pub fn version(&self) -> u8 {
+        match self {
+            Self::V4(_) => TUIC_PROTOCOL_VERSION,
+            Self::V5(_) => v5::VERSION,
+        }
+    }
+}
+
 /// Address
 ///
 /// ```plain
@@ -227,7 +358,7 @@ impl Command {
 /// 0x00: fully-qualified domain name (the first byte indicates the length of the domain name)
 /// 0x01: IPv4 address
 /// 0x02: IPv6 address
-#[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum Address {
     DomainAddress(String, u16),
     SocketAddress(SocketAddr),
@@ -238,105 +369,133 @@ impl Address {
     const TYPE_IPV4: u8 = 0x01;
     const TYPE_IPV6: u8 = 0x02;
 
-    pub async fn read_from<R>(stream: &mut R) -> Result<Self>
-    where
-        R: AsyncRead + Unpin,
-    {
-        let addr_type = stream.read_u8().await?;
+    /// Decodes an `Address` from the beginning of `buf` without performing any I/O
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        let addr_type = match buf.first() {
+            Some(addr_type) => *addr_type,
+            None => return Ok(Decoded::Incomplete(1)),
+        };
 
         match addr_type {
             Self::TYPE_DOMAIN => {
-                let len = stream.read_u8().await? as usize;
+                let len = match buf.get(1) {
+                    Some(len) => *len as usize,
+                    None => return Ok(Decoded::Incomplete(2)),
+                };
 
-                let mut buf = vec![0; len + 2];
-                stream.read_exact(&mut buf).await?;
+                if buf.len() < 2 + len + 2 {
+                    return Ok(Decoded::Incomplete(2 + len + 2));
+                }
 
-                let port = ReadBytesExt::read_u16::<BigEndian>(&mut &buf[len..]).unwrap();
-                buf.truncate(len);
+                let addr = str::from_utf8(&buf[2..2 + len])
+                    .map_err(ProtocolError::InvalidDomainEncoding)?;
 
-                let addr = String::from_utf8(buf).map_err(|err| {
-                    Error::new(
-                        ErrorKind::InvalidData,
-                        format!("Invalid address encoding: {err}"),
-                    )
-                })?;
+                let port = (&buf[2 + len..]).get_u16();
 
-                Ok(Self::DomainAddress(addr, port))
+                Ok(Decoded::Complete(
+                    Self::DomainAddress(addr.to_owned(), port),
+                    2 + len + 2,
+                ))
             }
             Self::TYPE_IPV4 => {
-                let mut buf = [0; 6];
-                stream.read_exact(&mut buf).await?;
-                let mut rdr = Cursor::new(buf);
-
-                let addr = Ipv4Addr::new(
-                    ReadBytesExt::read_u8(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u8(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u8(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u8(&mut rdr).unwrap(),
-                );
+                if buf.len() < 1 + 6 {
+                    return Ok(Decoded::Incomplete(1 + 6));
+                }
 
-                let port = ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap();
+                let mut rdr = &buf[1..];
+                let addr = Ipv4Addr::from(rdr.get_u32());
+                let port = rdr.get_u16();
 
-                Ok(Self::SocketAddress(SocketAddr::from((addr, port))))
+                Ok(Decoded::Complete(
+                    Self::SocketAddress(SocketAddr::from((addr, port))),
+                    1 + 6,
+                ))
             }
             Self::TYPE_IPV6 => {
-                let mut buf = [0; 18];
-                stream.read_exact(&mut buf).await?;
-                let mut rdr = Cursor::new(buf);
-
-                let addr = Ipv6Addr::new(
-                    ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap(),
-                    ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap(),
-                );
-
-                let port = ReadBytesExt::read_u16::<BigEndian>(&mut rdr).unwrap();
-
-                Ok(Self::SocketAddress(SocketAddr::from((addr, port))))
+                if buf.len() < 1 + 18 {
+                    return Ok(Decoded::Incomplete(1 + 18));
+                }
+
+                let mut rdr = &buf[1..];
+                let addr = Ipv6Addr::from(rdr.get_u128());
+                let port = rdr.get_u16();
+
+                Ok(Decoded::Complete(
+                    Self::SocketAddress(SocketAddr::from((addr, port))),
+                    1 + 18,
+                ))
             }
-            _ => Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!("Unsupported address type: {addr_type}"),
-            )),
+            _ => Err(ProtocolError::InvalidAddressType(addr_type)),
         }
     }
 
+    pub async fn read_from<R>(stream: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        read_decoded(stream, Self::decode).await
+    }
+
     pub async fn write_to<W>(&self, writer: &mut W) -> Result<()>
     where
         W: AsyncWrite + Unpin,
     {
         let mut buf = Vec::with_capacity(self.serialized_len());
-        self.write_to_buf(&mut buf);
-        writer.write_all(&buf).await
+        self.write_to_buf(&mut buf)?;
+        writer.write_all(&buf).await?;
+        Ok(())
     }
 
-    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+    /// Writes the address into `buf`
+    ///
+    /// Nothing is written if the address can not be encoded.
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        self.check_encodable()?;
+
         match self {
-            Self::DomainAddress(addr, port) => {
-                buf.put_u8(Self::TYPE_DOMAIN);
-                buf.put_u8(addr.len() as u8);
-                buf.put_slice(addr.as_bytes());
-                buf.put_u16(*port);
+            Self::DomainAddress(addr, port) => Self::write_domain(buf, addr, *port),
+            Self::SocketAddress(addr) => Self::write_socket_addr(buf, addr),
+        }
+
+        Ok(())
+    }
+
+    fn check_encodable(&self) -> Result<()> {
+        match self {
+            Self::DomainAddress(addr, _) => Self::check_domain(addr),
+            Self::SocketAddress(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_domain(addr: &str) -> Result<()> {
+        if addr.len() > u8::MAX as usize {
+            Err(ProtocolError::DomainTooLong(addr.len()))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn write_domain<B: BufMut>(buf: &mut B, addr: &str, port: u16) {
+        buf.put_u8(Self::TYPE_DOMAIN);
+        buf.put_u8(addr.len() as u8);
+        buf.put_slice(addr.as_bytes());
+        buf.put_u16(port);
+    }
+
+    pub(crate) fn write_socket_addr<B: BufMut>(buf: &mut B, addr: &SocketAddr) {
+        match addr {
+            SocketAddr::V4(addr) => {
+                buf.put_u8(Self::TYPE_IPV4);
+                buf.put_slice(&addr.ip().octets());
+                buf.put_u16(addr.port());
             }
-            Self::SocketAddress(addr) => match addr {
-                SocketAddr::V4(addr) => {
-                    buf.put_u8(Self::TYPE_IPV4);
-                    buf.put_slice(&addr.ip().octets());
-                    buf.put_u16(addr.port());
+            SocketAddr::V6(addr) => {
+                buf.put_u8(Self::TYPE_IPV6);
+                for seg in addr.ip().segments() {
+                    buf.put_u16(seg);
                 }
-                SocketAddr::V6(addr) => {
-                    buf.put_u8(Self::TYPE_IPV6);
-                    for seg in addr.ip().segments() {
-                        buf.put_u16(seg);
-                    }
-                    buf.put_u16(addr.port());
-                }
-            },
+                buf.put_u16(addr.port());
+            }
         }
     }
 
@@ -363,3 +522,87 @@ impl Display for Address {
         }
     }
 }
+
+/// Errors that occur when reading or writing TUIC protocol data
+#[derive(ThisError, Debug)]
+pub enum ProtocolError {
+    #[error(transparent)]
+    Io(#[from] Error),
+    #[error("unsupported TUIC version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("unknown command: {0}")]
+    UnknownCommand(u8),
+    #[error("invalid response code: {0}")]
+    InvalidResponseCode(u8),
+    #[error("invalid address type: {0}")]
+    InvalidAddressType(u8),
+    #[error("invalid domain name encoding: {0}")]
+    InvalidDomainEncoding(#[source] Utf8Error),
+    #[error("domain name is {0} bytes long, exceeding the 255 bytes limit")]
+    DomainTooLong(usize),
+    #[error("missing address")]
+    MissingAddress,
+    #[error("invalid fragment {frag_id} of {frag_total}")]
+    InvalidFragment { frag_id: u8, frag_total: u8 },
+    #[error("UDP packet of {0} bytes can not be fragmented to fit")]
+    PacketTooLarge(usize),
+    #[error("UDP packet of at least {0} bytes exceeds the limit of {1} bytes")]
+    PacketTooLong(usize, usize),
+}
+
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Self {
+        match err {
+            ProtocolError::Io(err) => err,
+            ProtocolError::UnsupportedVersion(_) => Self::new(ErrorKind::Unsupported, err),
+            ProtocolError::InvalidDomainEncoding(_) => Self::new(ErrorKind::InvalidData, err),
+            err => Self::new(ErrorKind::InvalidInput, err),
+        }
+    }
+}
+
+/// The result of decoding from a buffer that may not hold a complete frame yet
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Decoded<T> {
+    /// The decoded value, followed by the number of bytes it occupies
+    Complete(T, usize),
+    /// The buffer is too short. The value is the minimum total length the buffer needs to reach before decoding can make progress
+    Incomplete(usize),
+}
+
+impl<T> Decoded<T> {
+    pub(crate) fn map<U>(self, f: impl FnOnce(T) -> U) -> Decoded<U> {
+        match self {
+            Self::Complete(val, len) => Decoded::Complete(f(val), len),
+            Self::Incomplete(len) => Decoded::Incomplete(len),
+        }
+    }
+
+    pub(crate) fn offset(self, offset: usize) -> Self {
+        match self {
+            Self::Complete(val, len) => Self::Complete(val, offset + len),
+            Self::Incomplete(len) => Self::Incomplete(offset + len),
+        }
+    }
+}
+
+pub(crate) async fn read_decoded<R, T>(
+    r: &mut R,
+    decode: fn(&[u8]) -> Result<Decoded<T>>,
+) -> Result<T>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+
+    loop {
+        match decode(&buf)? {
+            Decoded::Complete(val, _) => return Ok(val),
+            Decoded::Incomplete(len) => {
+                let filled = buf.len();
+                buf.resize(len, 0);
+                r.read_exact(&mut buf[filled..]).await?;
+            }
+        }
+    }
+}
@@ -0,0 +1,581 @@
+//! TUIC protocol version 5
+//!
+//! Compared to version 4, authentication carries a UUID and a token derived from the TLS session, UDP packets can be fragmented, the associate ID is shortened to 16 bits, and `Connect` is never replied.
+
+use crate::{read_decoded, Address as V4Address, Decoded, ProtocolError, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+pub const VERSION: u8 = 0x05;
+
+/// Command
+///
+/// ```plain
+/// +-----+------+----------+
+/// | VER | TYPE |   OPT    |
+/// +-----+------+----------+
+/// |  1  |  1   | Variable |
+/// +-----+------+----------+
+/// ```
+#[non_exhaustive]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    Authenticate {
+        uuid: [u8; 16],
+        token: [u8; 32],
+    },
+    Connect {
+        addr: Address,
+    },
+    Packet {
+        assoc_id: u16,
+        pkt_id: u16,
+        frag_total: u8,
+        frag_id: u8,
+        size: u16,
+        addr: Address,
+    },
+    Dissociate {
+        assoc_id: u16,
+    },
+    Heartbeat,
+}
+
+impl Command {
+    const TYPE_AUTHENTICATE: u8 = 0x00;
+    const TYPE_CONNECT: u8 = 0x01;
+    const TYPE_PACKET: u8 = 0x02;
+    const TYPE_DISSOCIATE: u8 = 0x03;
+    const TYPE_HEARTBEAT: u8 = 0x04;
+
+    pub fn new_authenticate(uuid: [u8; 16], token: [u8; 32]) -> Self {
+        Self::Authenticate { uuid, token }
+    }
+
+    pub fn new_connect(addr: Address) -> Self {
+        Self::Connect { addr }
+    }
+
+    pub fn new_packet(
+        assoc_id: u16,
+        pkt_id: u16,
+        frag_total: u8,
+        frag_id: u8,
+        size: u16,
+        addr: Address,
+    ) -> Self {
+        Self::Packet {
+            assoc_id,
+            pkt_id,
+            frag_total,
+            frag_id,
+            size,
+            addr,
+        }
+    }
+
+    pub fn new_dissociate(assoc_id: u16) -> Self {
+        Self::Dissociate { assoc_id }
+    }
+
+    pub fn new_heartbeat() -> Self {
+        Self::Heartbeat
+    }
+
+    /// Decodes a `Command` header from the beginning of `buf` without performing any I/O
+    ///
+    /// The payload following a `Packet` header is not consumed.
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        let ver = match buf.first() {
+            Some(ver) => *ver,
+            None => return Ok(Decoded::Incomplete(1)),
+        };
+
+        if ver != VERSION {
+            return Err(ProtocolError::UnsupportedVersion(ver));
+        }
+
+        let cmd = match buf.get(1) {
+            Some(cmd) => *cmd,
+            None => return Ok(Decoded::Incomplete(2)),
+        };
+
+        let opt = &buf[2..];
+
+        let decoded = match cmd {
+            Self::TYPE_AUTHENTICATE => {
+                if opt.len() < 16 + 32 {
+                    Decoded::Incomplete(16 + 32)
+                } else {
+                    let mut uuid = [0; 16];
+                    let mut token = [0; 32];
+                    uuid.copy_from_slice(&opt[..16]);
+                    token.copy_from_slice(&opt[16..48]);
+                    Decoded::Complete(Self::new_authenticate(uuid, token), 16 + 32)
+                }
+            }
+            Self::TYPE_CONNECT => Address::decode(opt)?.map(Self::new_connect),
+            Self::TYPE_PACKET => {
+                if opt.len() < 8 {
+                    Decoded::Incomplete(8)
+                } else {
+                    let mut rdr = &opt[..8];
+                    let assoc_id = rdr.get_u16();
+                    let pkt_id = rdr.get_u16();
+                    let frag_total = rdr.get_u8();
+                    let frag_id = rdr.get_u8();
+                    let size = rdr.get_u16();
+
+                    Address::decode(&opt[8..])?
+                        .map(|addr| {
+                            Self::new_packet(assoc_id, pkt_id, frag_total, frag_id, size, addr)
+                        })
+                        .offset(8)
+                }
+            }
+            Self::TYPE_DISSOCIATE => {
+                if opt.len() < 2 {
+                    Decoded::Incomplete(2)
+                } else {
+                    let assoc_id = (&opt[..2]).get_u16();
+                    Decoded::Complete(Self::new_dissociate(assoc_id), 2)
+                }
+            }
+            Self::TYPE_HEARTBEAT => Decoded::Complete(Self::new_heartbeat(), 0),
+            _ => return Err(ProtocolError::UnknownCommand(cmd)),
+        };
+
+        Ok(decoded.offset(2))
+    }
+
+    pub async fn read_from<R>(r: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        read_decoded(r, Self::decode).await
+    }
+
+    pub async fn write_to<W>(&self, w: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = Vec::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf)?;
+        w.write_all(&buf).await?;
+        Ok(())
+    }
+
+    /// Writes the command into `buf`
+    ///
+    /// Nothing is written if the command can not be encoded.
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        if let Self::Connect { addr, .. } | Self::Packet { addr, .. } = self {
+            addr.check_encodable()?;
+        }
+
+        buf.put_u8(VERSION);
+
+        match self {
+            Self::Authenticate { uuid, token } => {
+                buf.put_u8(Self::TYPE_AUTHENTICATE);
+                buf.put_slice(uuid);
+                buf.put_slice(token);
+            }
+            Self::Connect { addr } => {
+                buf.put_u8(Self::TYPE_CONNECT);
+                addr.write_to_buf(buf)?;
+            }
+            Self::Packet {
+                assoc_id,
+                pkt_id,
+                frag_total,
+                frag_id,
+                size,
+                addr,
+            } => {
+                buf.put_u8(Self::TYPE_PACKET);
+                buf.put_u16(*assoc_id);
+                buf.put_u16(*pkt_id);
+                buf.put_u8(*frag_total);
+                buf.put_u8(*frag_id);
+                buf.put_u16(*size);
+                addr.write_to_buf(buf)?;
+            }
+            Self::Dissociate { assoc_id } => {
+                buf.put_u8(Self::TYPE_DISSOCIATE);
+                buf.put_u16(*assoc_id);
+            }
+            Self::Heartbeat => {
+                buf.put_u8(Self::TYPE_HEARTBEAT);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn serialized_len(&self) -> usize {
+        2 + match self {
+            Self::Authenticate { .. } => 16 + 32,
+            Self::Connect { addr } => addr.serialized_len(),
+            Self::Packet { addr, .. } => 8 + addr.serialized_len(),
+            Self::Dissociate { .. } => 2,
+            Self::Heartbeat => 0,
+        }
+    }
+
+    pub const fn max_serialized_len() -> usize {
+        2 + 8 + Address::max_serialized_len()
+    }
+}
+
+/// Address
+///
+/// ```plain
+/// +------+----------+----------+
+/// | TYPE |   ADDR   |   PORT   |
+/// +------+----------+----------+
+/// |  1   | Variable |    2     |
+/// +------+----------+----------+
+/// ```
+///
+/// The address type can be one of the following:
+/// 0xff: none (`ADDR` and `PORT` are omitted)
+/// 0x00: fully-qualified domain name (the first byte indicates the length of the domain name)
+/// 0x01: IPv4 address
+/// 0x02: IPv6 address
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Address {
+    None,
+    DomainAddress(String, u16),
+    SocketAddress(SocketAddr),
+}
+
+impl Address {
+    const TYPE_NONE: u8 = 0xff;
+
+    /// Decodes an `Address` from the beginning of `buf` without performing any I/O
+    pub fn decode(buf: &[u8]) -> Result<Decoded<Self>> {
+        match buf.first() {
+            Some(&Self::TYPE_NONE) => Ok(Decoded::Complete(Self::None, 1)),
+            Some(_) => Ok(V4Address::decode(buf)?.map(Self::from)),
+            None => Ok(Decoded::Incomplete(1)),
+        }
+    }
+
+    /// Writes the address into `buf`
+    ///
+    /// Nothing is written if the address can not be encoded.
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) -> Result<()> {
+        self.check_encodable()?;
+
+        match self {
+            Self::None => buf.put_u8(Self::TYPE_NONE),
+            Self::DomainAddress(addr, port) => V4Address::write_domain(buf, addr, *port),
+            Self::SocketAddress(addr) => V4Address::write_socket_addr(buf, addr),
+        }
+
+        Ok(())
+    }
+
+    fn check_encodable(&self) -> Result<()> {
+        match self {
+            Self::DomainAddress(addr, _) => V4Address::check_domain(addr),
+            Self::None | Self::SocketAddress(_) => Ok(()),
+        }
+    }
+
+    pub fn serialized_len(&self) -> usize {
+        match self {
+            Self::None => 1,
+            Self::DomainAddress(addr, _) => 1 + 1 + addr.len() + 2,
+            Self::SocketAddress(SocketAddr::V4(_)) => 1 + 6,
+            Self::SocketAddress(SocketAddr::V6(_)) => 1 + 18,
+        }
+    }
+
+    pub const fn max_serialized_len() -> usize {
+        V4Address::max_serialized_len()
+    }
+
+    pub fn is_none(&self) -> bool {
+        matches!(self, Self::None)
+    }
+}
+
+impl From<V4Address> for Address {
+    fn from(addr: V4Address) -> Self {
+        match addr {
+            V4Address::DomainAddress(addr, port) => Self::DomainAddress(addr, port),
+            V4Address::SocketAddress(addr) => Self::SocketAddress(addr),
+        }
+    }
+}
+
+impl TryFrom<Address> for V4Address {
+    type Error = ProtocolError;
+
+    fn try_from(addr: Address) -> Result<Self> {
+        match addr {
+            Address::None => Err(ProtocolError::MissingAddress),
+            Address::DomainAddress(addr, port) => Ok(Self::DomainAddress(addr, port)),
+            Address::SocketAddress(addr) => Ok(Self::SocketAddress(addr)),
+        }
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::DomainAddress(addr, port) => write!(f, "{addr}:{port}"),
+            Self::SocketAddress(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+/// Splits a UDP packet into `Packet` commands, so that each command followed by its fragment of `pkt` fits in `max_len` bytes
+///
+/// Only the first fragment carries the address.
+pub fn fragment(
+    assoc_id: u16,
+    pkt_id: u16,
+    addr: Address,
+    pkt: Bytes,
+    max_len: usize,
+) -> Result<Vec<(Command, Bytes)>> {
+    let first_hdr_len = 2 + 8 + addr.serialized_len();
+    let rest_hdr_len = 2 + 8 + Address::None.serialized_len();
+
+    if max_len <= first_hdr_len {
+        return Err(ProtocolError::PacketTooLarge(pkt.len()));
+    }
+
+    let first_cap = max_len - first_hdr_len;
+    let rest_cap = max_len - rest_hdr_len;
+
+    let frag_total = if pkt.len() <= first_cap {
+        1
+    } else {
+        1 + (pkt.len() - first_cap + rest_cap - 1) / rest_cap
+    };
+
+    if frag_total > u8::MAX as usize {
+        return Err(ProtocolError::PacketTooLarge(pkt.len()));
+    }
+
+    let mut frags = Vec::with_capacity(frag_total);
+    let mut addr = Some(addr);
+    let mut rest = pkt;
+
+    for frag_id in 0..frag_total {
+        let (addr, cap) = match addr.take() {
+            Some(addr) => (addr, first_cap),
+            None => (Address::None, rest_cap),
+        };
+
+        let frag = rest.split_to(cap.min(rest.len()));
+
+        let cmd = Command::new_packet(
+            assoc_id,
+            pkt_id,
+            frag_total as u8,
+            frag_id as u8,
+            frag.len() as u16,
+            addr,
+        );
+
+        frags.push((cmd, frag));
+    }
+
+    Ok(frags)
+}
+
+/// Reassembles fragmented UDP packets
+///
+/// At most `capacity` partially received packets holding `max_bytes` in total are kept. The oldest ones are dropped to make room for a new fragment, and a packet not completed within `timeout` is dropped. Packets longer than `max_pkt_size` are rejected.
+pub struct Reassembler {
+    pkts: HashMap<(u16, u16), PartialPacket>,
+    capacity: usize,
+    max_pkt_size: usize,
+    max_bytes: usize,
+    timeout: Duration,
+    bytes: usize,
+    seq: u64,
+}
+
+struct PartialPacket {
+    seq: u64,
+    created: Instant,
+    addr: Option<Address>,
+    frags: Vec<Option<Bytes>>,
+    received: usize,
+    len: usize,
+}
+
+impl Reassembler {
+    pub fn new(capacity: usize, max_pkt_size: usize, max_bytes: usize, timeout: Duration) -> Self {
+        Self {
+            pkts: HashMap::new(),
+            capacity,
+            max_pkt_size,
+            max_bytes,
+            timeout,
+            bytes: 0,
+            seq: 0,
+        }
+    }
+
+    /// Feeds a fragment, returning the address and the whole packet once all of its fragments have arrived
+    pub fn insert(
+        &mut self,
+        assoc_id: u16,
+        pkt_id: u16,
+        frag_total: u8,
+        frag_id: u8,
+        addr: Address,
+        frag: Bytes,
+    ) -> Result<Option<(V4Address, Bytes)>> {
+        if frag_id >= frag_total {
+            return Err(ProtocolError::InvalidFragment {
+                frag_id,
+                frag_total,
+            });
+        }
+
+        if frag.len() > self.max_pkt_size {
+            self.remove_packet(&(assoc_id, pkt_id));
+            return Err(ProtocolError::PacketTooLong(frag.len(), self.max_pkt_size));
+        }
+
+        if frag_total == 1 {
+            return Ok(Some((V4Address::try_from(addr)?, frag)));
+        }
+
+        let now = Instant::now();
+        let timeout = self.timeout;
+        let expired = self
+            .pkts
+            .iter()
+            .filter(|(_, pkt)| now.duration_since(pkt.created) >= timeout)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            self.remove_packet(&key);
+        }
+
+        let key = (assoc_id, pkt_id);
+
+        if let Some(pkt) = self.pkts.get(&key) {
+            if pkt.frags.len() != frag_total as usize {
+                self.remove_packet(&key);
+
+                return Err(ProtocolError::InvalidFragment {
+                    frag_id,
+                    frag_total,
+                });
+            }
+
+            let len = pkt.len + frag.len();
+
+            if len > self.max_pkt_size {
+                self.remove_packet(&key);
+                return Err(ProtocolError::PacketTooLong(len, self.max_pkt_size));
+            }
+        }
+
+        // the oldest packets other than this one make room for the fragment
+        while (!self.pkts.contains_key(&key) && self.pkts.len() >= self.capacity)
+            || self.bytes + frag.len() > self.max_bytes
+        {
+            let oldest = self
+                .pkts
+                .iter()
+                .filter(|(other, _)| **other != key)
+                .min_by_key(|(_, pkt)| pkt.seq)
+                .map(|(key, _)| *key);
+
+            match oldest {
+                Some(oldest) => {
+                    self.remove_packet(&oldest);
+                }
+                None => break,
+            }
+        }
+
+        let seq = self.seq;
+        self.seq += 1;
+
+        let pkt = self.pkts.entry(key).or_insert_with(|| PartialPacket {
+            seq,
+            created: now,
+            addr: None,
+            frags: vec![None; frag_total as usize],
+            received: 0,
+            len: 0,
+        });
+
+        if frag_id == 0 {
+            pkt.addr = Some(addr);
+        }
+
+        let frag_len = frag.len();
+
+        match pkt.frags[frag_id as usize].replace(frag) {
+            Some(old) => {
+                pkt.len -= old.len();
+                self.bytes -= old.len();
+            }
+            None => pkt.received += 1,
+        }
+
+        pkt.len += frag_len;
+        self.bytes += frag_len;
+
+        if pkt.received < pkt.frags.len() {
+            return Ok(None);
+        }
+
+        let pkt = self.remove_packet(&key).unwrap();
+        let addr = V4Address::try_from(pkt.addr.unwrap_or(Address::None))?;
+
+        let mut buf = BytesMut::with_capacity(pkt.len);
+
+        for frag in pkt.frags.into_iter().flatten() {
+            buf.extend_from_slice(&frag);
+        }
+
+        Ok(Some((addr, buf.freeze())))
+    }
+
+    /// The number of bytes held by partially received packets
+    pub fn buffered_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn remove_packet(&mut self, key: &(u16, u16)) -> Option<PartialPacket> {
+        let pkt = self.pkts.remove(key)?;
+        self.bytes -= pkt.len;
+        Some(pkt)
+    }
+
+    /// Drops all partially received packets of an associate ID
+    pub fn remove(&mut self, assoc_id: u16) {
+        let bytes = &mut self.bytes;
+
+        self.pkts.retain(|(id, _), pkt| {
+            if *id == assoc_id {
+                *bytes -= pkt.len;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
@@ -0,0 +1,21 @@
+use tuic_protocol::session_bound_digest;
+
+#[test]
+fn session_bound_digest_depends_on_session() {
+    let token_digest = *blake3::hash(b"token").as_bytes();
+
+    let a = session_bound_digest(&token_digest, &[0; 32]);
+    let b = session_bound_digest(&token_digest, &[1; 32]);
+
+    assert_eq!(a, session_bound_digest(&token_digest, &[0; 32]));
+    assert_ne!(a, b);
+    assert_ne!(a, token_digest);
+}
+
+#[test]
+fn session_bound_digest_depends_on_token() {
+    let a = session_bound_digest(blake3::hash(b"token0").as_bytes(), &[0; 32]);
+    let b = session_bound_digest(blake3::hash(b"token1").as_bytes(), &[0; 32]);
+
+    assert_ne!(a, b);
+}
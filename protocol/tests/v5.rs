@@ -0,0 +1,182 @@
+use bytes::Bytes;
+use proptest::prelude::*;
+use std::{
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
+};
+use tuic_protocol::{
+    v5::{self, Address, Command, Reassembler},
+    Address as V4Address, AnyCommand, Decoded, ProtocolError,
+};
+
+fn address() -> impl Strategy<Value = Address> {
+    prop_oneof![
+        Just(Address::None),
+        ("[a-z0-9.-]{0,255}", any::<u16>())
+            .prop_map(|(domain, port)| Address::DomainAddress(domain, port)),
+        any::<SocketAddrV4>().prop_map(|addr| Address::SocketAddress(SocketAddr::V4(addr))),
+        any::<SocketAddrV6>()
+            .prop_map(|addr| SocketAddrV6::new(*addr.ip(), addr.port(), 0, 0))
+            .prop_map(|addr| Address::SocketAddress(SocketAddr::V6(addr))),
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        (any::<[u8; 16]>(), any::<[u8; 32]>())
+            .prop_map(|(uuid, token)| Command::new_authenticate(uuid, token)),
+        address().prop_map(Command::new_connect),
+        (
+            any::<u16>(),
+            any::<u16>(),
+            any::<u8>(),
+            any::<u8>(),
+            any::<u16>(),
+            address()
+        )
+            .prop_map(|(assoc_id, pkt_id, frag_total, frag_id, size, addr)| {
+                Command::new_packet(assoc_id, pkt_id, frag_total, frag_id, size, addr)
+            }),
+        any::<u16>().prop_map(Command::new_dissociate),
+        Just(Command::new_heartbeat()),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn command_round_trip(cmd in command()) {
+        let mut buf = Vec::new();
+        cmd.write_to_buf(&mut buf).unwrap();
+
+        prop_assert_eq!(buf.len(), cmd.serialized_len());
+        prop_assert!(buf.len() <= Command::max_serialized_len());
+        prop_assert_eq!(Command::decode(&buf).unwrap(), Decoded::Complete(cmd.clone(), buf.len()));
+        prop_assert_eq!(AnyCommand::decode(&buf).unwrap(), Decoded::Complete(AnyCommand::V5(cmd), buf.len()));
+    }
+
+    #[test]
+    fn command_prefix_is_incomplete(cmd in command()) {
+        let mut buf = Vec::new();
+        cmd.write_to_buf(&mut buf).unwrap();
+
+        for len in 0..buf.len() {
+            match Command::decode(&buf[..len]).unwrap() {
+                Decoded::Incomplete(need) => prop_assert!(need > len && need <= buf.len()),
+                Decoded::Complete(..) => prop_assert!(false, "decoded from {} of {} bytes", len, buf.len()),
+            }
+        }
+    }
+
+    #[test]
+    fn fragment_and_reassemble(
+        addr in address().prop_filter("the first fragment needs an address", |addr| !addr.is_none()),
+        pkt in prop::collection::vec(any::<u8>(), 0..4096),
+        max_len in 300usize..1500,
+    ) {
+        let pkt = Bytes::from(pkt);
+        let frags = v5::fragment(1, 2, addr.clone(), pkt.clone(), max_len).unwrap();
+        let mut reassembler = Reassembler::new(16, 0x10000, 0x10000, Duration::from_secs(10));
+        let mut res = None;
+
+        for (i, (cmd, frag)) in frags.iter().enumerate().rev() {
+            prop_assert!(cmd.serialized_len() + frag.len() <= max_len);
+
+            if let Command::Packet { assoc_id, pkt_id, frag_total, frag_id, size, addr } = cmd.clone() {
+                prop_assert_eq!(frag_id as usize, i);
+                prop_assert_eq!(frag_total as usize, frags.len());
+                prop_assert_eq!(size as usize, frag.len());
+                prop_assert_eq!(addr.is_none(), i != 0);
+                prop_assert!(res.is_none());
+
+                res = reassembler.insert(assoc_id, pkt_id, frag_total, frag_id, addr, frag.clone()).unwrap();
+            } else {
+                prop_assert!(false, "not a packet command");
+            }
+        }
+
+        prop_assert_eq!(res, Some((V4Address::try_from(addr).unwrap(), pkt)));
+    }
+}
+
+#[test]
+fn fragment_too_large() {
+    let addr = Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 53)));
+    let res = v5::fragment(0, 0, addr, Bytes::from(vec![0; 0x10000]), 64);
+    assert!(matches!(res, Err(ProtocolError::PacketTooLarge(0x10000))));
+}
+
+#[test]
+fn reassemble_invalid_fragment() {
+    let mut reassembler = Reassembler::new(16, 0x10000, 0x10000, Duration::from_secs(10));
+    let res = reassembler.insert(0, 0, 2, 2, Address::None, Bytes::new());
+    assert!(matches!(
+        res,
+        Err(ProtocolError::InvalidFragment {
+            frag_id: 2,
+            frag_total: 2
+        })
+    ));
+}
+
+#[test]
+fn reassemble_evicts_oldest() {
+    let addr = Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 53)));
+    let mut reassembler = Reassembler::new(1, 0x10000, 0x10000, Duration::from_secs(10));
+
+    let res = reassembler.insert(0, 0, 2, 0, addr.clone(), Bytes::from_static(b"a"));
+    assert!(matches!(res, Ok(None)));
+    let res = reassembler.insert(0, 1, 2, 0, addr, Bytes::from_static(b"b"));
+    assert!(matches!(res, Ok(None)));
+    let res = reassembler.insert(0, 0, 2, 1, Address::None, Bytes::from_static(b"c"));
+    assert!(matches!(res, Ok(None)));
+}
+
+#[test]
+fn reassemble_rejects_long_packets() {
+    let addr = Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 53)));
+    let mut reassembler = Reassembler::new(16, 4, 1024, Duration::from_secs(10));
+
+    let res = reassembler.insert(0, 0, 1, 0, addr.clone(), Bytes::from_static(b"abcde"));
+    assert!(matches!(res, Err(ProtocolError::PacketTooLong(5, 4))));
+
+    let res = reassembler.insert(0, 1, 3, 0, addr, Bytes::from_static(b"ab"));
+    assert!(matches!(res, Ok(None)));
+    assert_eq!(reassembler.buffered_bytes(), 2);
+
+    let res = reassembler.insert(0, 1, 3, 1, Address::None, Bytes::from_static(b"cde"));
+    assert!(matches!(res, Err(ProtocolError::PacketTooLong(5, 4))));
+    assert_eq!(reassembler.buffered_bytes(), 0);
+
+    // the packet is gone, so its last fragment starts a new one
+    let res = reassembler.insert(0, 1, 3, 2, Address::None, Bytes::from_static(b"f"));
+    assert!(matches!(res, Ok(None)));
+}
+
+#[test]
+fn reassemble_evicts_over_byte_budget() {
+    let addr = Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 53)));
+    let mut reassembler = Reassembler::new(16, 4, 6, Duration::from_secs(10));
+
+    let res = reassembler.insert(0, 0, 2, 0, addr.clone(), Bytes::from_static(b"abcd"));
+    assert!(matches!(res, Ok(None)));
+    let res = reassembler.insert(0, 1, 2, 0, addr, Bytes::from_static(b"efgh"));
+    assert!(matches!(res, Ok(None)));
+    assert_eq!(reassembler.buffered_bytes(), 4);
+
+    let res = reassembler.insert(0, 0, 2, 1, Address::None, Bytes::from_static(b"i"));
+    assert!(matches!(res, Ok(None)));
+}
+
+#[test]
+fn reassemble_evicts_expired() {
+    let addr = Address::SocketAddress(SocketAddr::from(([127, 0, 0, 1], 53)));
+    let mut reassembler = Reassembler::new(16, 1024, 1024, Duration::ZERO);
+
+    let res = reassembler.insert(0, 0, 2, 0, addr, Bytes::from_static(b"a"));
+    assert!(matches!(res, Ok(None)));
+    assert_eq!(reassembler.buffered_bytes(), 1);
+
+    let res = reassembler.insert(0, 0, 2, 1, Address::None, Bytes::from_static(b"b"));
+    assert!(matches!(res, Ok(None)));
+    assert_eq!(reassembler.buffered_bytes(), 1);
+}
@@ -0,0 +1,181 @@
+use bytes::BytesMut;
+use proptest::prelude::*;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use tokio::runtime::{Builder, Runtime};
+use tokio_util::codec::{Decoder, Encoder};
+use tuic_protocol::{Address, Command, CommandCodec, Decoded, ProtocolError, Reply};
+
+fn address() -> impl Strategy<Value = Address> {
+    prop_oneof![
+        ("\\PC{0,64}", any::<u16>())
+            .prop_filter("domain must fit in 255 bytes", |(domain, _)| domain.len()
+                <= 255)
+            .prop_map(|(domain, port)| Address::DomainAddress(domain, port)),
+        any::<SocketAddrV4>().prop_map(|addr| Address::SocketAddress(SocketAddr::V4(addr))),
+        any::<SocketAddrV6>()
+            .prop_map(|addr| SocketAddrV6::new(*addr.ip(), addr.port(), 0, 0))
+            .prop_map(|addr| Address::SocketAddress(SocketAddr::V6(addr))),
+    ]
+}
+
+fn reply() -> impl Strategy<Value = Reply> {
+    prop_oneof![
+        Just(Reply::Succeeded),
+        Just(Reply::Failed),
+        Just(Reply::DnsFailure),
+        Just(Reply::ConnectionRefused),
+        Just(Reply::TimedOut),
+        Just(Reply::Blocked),
+        Just(Reply::HostUnreachable),
+    ]
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        reply().prop_map(Command::new_response),
+        any::<[u8; 32]>().prop_map(Command::new_authenticate),
+        (address(), any::<bool>()).prop_map(|(addr, fast)| Command::new_connect(addr, fast)),
+        (any::<u32>(), any::<u16>(), address())
+            .prop_map(|(assoc_id, len, addr)| Command::new_packet(assoc_id, len, addr)),
+        any::<u32>().prop_map(Command::new_dissociate),
+        Just(Command::new_heartbeat()),
+    ]
+}
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().build().unwrap()
+}
+
+proptest! {
+    #[test]
+    fn address_round_trip(addr in address()) {
+        let mut buf = Vec::new();
+        addr.write_to_buf(&mut buf).unwrap();
+
+        prop_assert_eq!(buf.len(), addr.serialized_len());
+        prop_assert!(buf.len() <= Address::max_serialized_len());
+        prop_assert_eq!(Address::decode(&buf).unwrap(), Decoded::Complete(addr.clone(), buf.len()));
+        prop_assert_eq!(runtime().block_on(Address::read_from(&mut buf.as_slice())).unwrap(), addr);
+    }
+
+    #[test]
+    fn command_round_trip(cmd in command()) {
+        let mut buf = Vec::new();
+        cmd.write_to_buf(&mut buf).unwrap();
+
+        prop_assert_eq!(buf.len(), cmd.serialized_len());
+        prop_assert!(buf.len() <= Command::max_serialized_len());
+        prop_assert_eq!(Command::decode(&buf).unwrap(), Decoded::Complete(cmd.clone(), buf.len()));
+        prop_assert_eq!(runtime().block_on(Command::read_from(&mut buf.as_slice())).unwrap(), cmd);
+    }
+
+    #[test]
+    fn command_prefix_is_incomplete(cmd in command()) {
+        let mut buf = Vec::new();
+        cmd.write_to_buf(&mut buf).unwrap();
+
+        for len in 0..buf.len() {
+            match Command::decode(&buf[..len]).unwrap() {
+                Decoded::Incomplete(need) => prop_assert!(need > len && need <= buf.len()),
+                Decoded::Complete(..) => prop_assert!(false, "decoded from {} of {} bytes", len, buf.len()),
+            }
+        }
+    }
+
+    #[test]
+    fn command_decode_ignores_trailing_bytes(cmd in command(), trailing in prop::collection::vec(any::<u8>(), 0..64)) {
+        let mut buf = Vec::new();
+        cmd.write_to_buf(&mut buf).unwrap();
+        let len = buf.len();
+        buf.extend_from_slice(&trailing);
+
+        prop_assert_eq!(Command::decode(&buf).unwrap(), Decoded::Complete(cmd, len));
+    }
+
+    #[test]
+    fn command_codec_round_trip(cmds in prop::collection::vec(command(), 1..16)) {
+        let mut buf = BytesMut::new();
+
+        for cmd in &cmds {
+            CommandCodec.encode(cmd.clone(), &mut buf).unwrap();
+        }
+
+        for cmd in cmds {
+            prop_assert_eq!(CommandCodec.decode(&mut buf).unwrap(), Some(cmd));
+        }
+
+        prop_assert!(buf.is_empty());
+        prop_assert_eq!(CommandCodec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_arbitrary_bytes(buf in prop::collection::vec(any::<u8>(), 0..512)) {
+        if let Ok(Decoded::Complete(cmd, len)) = Command::decode(&buf) {
+            let mut encoded = Vec::new();
+            cmd.write_to_buf(&mut encoded).unwrap();
+            prop_assert_eq!(&encoded[..], &buf[..len]);
+        }
+
+        if let Ok(Decoded::Complete(addr, len)) = Address::decode(&buf) {
+            let mut encoded = Vec::new();
+            addr.write_to_buf(&mut encoded).unwrap();
+            prop_assert_eq!(&encoded[..], &buf[..len]);
+        }
+    }
+
+    #[test]
+    fn domain_too_long(len in 256usize..1024, port in any::<u16>(), fast in any::<bool>()) {
+        let addr = Address::DomainAddress("a".repeat(len), port);
+
+        let mut buf = Vec::new();
+        let res = addr.write_to_buf(&mut buf);
+        prop_assert!(matches!(res, Err(ProtocolError::DomainTooLong(l)) if l == len));
+        prop_assert!(buf.is_empty());
+
+        let cmd = Command::new_connect(addr, fast);
+        let res = cmd.write_to_buf(&mut buf);
+        prop_assert!(matches!(res, Err(ProtocolError::DomainTooLong(l)) if l == len));
+        prop_assert!(buf.is_empty());
+    }
+}
+
+proptest! {
+    #[test]
+    fn unsupported_version(ver in any::<u8>().prop_filter("must not be the current version", |ver| *ver != 0x04)) {
+        prop_assert!(matches!(Command::decode(&[ver]), Err(ProtocolError::UnsupportedVersion(v)) if v == ver));
+    }
+
+    #[test]
+    fn unknown_command(cmd in 0x05u8..0xf1) {
+        prop_assert!(matches!(Command::decode(&[0x04, cmd]), Err(ProtocolError::UnknownCommand(c)) if c == cmd));
+    }
+
+    #[test]
+    fn response_code_round_trip(resp in prop_oneof![0x00u8..0x06, Just(0xff)]) {
+        let reply = Reply::from_code(resp).unwrap();
+        prop_assert_eq!(reply.code(), resp);
+        prop_assert_eq!(Command::decode(&[0x04, 0xff, resp]).unwrap(), Decoded::Complete(Command::new_response(reply), 3));
+    }
+
+    #[test]
+    fn invalid_response_code(resp in 0x06u8..0xff) {
+        prop_assert!(matches!(Command::decode(&[0x04, 0xff, resp]), Err(ProtocolError::InvalidResponseCode(r)) if r == resp));
+    }
+
+    #[test]
+    fn invalid_address_type(addr_type in 0x03u8..) {
+        prop_assert!(matches!(Address::decode(&[addr_type]), Err(ProtocolError::InvalidAddressType(t)) if t == addr_type));
+    }
+}
+
+#[test]
+fn invalid_domain_encoding() {
+    let res = Address::decode(&[0x00, 0x02, 0xc3, 0x28, 0x00, 0x50]);
+    assert!(matches!(res, Err(ProtocolError::InvalidDomainEncoding(_))));
+}
+
+#[test]
+fn truncated_stream_is_io_error() {
+    let res = runtime().block_on(Command::read_from(&mut &[0x04, 0x01, 0x01, 127, 0][..]));
+    assert!(matches!(res, Err(ProtocolError::Io(_))));
+}
@@ -0,0 +1,156 @@
+use crate::cidr::Cidr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Private, loopback, link-local, multicast and reserved ranges, denied unless allowed explicitly.
+/// `169.254.0.0/16` covers the cloud metadata endpoint. The IPv6 ranges embedding IPv4 addresses (IPv4-compatible, NAT64 and 6to4) are denied as a whole
+const DEFAULT_DENY: &[(IpAddr, u8)] = &[
+    (IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(100, 64, 0, 0)), 10),
+    (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16),
+    (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12),
+    (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16),
+    (IpAddr::V4(Ipv4Addr::new(198, 18, 0, 0)), 15),
+    (IpAddr::V4(Ipv4Addr::new(224, 0, 0, 0)), 4),
+    (IpAddr::V4(Ipv4Addr::new(240, 0, 0, 0)), 4),
+    (IpAddr::V6(Ipv6Addr::UNSPECIFIED), 96),
+    (
+        IpAddr::V6(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0)),
+        96,
+    ),
+    (IpAddr::V6(Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0)), 16),
+    (IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7),
+    (IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10),
+    (IpAddr::V6(Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0)), 8),
+];
+
+/// Decides which resolved destinations the server may connect to
+pub struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    allow_ports: Vec<u16>,
+    deny_ports: Vec<u16>,
+}
+
+impl Acl {
+    pub fn new(
+        allow: Vec<Cidr>,
+        deny: Vec<Cidr>,
+        allow_ports: Vec<u16>,
+        deny_ports: Vec<u16>,
+    ) -> Self {
+        let deny = DEFAULT_DENY
+            .iter()
+            .filter_map(|(addr, prefix_len)| Cidr::new(*addr, *prefix_len))
+            .chain(deny)
+            .collect();
+
+        Self {
+            allow,
+            deny,
+            allow_ports,
+            deny_ports,
+        }
+    }
+
+    /// Ports are checked first. Then allowed ranges take precedence over denied ones
+    pub fn is_allowed(&self, addr: SocketAddr) -> bool {
+        let port = addr.port();
+
+        if self.deny_ports.contains(&port)
+            || (!self.allow_ports.is_empty() && !self.allow_ports.contains(&port))
+        {
+            return false;
+        }
+
+        let ip = addr.ip();
+
+        self.allow.iter().any(|cidr| cidr.contains(ip))
+            || !self.deny.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_allowed(acl: &Acl, addr: &str) -> bool {
+        acl.is_allowed(addr.parse().unwrap())
+    }
+
+    fn default_acl() -> Acl {
+        Acl::new(Vec::new(), Vec::new(), Vec::new(), Vec::new())
+    }
+
+    #[test]
+    fn denies_metadata_endpoint() {
+        let acl = default_acl();
+        assert!(!is_allowed(&acl, "169.254.169.254:80"));
+        assert!(!is_allowed(&acl, "[::ffff:169.254.169.254]:80"));
+        assert!(!is_allowed(&acl, "[::a9fe:a9fe]:80"));
+        assert!(!is_allowed(&acl, "[64:ff9b::a9fe:a9fe]:80"));
+        assert!(!is_allowed(&acl, "[2002:a9fe:a9fe::1]:80"));
+    }
+
+    #[test]
+    fn denies_private_and_reserved_ranges() {
+        let acl = default_acl();
+
+        for addr in [
+            "127.0.0.1:80",
+            "10.1.2.3:80",
+            "192.168.1.1:80",
+            "198.19.255.255:80",
+            "[::]:80",
+            "[::1]:80",
+            "[fd00::1]:80",
+            "[fe80::1]:80",
+        ] {
+            assert!(!is_allowed(&acl, addr), "{addr}");
+        }
+
+        assert!(is_allowed(&acl, "8.8.8.8:53"));
+        assert!(is_allowed(&acl, "[2606:4700:4700::1111]:53"));
+    }
+
+    #[test]
+    fn allow_overrides_default_deny() {
+        let allow = vec![Cidr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8).unwrap()];
+        let acl = Acl::new(allow, Vec::new(), Vec::new(), Vec::new());
+
+        assert!(is_allowed(&acl, "10.1.2.3:80"));
+        assert!(!is_allowed(&acl, "192.168.1.1:80"));
+    }
+
+    #[test]
+    fn allow_overrides_deny() {
+        let net = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 0));
+        let acl = Acl::new(
+            vec![Cidr::new(net, 32).unwrap()],
+            vec![Cidr::new(net, 24).unwrap()],
+            Vec::new(),
+            Vec::new(),
+        );
+
+        assert!(is_allowed(&acl, "8.8.8.0:53"));
+        assert!(!is_allowed(&acl, "8.8.8.8:53"));
+    }
+
+    #[test]
+    fn port_rules() {
+        let acl = Acl::new(Vec::new(), Vec::new(), Vec::new(), vec![25]);
+        assert!(!is_allowed(&acl, "8.8.8.8:25"));
+        assert!(is_allowed(&acl, "8.8.8.8:80"));
+
+        let acl = Acl::new(Vec::new(), Vec::new(), vec![80, 443], vec![443]);
+        assert!(is_allowed(&acl, "8.8.8.8:80"));
+        assert!(!is_allowed(&acl, "8.8.8.8:443"));
+        assert!(!is_allowed(&acl, "8.8.8.8:22"));
+
+        // ports are checked before addresses
+        let allow = vec![Cidr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).unwrap()];
+        let acl = Acl::new(allow, Vec::new(), Vec::new(), vec![25]);
+        assert!(!is_allowed(&acl, "8.8.8.8:25"));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::certificate::write_private;
+use instant_acme::{
+    Account, AuthorizationStatus, Challenge as AcmeChallenge, ChallengeType,
+    Error as InstantAcmeError, Identifier, KeyAuthorization, NewAccount, NewOrder, Order,
+    OrderStatus,
+};
+use rcgen::{
+    Certificate as RcgenCertificate, CertificateParams, CustomExtension, DistinguishedName,
+    RcgenError,
+};
+use rustls::{Certificate, Error as RustlsError, PrivateKey, ServerConfig as RustlsServerConfig};
+use serde_json::Error as JsonError;
+use std::{
+    fs,
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Let's Encrypt certificates are valid for 90 days
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const ORDER_TIMEOUT: Duration = Duration::from_secs(180);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const VALIDATION_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const MAX_HTTP_REQUEST_HEAD_SIZE: usize = 8192;
+
+/// How the CA validates control of the domain
+#[derive(Clone, Copy)]
+pub enum Challenge {
+    TlsAlpn01,
+    Http01,
+}
+
+impl Challenge {
+    pub fn default_port(self) -> u16 {
+        match self {
+            Challenge::TlsAlpn01 => 443,
+            Challenge::Http01 => 80,
+        }
+    }
+
+    fn kind(self) -> ChallengeType {
+        match self {
+            Challenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+            Challenge::Http01 => ChallengeType::Http01,
+        }
+    }
+}
+
+/// Obtains and renews the certificate of a domain from an ACME CA, keeping it in a cache directory
+pub struct Acme {
+    domain: String,
+    email: String,
+    directory: String,
+    challenge: Challenge,
+    challenge_addr: SocketAddr,
+    cache_dir: PathBuf,
+}
+
+impl Acme {
+    pub fn new(
+        domain: String,
+        email: String,
+        directory: String,
+        challenge: Challenge,
+        challenge_addr: SocketAddr,
+        cache_dir: PathBuf,
+    ) -> Self {
+        Self {
+            domain,
+            email,
+            directory,
+            challenge,
+            challenge_addr,
+            cache_dir,
+        }
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn certificate_path(&self) -> PathBuf {
+        self.cache_dir.join(format!("{}.crt", self.domain))
+    }
+
+    pub fn private_key_path(&self) -> PathBuf {
+        self.cache_dir.join(format!("{}.key", self.domain))
+    }
+
+    /// Accounts belong to a CA, so they are cached by the directory URL
+    fn account_path(&self) -> PathBuf {
+        let hash = blake3::hash(self.directory.as_bytes()).to_hex();
+        self.cache_dir.join(format!("account-{}.json", &hash[..16]))
+    }
+
+    /// Renews the certificate whenever it is due, notifying `renewed` once the new one is saved
+    pub async fn run(self, renewed: Arc<Notify>) {
+        loop {
+            time::sleep(self.renew_in()).await;
+
+            log::info!("[acme] Requesting a certificate for {}", self.domain);
+
+            let res = time::timeout(ORDER_TIMEOUT, self.issue())
+                .await
+                .unwrap_or(Err(AcmeError::Timeout));
+
+            match res {
+                Ok(()) => {
+                    log::info!("[acme] Obtained a certificate for {}", self.domain);
+                    renewed.notify_one();
+                }
+                Err(err) => {
+                    log::error!(
+                        "[acme] Failed to obtain a certificate for {}, retrying in {RETRY_INTERVAL:?}: {err}",
+                        self.domain
+                    );
+                    time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// The age of the cached certificate is taken from its modification time
+    fn renew_in(&self) -> Duration {
+        fs::metadata(self.certificate_path())
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(Duration::ZERO, |age| RENEW_AFTER.saturating_sub(age))
+    }
+
+    async fn issue(&self) -> Result<(), AcmeError> {
+        fs::create_dir_all(&self.cache_dir)?;
+
+        let account = self.account().await?;
+        let identifier = Identifier::Dns(self.domain.clone());
+
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &[identifier],
+            })
+            .await?;
+
+        let authorizations = order.authorizations().await?;
+
+        match authorizations
+            .iter()
+            .find(|authz| authz.status != AuthorizationStatus::Valid)
+        {
+            Some(authz) if authz.status == AuthorizationStatus::Pending => {
+                let challenge = authz
+                    .challenges
+                    .iter()
+                    .find(|challenge| challenge.r#type == self.challenge.kind())
+                    .ok_or(AcmeError::ChallengeNotOffered)?;
+
+                let responder = Arc::new(Responder::new(
+                    self.challenge,
+                    &self.domain,
+                    challenge,
+                    order.key_authorization(challenge),
+                )?);
+
+                let listener = TcpListener::bind(self.challenge_addr).await?;
+
+                tokio::select! {
+                    res = Self::validate(&mut order, &challenge.url) => res?,
+                    err = responder.serve(listener) => return Err(err.into()),
+                }
+            }
+            Some(_) => return Err(AcmeError::AuthorizationFailed),
+            None => {}
+        }
+
+        let mut params = CertificateParams::new(vec![self.domain.clone()]);
+        params.distinguished_name = DistinguishedName::new();
+        let cert = RcgenCertificate::from_params(params)?;
+
+        order.finalize(&cert.serialize_request_der()?).await?;
+
+        let chain = loop {
+            match order.certificate().await? {
+                Some(chain) => break chain,
+                None => time::sleep(POLL_INTERVAL).await,
+            }
+        };
+
+        write_private(&self.private_key_path(), &cert.serialize_private_key_pem())?;
+        write_private(&self.certificate_path(), &chain)?;
+
+        Ok(())
+    }
+
+    async fn account(&self) -> Result<Account, AcmeError> {
+        let path = self.account_path();
+
+        match fs::read_to_string(&path) {
+            Ok(credentials) => {
+                return Ok(Account::from_credentials(serde_json::from_str(
+                    &credentials,
+                )?)?)
+            }
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            Err(_) => {}
+        }
+
+        let contact = format!("mailto:{}", self.email);
+
+        let account = Account::create(
+            &NewAccount {
+                contact: &[&contact],
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            &self.directory,
+        )
+        .await?;
+
+        write_private(&path, &serde_json::to_string(&account.credentials())?)?;
+        log::info!("[acme] Registered an account with {}", self.directory);
+
+        Ok(account)
+    }
+
+    /// Waits for the order to be ready for finalization once the challenge is validated
+    async fn validate(order: &mut Order, challenge_url: &str) -> Result<(), AcmeError> {
+        order.set_challenge_ready(challenge_url).await?;
+
+        loop {
+            match order.refresh().await?.status {
+                OrderStatus::Ready => return Ok(()),
+                OrderStatus::Invalid => return Err(AcmeError::AuthorizationFailed),
+                _ => time::sleep(POLL_INTERVAL).await,
+            }
+        }
+    }
+}
+
+/// Answers the validation requests of the CA for a challenge
+enum Responder {
+    TlsAlpn01(TlsAcceptor),
+    Http01 {
+        token: String,
+        key_authorization: String,
+    },
+}
+
+impl Responder {
+    fn new(
+        kind: Challenge,
+        domain: &str,
+        challenge: &AcmeChallenge,
+        key_authorization: KeyAuthorization,
+    ) -> Result<Self, AcmeError> {
+        match kind {
+            Challenge::TlsAlpn01 => {
+                // RFC 8737, a self-signed certificate carrying the digest of the key authorization
+                let mut params = CertificateParams::new(vec![domain.to_owned()]);
+                params.custom_extensions = vec![CustomExtension::new_acme_identifier(
+                    key_authorization.digest().as_ref(),
+                )];
+                let cert = RcgenCertificate::from_params(params)?;
+
+                let mut config = RustlsServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_single_cert(
+                        vec![Certificate(cert.serialize_der()?)],
+                        PrivateKey(cert.serialize_private_key_der()),
+                    )?;
+
+                config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+
+                Ok(Self::TlsAlpn01(TlsAcceptor::from(Arc::new(config))))
+            }
+            Challenge::Http01 => Ok(Self::Http01 {
+                token: challenge.token.clone(),
+                key_authorization: key_authorization.as_str().to_owned(),
+            }),
+        }
+    }
+
+    /// Returns only if accepting fails
+    async fn serve(self: Arc<Self>, listener: TcpListener) -> IoError {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => return err,
+            };
+
+            let responder = self.clone();
+
+            tokio::spawn(async move {
+                let res =
+                    time::timeout(VALIDATION_CONNECTION_TIMEOUT, responder.respond(stream)).await;
+
+                match res {
+                    Ok(Ok(())) => log::debug!("[acme] Answered a validation request from {addr}"),
+                    Ok(Err(err)) => log::debug!("[acme] Validation request from {addr}: {err}"),
+                    Err(_) => log::debug!("[acme] Validation request from {addr} timed out"),
+                }
+            });
+        }
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> IoResult<()> {
+        match self {
+            // the CA only checks the certificate in the handshake
+            Self::TlsAlpn01(acceptor) => acceptor.accept(stream).await.map(|_| ()),
+            Self::Http01 {
+                token,
+                key_authorization,
+            } => {
+                let mut buf = vec![0; MAX_HTTP_REQUEST_HEAD_SIZE];
+                let mut len = 0;
+
+                while !buf[..len].windows(4).any(|window| window == b"\r\n\r\n") {
+                    if len == buf.len() {
+                        return Err(IoError::new(
+                            ErrorKind::InvalidData,
+                            "request head too long",
+                        ));
+                    }
+
+                    match stream.read(&mut buf[len..]).await? {
+                        0 => return Err(IoError::from(ErrorKind::UnexpectedEof)),
+                        n => len += n,
+                    }
+                }
+
+                let path = String::from_utf8_lossy(&buf[..len])
+                    .split_whitespace()
+                    .nth(1)
+                    .map(str::to_owned);
+
+                let (status, body) =
+                    if path.as_deref() == Some(&format!("/.well-known/acme-challenge/{token}")) {
+                        ("200 OK", key_authorization.as_str())
+                    } else {
+                        ("404 Not Found", "")
+                    };
+
+                let resp = format!(
+                    "HTTP/1.1 {status}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+
+                stream.write_all(resp.as_bytes()).await?;
+                stream.shutdown().await
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AcmeError {
+    #[error(transparent)]
+    Io(#[from] IoError),
+    #[error(transparent)]
+    Acme(#[from] InstantAcmeError),
+    #[error(transparent)]
+    Rcgen(#[from] RcgenError),
+    #[error(transparent)]
+    Rustls(#[from] RustlsError),
+    #[error(transparent)]
+    Json(#[from] JsonError),
+    #[error("The CA does not offer the configured challenge")]
+    ChallengeNotOffered,
+    #[error("The CA did not authorize the domain")]
+    AuthorizationFailed,
+    #[error("Timed out")]
+    Timeout,
+}
@@ -0,0 +1,287 @@
+use crate::{certificate, static_site};
+use quinn::{
+    ClientConfig, Connection as QuinnConnection, ConnectionError, Endpoint, ReadError, RecvStream,
+    SendStream, ServerConfig, VarInt, WriteError,
+};
+use rustls::{
+    version::TLS13, Certificate, ClientConfig as RustlsClientConfig, RootCertStore,
+    ServerConfig as RustlsServerConfig,
+};
+use std::{
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+const H3_ALPN: &[u8] = b"h3";
+const H3_NO_ERROR: VarInt = VarInt::from_u32(0x100);
+const SERVER_NAME_STATIC_SITE: &str = "localhost";
+const COPY_BUF_SIZE: usize = 16384;
+
+/// The HTTP/3 site unauthenticated clients are handed over to
+pub enum Site {
+    /// A directory served by the built-in HTTP/3 server
+    StaticDir(PathBuf),
+    /// An HTTP/3 server, with the certificates trusted alongside the native CA roots
+    Backend {
+        addr: SocketAddr,
+        server_name: String,
+        certificates: Vec<Certificate>,
+    },
+}
+
+/// A stream of the client read from before the connection was handed over, with the bytes already read
+pub enum PendingStream {
+    Uni(Vec<u8>, RecvStream),
+    Bi(Vec<u8>, SendStream, RecvStream),
+}
+
+/// Relays connections that fail TUIC authentication, or that negotiate an ALPN protocol of the site, to an HTTP/3 site stream by stream, so that the server looks like an ordinary web server to probes
+pub struct Camouflage {
+    endpoint: Endpoint,
+    site_addr: SocketAddr,
+    server_name: String,
+    alpn_protocols: Vec<Vec<u8>>,
+}
+
+impl Camouflage {
+    /// Starts the built-in HTTP/3 server of a static directory on a loopback address
+    pub fn new(site: Site, alpn_protocols: Vec<Vec<u8>>) -> IoResult<Self> {
+        let (site_addr, server_name, roots) = match site {
+            Site::StaticDir(root) => {
+                // symlinks are checked against the canonical root when files are served
+                let root = std::fs::canonicalize(root)?;
+                let (certs, priv_key) = certificate::generate_self_signed(SERVER_NAME_STATIC_SITE);
+
+                let mut roots = RootCertStore::empty();
+                roots.add_parsable_certificates(&[certs[0].0.clone()]);
+
+                let mut crypto = RustlsServerConfig::builder()
+                    .with_safe_default_cipher_suites()
+                    .with_safe_default_kx_groups()
+                    .with_protocol_versions(&[&TLS13])
+                    .unwrap()
+                    .with_no_client_auth()
+                    .with_single_cert(certs, priv_key)
+                    .map_err(|err| IoError::new(ErrorKind::Other, err))?;
+
+                crypto.alpn_protocols = vec![H3_ALPN.to_vec()];
+
+                let endpoint = Endpoint::server(
+                    ServerConfig::with_crypto(Arc::new(crypto)),
+                    SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                )?;
+
+                let addr = endpoint.local_addr()?;
+                tokio::spawn(static_site::serve(endpoint, root));
+
+                (addr, String::from(SERVER_NAME_STATIC_SITE), roots)
+            }
+            Site::Backend {
+                addr,
+                server_name,
+                certificates,
+            } => {
+                let mut roots = RootCertStore::empty();
+
+                let certs = rustls_native_certs::load_native_certs()?
+                    .into_iter()
+                    .map(|cert| cert.0)
+                    .chain(certificates.into_iter().map(|cert| cert.0))
+                    .collect::<Vec<_>>();
+
+                roots.add_parsable_certificates(&certs);
+
+                (addr, server_name, roots)
+            }
+        };
+
+        let mut crypto = RustlsClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        crypto.alpn_protocols = vec![H3_ALPN.to_vec()];
+
+        let bind_addr = match site_addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+
+        let mut endpoint = Endpoint::client(bind_addr)?;
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+
+        Ok(Self {
+            endpoint,
+            site_addr,
+            server_name,
+            alpn_protocols,
+        })
+    }
+
+    /// Whether connections negotiating the ALPN protocol go to the site right away
+    pub fn is_site_protocol(&self, protocol: &[u8]) -> bool {
+        self.alpn_protocols.iter().any(|alpn| alpn == protocol)
+    }
+
+    /// Relays the connection to the site until either side closes it, starting with the pending streams
+    pub async fn relay(
+        &self,
+        conn: QuinnConnection,
+        mut pending: UnboundedReceiver<PendingStream>,
+    ) -> ConnectionError {
+        let site = match self.connect().await {
+            Ok(site) => site,
+            Err(err) => {
+                conn.close(H3_NO_ERROR, b"");
+                return err;
+            }
+        };
+
+        let err = tokio::select! {
+            err = Self::relay_pending(&site, &mut pending) => err,
+            err = Self::relay_uni_streams(&conn, &site) => err,
+            err = Self::relay_bi_streams(&conn, &site) => err,
+            err = Self::relay_uni_streams(&site, &conn) => err,
+            err = Self::relay_datagrams(&conn, &site) => err,
+            err = Self::relay_datagrams(&site, &conn) => err,
+        };
+
+        // the side still open is closed as the other one was
+        let (code, reason) = match &err {
+            ConnectionError::ApplicationClosed(close) => (close.error_code, close.reason.to_vec()),
+            _ => (H3_NO_ERROR, Vec::new()),
+        };
+
+        conn.close(code, &reason);
+        site.close(code, &reason);
+
+        err
+    }
+
+    async fn connect(&self) -> Result<QuinnConnection, ConnectionError> {
+        match self.endpoint.connect(self.site_addr, &self.server_name) {
+            Ok(connecting) => connecting.await,
+            Err(err) => {
+                log::error!("[camouflage] Failed to connect to the site: {err}");
+                Err(ConnectionError::LocallyClosed)
+            }
+        }
+    }
+
+    async fn relay_pending(
+        site: &QuinnConnection,
+        pending: &mut UnboundedReceiver<PendingStream>,
+    ) -> ConnectionError {
+        while let Some(stream) = pending.recv().await {
+            match stream {
+                PendingStream::Uni(read, recv) => {
+                    let send = match site.open_uni().await {
+                        Ok(send) => send,
+                        Err(err) => return err,
+                    };
+
+                    tokio::spawn(copy(read, recv, send));
+                }
+                PendingStream::Bi(read, client_send, client_recv) => {
+                    let (site_send, site_recv) = match site.open_bi().await {
+                        Ok(stream) => stream,
+                        Err(err) => return err,
+                    };
+
+                    tokio::spawn(copy(read, client_recv, site_send));
+                    tokio::spawn(copy(Vec::new(), site_recv, client_send));
+                }
+            }
+        }
+
+        // the connection stopped handing over streams, so only new ones are relayed from now on
+        site.closed().await
+    }
+
+    async fn relay_uni_streams(from: &QuinnConnection, to: &QuinnConnection) -> ConnectionError {
+        loop {
+            let recv = match from.accept_uni().await {
+                Ok(recv) => recv,
+                Err(err) => return err,
+            };
+
+            let send = match to.open_uni().await {
+                Ok(send) => send,
+                Err(err) => return err,
+            };
+
+            tokio::spawn(copy(Vec::new(), recv, send));
+        }
+    }
+
+    /// HTTP/3 servers never open bidirectional streams
+    async fn relay_bi_streams(client: &QuinnConnection, site: &QuinnConnection) -> ConnectionError {
+        loop {
+            let (client_send, client_recv) = match client.accept_bi().await {
+                Ok(stream) => stream,
+                Err(err) => return err,
+            };
+
+            let (site_send, site_recv) = match site.open_bi().await {
+                Ok(stream) => stream,
+                Err(err) => return err,
+            };
+
+            tokio::spawn(copy(Vec::new(), client_recv, site_send));
+            tokio::spawn(copy(Vec::new(), site_recv, client_send));
+        }
+    }
+
+    async fn relay_datagrams(from: &QuinnConnection, to: &QuinnConnection) -> ConnectionError {
+        loop {
+            match from.read_datagram().await {
+                Ok(datagram) => {
+                    let _ = to.send_datagram(datagram);
+                }
+                Err(err) => return err,
+            }
+        }
+    }
+}
+
+/// Copies a stream after writing the bytes already read from it, passing resets and stops on to the other side
+async fn copy(read: Vec<u8>, mut recv: RecvStream, mut send: SendStream) {
+    let mut buf = read;
+    let mut len = buf.len();
+
+    if buf.len() < COPY_BUF_SIZE {
+        buf.resize(COPY_BUF_SIZE, 0);
+    }
+
+    loop {
+        if len > 0 {
+            match send.write_all(&buf[..len]).await {
+                Ok(()) => {}
+                Err(WriteError::Stopped(code)) => {
+                    let _ = recv.stop(code);
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
+
+        match recv.read(&mut buf).await {
+            Ok(Some(n)) => len = n,
+            Ok(None) => {
+                let _ = send.finish().await;
+                return;
+            }
+            Err(ReadError::Reset(code)) => {
+                let _ = send.reset(code);
+                return;
+            }
+            Err(_) => return,
+        }
+    }
+}
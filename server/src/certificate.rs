@@ -1,11 +1,21 @@
-use rustls::{Certificate, PrivateKey};
+use ring::digest;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, Error as RustlsError, PrivateKey,
+};
 use rustls_pemfile::Item;
 use std::{
-    fs::{self, File},
-    io::{BufReader, Error as IoError},
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Error as IoError, Write},
+    path::Path,
+    sync::Arc,
 };
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
 
-pub fn load_certificates(path: &str) -> Result<Vec<Certificate>, IoError> {
+pub fn load_certificates(path: impl AsRef<Path>) -> Result<Vec<Certificate>, IoError> {
+    let path = path.as_ref();
     let mut file = BufReader::new(File::open(path)?);
     let mut certs = Vec::new();
 
@@ -22,7 +32,8 @@ pub fn load_certificates(path: &str) -> Result<Vec<Certificate>, IoError> {
     Ok(certs)
 }
 
-pub fn load_private_key(path: &str) -> Result<PrivateKey, IoError> {
+pub fn load_private_key(path: impl AsRef<Path>) -> Result<PrivateKey, IoError> {
+    let path = path.as_ref();
     let mut file = BufReader::new(File::open(path)?);
     let mut priv_key = None;
 
@@ -38,6 +49,40 @@ pub fn load_private_key(path: &str) -> Result<PrivateKey, IoError> {
         .map(PrivateKey)
 }
 
+/// Loads the self signed certificate of `server_name` saved in `dir`, generating and saving one if there is none
+pub fn load_or_generate_self_signed(
+    dir: &Path,
+    server_name: &str,
+) -> Result<(Vec<Certificate>, PrivateKey), IoError> {
+    let cert_path = dir.join(format!("{server_name}.crt"));
+    let priv_key_path = dir.join(format!("{server_name}.key"));
+
+    if cert_path.exists() && priv_key_path.exists() {
+        return Ok((
+            load_certificates(&cert_path)?,
+            load_private_key(&priv_key_path)?,
+        ));
+    }
+
+    let self_signed = rcgen::generate_simple_self_signed(vec![server_name.to_string()])
+        .expect("failed to generate self signed certificate and private key");
+
+    let cert_pem = self_signed
+        .serialize_pem()
+        .expect("failed to serialize self signed certificate");
+
+    fs::create_dir_all(dir)?;
+    write_private(&priv_key_path, &self_signed.serialize_private_key_pem())?;
+    write_private(&cert_path, &cert_pem)?;
+
+    // every serialization signs the certificate again, so serve the one that was saved
+    Ok((
+        load_certificates(&cert_path)?,
+        load_private_key(&priv_key_path)?,
+    ))
+}
+
+/// Generates a certificate not saved anywhere
 pub fn generate_self_signed(server_name: &str) -> (Vec<Certificate>, PrivateKey) {
     let self_signed = rcgen::generate_simple_self_signed(vec![server_name.to_string()])
         .expect("failed to generate self signed certificate and private key");
@@ -51,3 +96,113 @@ pub fn generate_self_signed(server_name: &str) -> (Vec<Certificate>, PrivateKey)
 
     (vec![cert], key)
 }
+
+/// The SHA-256 digest of the certificate in colon-separated hex, as shown by browsers and `openssl x509 -fingerprint`
+pub fn fingerprint(cert: &Certificate) -> String {
+    digest::digest(&digest::SHA256, &cert.0)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The DNS names and email addresses in the subject alternative names of a certificate, followed by the common names of its subject
+pub fn names(cert: &Certificate) -> Vec<String> {
+    let cert = match parse_x509_certificate(&cert.0) {
+        Ok((_, cert)) => cert,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut names = Vec::new();
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::DNSName(name) | GeneralName::RFC822Name(name) = name {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    names.extend(
+        cert.subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .map(str::to_owned),
+    );
+
+    names
+}
+
+/// Picks the certificate by the server name (SNI) the client asks for, falling back to the default one
+pub struct ServerNameResolver {
+    default: Arc<CertifiedKey>,
+    server_names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ServerNameResolver {
+    pub fn new(certs: Vec<Certificate>, priv_key: &PrivateKey) -> Result<Self, RustlsError> {
+        Ok(Self {
+            default: Arc::new(certified_key(certs, priv_key)?),
+            server_names: HashMap::new(),
+        })
+    }
+
+    /// A server name starting with `*.` matches any single label in its place
+    pub fn insert(
+        &mut self,
+        server_name: &str,
+        certs: Vec<Certificate>,
+        priv_key: &PrivateKey,
+    ) -> Result<(), RustlsError> {
+        let key = Arc::new(certified_key(certs, priv_key)?);
+        self.server_names
+            .insert(server_name.to_ascii_lowercase(), key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ServerNameResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello
+            .server_name()
+            .and_then(|server_name| match_server_name(&self.server_names, server_name));
+
+        Some(key.unwrap_or(&self.default).clone())
+    }
+}
+
+/// Looks up a server name case-insensitively, then the wildcard name of its parent domain. Keys must be in lowercase
+pub fn match_server_name<'a, T>(map: &'a HashMap<String, T>, server_name: &str) -> Option<&'a T> {
+    let server_name = server_name.to_ascii_lowercase();
+
+    map.get(&server_name).or_else(|| {
+        let (_, parent) = server_name.split_once('.')?;
+        map.get(&format!("*.{parent}"))
+    })
+}
+
+fn certified_key(
+    certs: Vec<Certificate>,
+    priv_key: &PrivateKey,
+) -> Result<CertifiedKey, RustlsError> {
+    let key = sign::any_supported_type(priv_key)
+        .map_err(|_| RustlsError::General(String::from("invalid private key")))?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Writes a file readable by the owner only, replacing it atomically
+pub fn write_private(path: &Path, contents: &str) -> Result<(), IoError> {
+    let tmp_path = path.with_extension("tmp");
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+
+    opts.open(&tmp_path)?.write_all(contents.as_bytes())?;
+    fs::rename(tmp_path, path)
+}
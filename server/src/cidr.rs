@@ -0,0 +1,103 @@
+use std::net::IpAddr;
+
+/// An IP network in CIDR notation, e.g. `10.0.0.0/8` or `fc00::/7`
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns `None` if the prefix length is longer than the address
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        (prefix_len <= max_len).then(|| Self { addr, prefix_len })
+    }
+
+    /// IPv4-mapped IPv6 addresses are matched as IPv4 addresses
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => match v6.segments() {
+                [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(v6.to_ipv4().unwrap()),
+                _ => addr,
+            },
+            addr => addr,
+        };
+
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn cidr(addr: &str, prefix_len: u8) -> Cidr {
+        Cidr::new(addr.parse().unwrap(), prefix_len).unwrap()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_len_is_bounded() {
+        assert!(Cidr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 32).is_some());
+        assert!(Cidr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 33).is_none());
+        assert!(Cidr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 128).is_some());
+        assert!(Cidr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 129).is_none());
+    }
+
+    #[test]
+    fn zero_prefix_contains_the_whole_family() {
+        assert!(cidr("0.0.0.0", 0).contains(ip("255.255.255.255")));
+        assert!(cidr("0.0.0.0", 0).contains(ip("1.2.3.4")));
+        assert!(!cidr("0.0.0.0", 0).contains(ip("2001:db8::1")));
+        assert!(cidr("::", 0).contains(ip("2001:db8::1")));
+        assert!(!cidr("::", 0).contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn full_prefix_contains_one_address() {
+        assert!(cidr("192.0.2.1", 32).contains(ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1", 32).contains(ip("192.0.2.2")));
+        assert!(cidr("2001:db8::1", 128).contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1", 128).contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn partial_prefix() {
+        assert!(cidr("10.0.0.0", 8).contains(ip("10.255.255.255")));
+        assert!(!cidr("10.0.0.0", 8).contains(ip("11.0.0.0")));
+        assert!(cidr("172.16.0.0", 12).contains(ip("172.31.0.1")));
+        assert!(!cidr("172.16.0.0", 12).contains(ip("172.32.0.1")));
+        assert!(cidr("fc00::", 7).contains(ip("fdff::1")));
+        assert!(!cidr("fc00::", 7).contains(ip("fe00::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_ipv6_matches_ipv4() {
+        assert!(cidr("10.0.0.0", 8).contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0", 8).contains(ip("::ffff:11.1.2.3")));
+        assert!(!cidr("::ffff:0.0.0.0", 96).contains(ip("::ffff:10.1.2.3")));
+    }
+}
@@ -1,129 +1,372 @@
-use super::connection::socks5_out;
-use crate::certificate;
+use crate::{
+    acl::Acl,
+    acme::{Acme, Challenge as AcmeChallenge},
+    camouflage::Site,
+    certificate,
+    cidr::Cidr,
+    connection::UdpRelayConfig,
+    logging::LogFormat,
+    outbound::{Direct, NatFiltering, Outbound, Socks5},
+    resolver::{IpPreference, Resolver, Upstream},
+    router::{Route, Router, Rule},
+    server::Listener,
+    traffic::{self, Quota, QuotaPeriod, Traffic},
+    user::{User, Users},
+};
 use getopts::{Fail, Options};
 use log::{LevelFilter, ParseLevelError};
 use quinn::{
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
     IdleTimeout, ServerConfig, VarInt,
 };
-use rustls::{version::TLS13, Error as RustlsError, ServerConfig as RustlsServerConfig};
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+    version::TLS13,
+    Certificate, Error as RustlsError, PrivateKey, RootCertStore,
+    ServerConfig as RustlsServerConfig,
+};
 use serde::{de::Error as DeError, Deserialize, Deserializer};
 use serde_json::Error as JsonError;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     env::ArgsOs,
+    ffi::OsString,
     fmt::Display,
     fs::File,
     io::Error as IoError,
+    mem,
     net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr},
     num::ParseIntError,
+    path::{Path, PathBuf},
     str::FromStr,
     sync::Arc,
     time::Duration,
 };
 use thiserror::Error;
+use tokio::sync::Semaphore;
+use trust_dns_resolver::error::ResolveError;
+use uuid::Uuid;
 
 pub struct Config {
     pub server_config: ServerConfig,
-    pub listen_addr: SocketAddr,
-    pub token: HashSet<[u8; 32]>,
+    pub listeners: Vec<Listener>,
+    pub users: Users,
+    pub reloader: Reloader,
+    pub watched_files: Vec<PathBuf>,
+    pub acme: Option<Acme>,
+    pub camouflage: Option<Site>,
+    pub camouflage_alpn: Vec<Vec<u8>>,
+    pub router: Router,
     pub authentication_timeout: Duration,
-    pub max_udp_relay_packet_size: usize,
+    pub require_session_bound_auth: bool,
+    pub udp_relay_config: UdpRelayConfig,
+    pub traffic_accounting_file: Option<PathBuf>,
+    pub drain_timeout: Duration,
+    pub metrics: Option<SocketAddr>,
     pub log_level: LevelFilter,
+    pub log_format: LogFormat,
 }
 
 impl Config {
     pub fn parse(args: ArgsOs) -> Result<Self, ConfigError> {
-        let raw = RawConfig::parse(args)?;
+        let args = args.collect::<Vec<_>>();
+        let mut raw = RawConfig::parse(args.clone())?;
 
-        if let Some(socks5) = raw.socks5 {
-            socks5_out::set_server(socks5.parse().unwrap());
+        #[cfg(all(unix, not(target_os = "android")))]
+        {
+            let _ = realm_syscall::bump_nofile_limit();
+            if let Ok((soft, hard)) = realm_syscall::get_nofile_limit() {
+                println!("fd limit: {soft}, {hard}")
+            }
         }
 
-        let server_config = {
-            let cert_path = raw.certificate.unwrap();
-            let priv_key_path = raw.private_key.unwrap();
+        let mut self_signed = None;
 
-            let (certs, priv_key) = if cert_path != priv_key_path {
-                let certs = certificate::load_certificates(&cert_path)
-                    .map_err(|err| ConfigError::Io(cert_path, err))?;
-                let priv_key = certificate::load_private_key(&priv_key_path)
-                    .map_err(|err| ConfigError::Io(priv_key_path, err))?;
-                (certs, priv_key)
-            } else {
-                eprintln!("use self signed certificate {}", &cert_path);
-                certificate::generate_self_signed(&cert_path)
-            };
+        let Reloaded {
+            server_config,
+            users,
+            watched_files,
+        } = raw.take_reloadable(&mut self_signed)?;
 
-            let mut crypto = RustlsServerConfig::builder()
-                .with_safe_default_cipher_suites()
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[&TLS13])
-                .unwrap()
-                .with_no_client_auth()
-                .with_single_cert(certs, priv_key)?;
+        #[cfg(unix)]
+        if raw.daemon {
+            realm_syscall::daemonize("tuic is running in the background.");
+        }
 
-            crypto.max_early_data_size = u32::MAX;
-            crypto.alpn_protocols = raw.alpn.into_iter().map(|alpn| alpn.into_bytes()).collect();
+        let reloader = Reloader { args, self_signed };
 
-            let mut config = ServerConfig::with_crypto(Arc::new(crypto));
-            let transport = Arc::get_mut(&mut config.transport).unwrap();
+        let listeners = if raw.listen.is_empty() {
+            vec![Listener {
+                addr: SocketAddr::from((raw.ip, raw.port.unwrap())),
+                ipv6_only: false,
+            }]
+        } else {
+            raw.listen
+                .iter()
+                .map(|listen| Listener {
+                    addr: listen.address,
+                    ipv6_only: listen.ipv6_only,
+                })
+                .collect()
+        };
 
-            match raw.congestion_controller {
-                CongestionController::Bbr => {
-                    transport.congestion_controller_factory(Arc::new(BbrConfig::default()));
-                }
-                CongestionController::Cubic => {
-                    transport.congestion_controller_factory(Arc::new(CubicConfig::default()));
-                }
-                CongestionController::NewReno => {
-                    transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()));
-                }
+        let acme = raw.acme.as_ref().map(|acme| acme.build(raw.ip));
+
+        let (camouflage, camouflage_alpn) = match raw.camouflage {
+            Some(camouflage) => {
+                let site = match (camouflage.static_dir, camouflage.backend) {
+                    (Some(dir), None) => Site::StaticDir(PathBuf::from(dir)),
+                    (None, Some(addr)) => Site::Backend {
+                        addr,
+                        server_name: camouflage
+                            .server_name
+                            .ok_or(ConfigError::MissingOption("camouflage server name"))?,
+                        certificates: match camouflage.certificate {
+                            Some(path) => certificate::load_certificates(&path)
+                                .map_err(|err| ConfigError::Io(path, err))?,
+                            None => Vec::new(),
+                        },
+                    },
+                    _ => {
+                        return Err(ConfigError::InvalidCamouflage(
+                            "set one of static_dir and backend",
+                        ))
+                    }
+                };
+
+                let alpn = camouflage
+                    .alpn
+                    .into_iter()
+                    .map(String::into_bytes)
+                    .collect();
+
+                (Some(site), alpn)
             }
+            None => (None, Vec::new()),
+        };
 
-            transport
-                .max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(raw.max_idle_time))));
-            transport.max_concurrent_bidi_streams(0x10000u32.into());
+        let acl = {
+            let parse_cidrs = |cidrs: Vec<String>| {
+                cidrs
+                    .iter()
+                    .map(|cidr| cidr.parse())
+                    .collect::<Result<_, ConfigError>>()
+            };
 
-            config
+            Acl::new(
+                parse_cidrs(raw.acl.allow)?,
+                parse_cidrs(raw.acl.deny)?,
+                raw.acl.allow_ports,
+                raw.acl.deny_ports,
+            )
         };
 
-        let listen_addr = SocketAddr::from((raw.ip, raw.port.unwrap()));
+        let resolver = {
+            let upstreams = raw
+                .dns
+                .upstreams
+                .into_iter()
+                .map(|upstream| match (upstream.kind, upstream.name) {
+                    (UpstreamType::Udp, None) => Ok(Upstream::Udp(upstream.address)),
+                    (UpstreamType::Tcp, None) => Ok(Upstream::Tcp(upstream.address)),
+                    (UpstreamType::Https, Some(name)) => {
+                        Ok(Upstream::Https(upstream.address, name))
+                    }
+                    (UpstreamType::Tls, Some(name)) => Ok(Upstream::Tls(upstream.address, name)),
+                    _ => Err(ConfigError::InvalidDnsUpstream(upstream.address)),
+                })
+                .collect::<Result<_, _>>()?;
+
+            Arc::new(Resolver::new(
+                upstreams,
+                raw.dns.ip_preference,
+                raw.dns.hosts,
+                raw.dns.cache_size,
+            )?)
+        };
 
-        let token = raw
-            .token
-            .into_iter()
-            .map(|token| *blake3::hash(&token.into_bytes()).as_bytes())
-            .collect();
+        let router = {
+            let direct: Arc<dyn Outbound> = Arc::new(Direct::new(
+                acl,
+                resolver.clone(),
+                Duration::from_millis(raw.connect_timeout),
+                Duration::from_millis(raw.connect_attempt_timeout),
+                raw.udp_nat_filtering,
+                raw.udp_sticky_port,
+            ));
+
+            let outbounds = raw
+                .outbounds
+                .into_iter()
+                .map(|(name, outbound)| {
+                    let auth = match (outbound.username, outbound.password) {
+                        (Some(username), Some(password)) => Some((username, password)),
+                        (None, None) => None,
+                        _ => return Err(ConfigError::InvalidOutbound(name)),
+                    };
+
+                    let outbound: Arc<dyn Outbound> = match outbound.kind {
+                        OutboundType::Socks5 => Arc::new(Socks5::new(
+                            outbound.server,
+                            auth,
+                            Duration::from_millis(raw.connect_timeout),
+                            resolver.clone(),
+                        )),
+                    };
+
+                    Ok((name, outbound))
+                })
+                .collect::<Result<HashMap<_, _>, _>>()?;
+
+            let find_route = |name: String| match name.as_str() {
+                "direct" => Ok(Route::Outbound(direct.clone())),
+                "reject" => Ok(Route::Reject),
+                _ => match outbounds.get(&name) {
+                    Some(outbound) => Ok(Route::Outbound(outbound.clone())),
+                    None => Err(ConfigError::UnknownOutbound(name)),
+                },
+            };
+
+            let rules = raw
+                .rules
+                .into_iter()
+                .map(|rule| {
+                    Ok(Rule {
+                        domain_suffix: rule.domain_suffix,
+                        cidr: rule
+                            .cidr
+                            .iter()
+                            .map(|cidr| cidr.parse())
+                            .collect::<Result<_, _>>()?,
+                        port: rule.port,
+                        user: rule.user,
+                        route: find_route(rule.outbound)?,
+                    })
+                })
+                .collect::<Result<_, ConfigError>>()?;
+
+            let default = match raw.socks5 {
+                Some(socks5) => Route::Outbound(Arc::new(Socks5::new(
+                    socks5.parse()?,
+                    None,
+                    Duration::from_millis(raw.connect_timeout),
+                    resolver.clone(),
+                ))),
+                None => Route::Outbound(direct.clone()),
+            };
 
+            Router::new(rules, default)
+        };
+
+        let traffic_accounting_file = raw.traffic_accounting_file.map(PathBuf::from);
         let authentication_timeout = Duration::from_secs(raw.authentication_timeout);
-        let max_udp_relay_packet_size = raw.max_udp_relay_packet_size;
+        let require_session_bound_auth = raw.require_session_bound_authentication;
+        let udp_relay_config = UdpRelayConfig {
+            max_pkt_size: raw.max_udp_relay_packet_size,
+            session_idle_timeout: Duration::from_millis(raw.udp_session_idle_timeout),
+            max_sessions_per_connection: raw.max_udp_sessions_per_connection,
+            socket_budget: Arc::new(Semaphore::new(raw.max_udp_sockets)),
+        };
+        let drain_timeout = Duration::from_millis(raw.drain_timeout);
+        let metrics = raw.metrics;
         let log_level = raw.log_level;
+        let log_format = raw.log_format;
 
         Ok(Self {
             server_config,
-            listen_addr,
-            token,
+            listeners,
+            users,
+            reloader,
+            watched_files,
+            acme,
+            camouflage,
+            camouflage_alpn,
+            router,
             authentication_timeout,
-            max_udp_relay_packet_size,
+            require_session_bound_auth,
+            udp_relay_config,
+            traffic_accounting_file,
+            drain_timeout,
+            metrics,
             log_level,
+            log_format,
         })
     }
 }
 
+/// Builds the users and the certificate again from the command line arguments and the configuration file
+pub struct Reloader {
+    args: Vec<OsString>,
+    /// Generated once, so that reloading keeps the certificate clients have pinned
+    self_signed: Option<(Vec<Certificate>, PrivateKey)>,
+}
+
+impl Reloader {
+    pub fn load(&mut self) -> Result<Reloaded, ConfigError> {
+        RawConfig::parse(self.args.clone())?.take_reloadable(&mut self.self_signed)
+    }
+}
+
+/// The part of the configuration that is reloaded without a restart
+pub struct Reloaded {
+    pub server_config: ServerConfig,
+    pub users: Users,
+    /// The configuration file, the certificates and the private keys
+    pub watched_files: Vec<PathBuf>,
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 struct RawConfig {
     port: Option<u16>,
+
+    #[serde(default)]
+    listen: Vec<RawListenConfig>,
+
+    #[serde(default)]
     token: Vec<String>,
+
+    #[serde(default)]
+    users: HashMap<String, RawUserConfig>,
+
     certificate: Option<String>,
     private_key: Option<String>,
 
+    #[serde(default = "default::self_signed_dir")]
+    self_signed_dir: String,
+
+    client_ca: Option<String>,
+
+    #[serde(default)]
+    require_client_certificate: bool,
+
+    acme: Option<RawAcmeConfig>,
+
+    #[serde(default)]
+    server_names: HashMap<String, RawServerNameConfig>,
+
+    #[serde(default)]
+    alpn_users: HashMap<String, Vec<String>>,
+
+    camouflage: Option<RawCamouflageConfig>,
+
     #[serde(default = "default::ip")]
     ip: IpAddr,
 
     socks5: Option<String>,
 
+    #[serde(default)]
+    outbounds: HashMap<String, RawOutboundConfig>,
+
+    #[serde(default)]
+    rules: Vec<RawRuleConfig>,
+
+    #[serde(default)]
+    acl: RawAclConfig,
+
+    #[serde(default)]
+    dns: RawDnsConfig,
+
     #[serde(
         default = "default::congestion_controller",
         deserialize_with = "deserialize_from_str"
@@ -136,37 +379,532 @@ struct RawConfig {
     #[serde(default = "default::authentication_timeout")]
     authentication_timeout: u64,
 
+    #[serde(default)]
+    require_session_bound_authentication: bool,
+
     #[serde(default = "default::alpn")]
     alpn: Vec<String>,
 
+    #[serde(default = "default::connect_timeout")]
+    connect_timeout: u64,
+
+    #[serde(default = "default::connect_attempt_timeout")]
+    connect_attempt_timeout: u64,
+
     #[serde(default = "default::max_udp_relay_packet_size")]
     max_udp_relay_packet_size: usize,
 
+    #[serde(default = "default::udp_session_idle_timeout")]
+    udp_session_idle_timeout: u64,
+
+    #[serde(default = "default::max_udp_sessions_per_connection")]
+    max_udp_sessions_per_connection: usize,
+
+    #[serde(default = "default::max_udp_sockets")]
+    max_udp_sockets: usize,
+
+    #[serde(
+        default = "default::udp_nat_filtering",
+        deserialize_with = "deserialize_from_str"
+    )]
+    udp_nat_filtering: NatFiltering,
+
+    #[serde(default)]
+    udp_sticky_port: bool,
+
+    traffic_accounting_file: Option<String>,
+
+    #[serde(default = "default::drain_timeout")]
+    drain_timeout: u64,
+
+    metrics: Option<SocketAddr>,
+
     #[serde(default = "default::log_level")]
     log_level: LevelFilter,
+
+    #[serde(
+        default = "default::log_format",
+        deserialize_with = "deserialize_from_str"
+    )]
+    log_format: LogFormat,
+
+    /// The configuration file given on the command line
+    #[serde(skip)]
+    config: Option<String>,
+
+    #[serde(skip)]
+    daemon: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawListenConfig {
+    address: SocketAddr,
+
+    #[serde(default)]
+    ipv6_only: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServerNameConfig {
+    certificate: Option<String>,
+    private_key: Option<String>,
+    users: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawUserConfig {
+    token: Option<String>,
+    uuid: Option<Uuid>,
+    password: Option<String>,
+    certificate_name: Option<String>,
+    tcp_rate_limit: Option<u64>,
+    udp_rate_limit: Option<u64>,
+    quota: Option<u64>,
+
+    #[serde(
+        default = "default::quota_period",
+        deserialize_with = "deserialize_from_str"
+    )]
+    quota_period: QuotaPeriod,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawOutboundConfig {
+    #[serde(rename = "type", deserialize_with = "deserialize_from_str")]
+    kind: OutboundType,
+    server: SocketAddr,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRuleConfig {
+    #[serde(default)]
+    domain_suffix: Vec<String>,
+
+    /// Never matches domain destinations, which are not resolved for routing
+    #[serde(default)]
+    cidr: Vec<String>,
+
+    #[serde(default)]
+    port: Vec<u16>,
+
+    #[serde(default)]
+    user: Vec<String>,
+
+    outbound: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RawAclConfig {
+    #[serde(default)]
+    allow: Vec<String>,
+
+    #[serde(default)]
+    deny: Vec<String>,
+
+    #[serde(default)]
+    allow_ports: Vec<u16>,
+
+    #[serde(default)]
+    deny_ports: Vec<u16>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDnsConfig {
+    #[serde(default)]
+    upstreams: Vec<RawDnsUpstreamConfig>,
+
+    #[serde(
+        default = "default::ip_preference",
+        deserialize_with = "deserialize_from_str"
+    )]
+    ip_preference: IpPreference,
+
+    #[serde(default)]
+    hosts: HashMap<String, Vec<IpAddr>>,
+
+    #[serde(default = "default::dns_cache_size")]
+    cache_size: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawDnsUpstreamConfig {
+    #[serde(rename = "type", deserialize_with = "deserialize_from_str")]
+    kind: UpstreamType,
+    address: SocketAddr,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawAcmeConfig {
+    domain: String,
+    email: String,
+
+    #[serde(default = "default::acme_directory")]
+    directory: String,
+
+    #[serde(
+        default = "default::acme_challenge",
+        deserialize_with = "deserialize_from_str"
+    )]
+    challenge: AcmeChallenge,
+
+    challenge_port: Option<u16>,
+    cache_dir: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCamouflageConfig {
+    static_dir: Option<String>,
+    backend: Option<SocketAddr>,
+    server_name: Option<String>,
+    certificate: Option<String>,
+
+    #[serde(default)]
+    alpn: Vec<String>,
 }
 
 impl Default for RawConfig {
     fn default() -> Self {
         Self {
             port: None,
+            listen: Vec::new(),
             token: Vec::new(),
+            users: HashMap::new(),
             certificate: None,
             private_key: None,
+            self_signed_dir: default::self_signed_dir(),
+            client_ca: None,
+            require_client_certificate: false,
+            acme: None,
+            server_names: HashMap::new(),
+            alpn_users: HashMap::new(),
+            camouflage: None,
             ip: default::ip(),
             socks5: None,
+            outbounds: HashMap::new(),
+            rules: Vec::new(),
+            acl: RawAclConfig::default(),
+            dns: RawDnsConfig::default(),
             congestion_controller: default::congestion_controller(),
             max_idle_time: default::max_idle_time(),
             authentication_timeout: default::authentication_timeout(),
+            require_session_bound_authentication: false,
             alpn: default::alpn(),
+            connect_timeout: default::connect_timeout(),
+            connect_attempt_timeout: default::connect_attempt_timeout(),
             max_udp_relay_packet_size: default::max_udp_relay_packet_size(),
+            udp_session_idle_timeout: default::udp_session_idle_timeout(),
+            max_udp_sessions_per_connection: default::max_udp_sessions_per_connection(),
+            max_udp_sockets: default::max_udp_sockets(),
+            udp_nat_filtering: default::udp_nat_filtering(),
+            udp_sticky_port: false,
+            traffic_accounting_file: None,
+            drain_timeout: default::drain_timeout(),
+            metrics: None,
             log_level: default::log_level(),
+            log_format: default::log_format(),
+            config: None,
+            daemon: false,
+        }
+    }
+}
+
+impl Default for RawUserConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            uuid: None,
+            password: None,
+            certificate_name: None,
+            tcp_rate_limit: None,
+            udp_rate_limit: None,
+            quota: None,
+            quota_period: default::quota_period(),
         }
     }
 }
 
+impl Default for RawDnsConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            ip_preference: default::ip_preference(),
+            hosts: HashMap::new(),
+            cache_size: default::dns_cache_size(),
+        }
+    }
+}
+
+impl RawAcmeConfig {
+    /// The challenge listener binds the IP the server listens on
+    fn build(&self, ip: IpAddr) -> Acme {
+        let port = self
+            .challenge_port
+            .unwrap_or_else(|| self.challenge.default_port());
+
+        Acme::new(
+            self.domain.clone(),
+            self.email.clone(),
+            self.directory.clone(),
+            self.challenge,
+            SocketAddr::new(ip, port),
+            PathBuf::from(&self.cache_dir),
+        )
+    }
+}
+
 impl RawConfig {
-    fn parse(args: ArgsOs) -> Result<Self, ConfigError> {
+    fn take_reloadable(
+        &mut self,
+        self_signed: &mut Option<(Vec<Certificate>, PrivateKey)>,
+    ) -> Result<Reloaded, ConfigError> {
+        let server_config = {
+            let (certs, priv_key) = if let Some(acme) = &self.acme {
+                let acme = acme.build(self.ip);
+                let cert_path = acme.certificate_path();
+
+                // serve a self-signed certificate until the first one is obtained
+                if cert_path.exists() {
+                    let priv_key_path = acme.private_key_path();
+                    let certs = certificate::load_certificates(&cert_path)
+                        .map_err(|err| ConfigError::Io(cert_path.display().to_string(), err))?;
+                    let priv_key = certificate::load_private_key(&priv_key_path)
+                        .map_err(|err| ConfigError::Io(priv_key_path.display().to_string(), err))?;
+                    (certs, priv_key)
+                } else {
+                    certificate::generate_self_signed(acme.domain())
+                }
+            } else if self.certificate != self.private_key {
+                let cert_path = self.certificate.clone().unwrap();
+                let priv_key_path = self.private_key.clone().unwrap();
+                let certs = certificate::load_certificates(&cert_path)
+                    .map_err(|err| ConfigError::Io(cert_path, err))?;
+                let priv_key = certificate::load_private_key(&priv_key_path)
+                    .map_err(|err| ConfigError::Io(priv_key_path, err))?;
+                (certs, priv_key)
+            } else if let Some(self_signed) = self_signed {
+                self_signed.clone()
+            } else {
+                let server_name = self.certificate.clone().unwrap();
+                let dir = Path::new(&self.self_signed_dir);
+
+                let (certs, priv_key) =
+                    certificate::load_or_generate_self_signed(dir, &server_name)
+                        .map_err(|err| ConfigError::Io(dir.display().to_string(), err))?;
+
+                eprintln!(
+                    "use self signed certificate {server_name}, SHA-256 fingerprint: {}",
+                    certificate::fingerprint(&certs[0])
+                );
+
+                self_signed.insert((certs, priv_key)).clone()
+            };
+
+            let crypto = RustlsServerConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[&TLS13])
+                .unwrap();
+
+            let crypto = if let Some(path) = &self.client_ca {
+                let certs = certificate::load_certificates(path)
+                    .map_err(|err| ConfigError::Io(path.clone(), err))?
+                    .into_iter()
+                    .map(|cert| cert.0)
+                    .collect::<Vec<_>>();
+
+                let mut roots = RootCertStore::empty();
+
+                if roots.add_parsable_certificates(&certs).0 == 0 {
+                    return Err(ConfigError::InvalidClientCa(path.clone()));
+                }
+
+                if self.require_client_certificate {
+                    crypto.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+                } else {
+                    crypto.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(
+                        roots,
+                    ))
+                }
+            } else if self.require_client_certificate {
+                return Err(ConfigError::MissingOption("client CA"));
+            } else {
+                crypto.with_no_client_auth()
+            };
+
+            let mut resolver = certificate::ServerNameResolver::new(certs, &priv_key)?;
+
+            for (server_name, raw_server_name) in &self.server_names {
+                match (&raw_server_name.certificate, &raw_server_name.private_key) {
+                    (Some(cert_path), Some(priv_key_path)) => {
+                        let certs = certificate::load_certificates(cert_path)
+                            .map_err(|err| ConfigError::Io(cert_path.clone(), err))?;
+                        let priv_key = certificate::load_private_key(priv_key_path)
+                            .map_err(|err| ConfigError::Io(priv_key_path.clone(), err))?;
+                        resolver.insert(server_name, certs, &priv_key)?;
+                    }
+                    (None, None) => {}
+                    _ => return Err(ConfigError::InvalidServerName(server_name.clone())),
+                }
+            }
+
+            let mut crypto = crypto.with_cert_resolver(Arc::new(resolver));
+
+            let camouflage_alpn = self
+                .camouflage
+                .as_ref()
+                .map_or(&[][..], |camouflage| &camouflage.alpn);
+
+            if !camouflage_alpn.is_empty() {
+                if self.alpn.is_empty() {
+                    return Err(ConfigError::InvalidCamouflage(
+                        "alpn must be set to tell TUIC clients from the ones of the site",
+                    ));
+                }
+
+                if camouflage_alpn.iter().any(|alpn| self.alpn.contains(alpn)) {
+                    return Err(ConfigError::InvalidCamouflage(
+                        "alpn of the site must differ from the one of TUIC",
+                    ));
+                }
+            }
+
+            // TUIC protocols come first, as the server picks the first protocol the client offers
+            crypto.max_early_data_size = u32::MAX;
+            crypto.alpn_protocols = mem::take(&mut self.alpn)
+                .into_iter()
+                .chain(camouflage_alpn.iter().cloned())
+                .map(|alpn| alpn.into_bytes())
+                .collect();
+
+            let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+            let transport = Arc::get_mut(&mut config.transport).unwrap();
+
+            match self.congestion_controller {
+                CongestionController::Bbr => {
+                    transport.congestion_controller_factory(Arc::new(BbrConfig::default()));
+                }
+                CongestionController::Cubic => {
+                    transport.congestion_controller_factory(Arc::new(CubicConfig::default()));
+                }
+                CongestionController::NewReno => {
+                    transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()));
+                }
+            }
+
+            transport.max_idle_timeout(Some(IdleTimeout::from(VarInt::from_u32(
+                self.max_idle_time,
+            ))));
+            transport.max_concurrent_bidi_streams(0x10000u32.into());
+
+            config
+        };
+
+        let users = {
+            let mut users = Users::new();
+
+            // tokens set without a user name are shared by an anonymous user
+            let anonymous = Arc::new(User::new(String::from("anonymous"), Traffic::default()));
+            users.insert(anonymous.clone());
+
+            for token in mem::take(&mut self.token) {
+                if !users.insert_token(&token, anonymous.clone()) {
+                    return Err(ConfigError::DuplicateCredential(anonymous.to_string()));
+                }
+            }
+
+            for (name, raw_user) in mem::take(&mut self.users) {
+                let quota = raw_user
+                    .quota
+                    .map(|quota| Quota::new(quota, raw_user.quota_period));
+
+                let traffic = Traffic::new(raw_user.tcp_rate_limit, raw_user.udp_rate_limit, quota);
+
+                let user = Arc::new(User::new(name, traffic));
+                users.insert(user.clone());
+
+                if raw_user.token.is_none()
+                    && raw_user.uuid.is_none()
+                    && raw_user.certificate_name.is_none()
+                {
+                    return Err(ConfigError::InvalidUser(user.to_string()));
+                }
+
+                if let Some(token) = raw_user.token {
+                    if !users.insert_token(&token, user.clone()) {
+                        return Err(ConfigError::DuplicateCredential(user.to_string()));
+                    }
+                }
+
+                match (raw_user.uuid, raw_user.password) {
+                    (Some(uuid), Some(password)) => {
+                        if !users.insert_uuid(uuid, password, user.clone()) {
+                            return Err(ConfigError::DuplicateCredential(user.to_string()));
+                        }
+                    }
+                    (None, None) => {}
+                    _ => return Err(ConfigError::InvalidUser(user.to_string())),
+                }
+
+                if let Some(name) = raw_user.certificate_name {
+                    if !users.insert_certificate_name(name, user.clone()) {
+                        return Err(ConfigError::DuplicateCredential(user.to_string()));
+                    }
+                }
+            }
+
+            for (server_name, raw_server_name) in &mut self.server_names {
+                if let Some(names) = raw_server_name.users.take() {
+                    let names = check_user_names(&users, names)?;
+                    users.restrict_server_name(server_name, names);
+                }
+            }
+
+            for (alpn, names) in mem::take(&mut self.alpn_users) {
+                let names = check_user_names(&users, names)?;
+                users.restrict_alpn(alpn.into_bytes(), names);
+            }
+
+            users
+        };
+
+        if let Some(path) = &self.traffic_accounting_file {
+            traffic::load_usage(Path::new(path), &users)
+                .map_err(|err| ConfigError::Io(path.clone(), err))?;
+        }
+
+        let mut watched_files = self.config.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        if self.acme.is_none() && self.certificate != self.private_key {
+            watched_files.extend(self.certificate.iter().map(PathBuf::from));
+            watched_files.extend(self.private_key.iter().map(PathBuf::from));
+        }
+
+        for raw_server_name in self.server_names.values() {
+            watched_files.extend(raw_server_name.certificate.iter().map(PathBuf::from));
+            watched_files.extend(raw_server_name.private_key.iter().map(PathBuf::from));
+        }
+
+        Ok(Reloaded {
+            server_config,
+            users,
+            watched_files,
+        })
+    }
+
+    fn parse(args: Vec<OsString>) -> Result<Self, ConfigError> {
         let mut opts = Options::new();
 
         opts.optopt(
@@ -178,13 +916,27 @@ impl RawConfig {
 
         opts.optopt("", "port", "Set the server listening port", "SERVER_PORT");
 
+        opts.optmulti(
+            "",
+            "listen",
+            "Set an address to listen on instead of the IP and the port. This option can be used multiple times to listen on multiple addresses",
+            "ADDRESS",
+        );
+
         opts.optmulti(
             "",
             "token",
-            "Set the token for TUIC authentication. This option can be used multiple times to set multiple tokens.",
+            "Set the token for TUIC authentication as an anonymous user. This option can be used multiple times to set multiple tokens.",
             "TOKEN",
         );
 
+        opts.optmulti(
+            "",
+            "user",
+            "Set a named user with a token (TUIC v4), or with a UUID and a password (TUIC v5), separated by colons. This option can be used multiple times to set multiple users.",
+            "NAME:TOKEN|NAME:UUID:PASSWORD",
+        );
+
         opts.optopt(
             "",
             "certificate",
@@ -199,6 +951,26 @@ impl RawConfig {
             "PRIVATE_KEY",
         );
 
+        opts.optopt(
+            "",
+            "self-signed-dir",
+            "Set the directory the generated self signed certificate and private key are saved in and loaded from. Default: the current directory",
+            "SELF_SIGNED_DIR",
+        );
+
+        opts.optopt(
+            "",
+            "client-ca",
+            "Set the X.509 CA certificates that client certificates must be signed by. Clients presenting one authenticate as the user it names",
+            "CLIENT_CA",
+        );
+
+        opts.optflag(
+            "",
+            "require-client-certificate",
+            "Reject clients without a certificate signed by the client CA",
+        );
+
         opts.optopt(
             "",
             "ip",
@@ -234,6 +1006,12 @@ impl RawConfig {
             "AUTHENTICATION_TIMEOUT",
         );
 
+        opts.optflag(
+            "",
+            "require-session-bound-authentication",
+            "Reject TUIC v4 clients authenticating with a static token digest instead of a digest bound to the TLS session",
+        );
+
         opts.optmulti(
             "",
             "alpn",
@@ -241,6 +1019,20 @@ impl RawConfig {
             "ALPN_PROTOCOL",
         );
 
+        opts.optopt(
+            "",
+            "connect-timeout",
+            "Set the maximum time for resolving and connecting to the destination of a relayed TCP connection, in milliseconds. Default: 10000",
+            "CONNECT_TIMEOUT",
+        );
+
+        opts.optopt(
+            "",
+            "connect-attempt-timeout",
+            "Set the maximum time for a connection attempt to one of the resolved addresses of a destination, in milliseconds. Default: 4000",
+            "CONNECT_ATTEMPT_TIMEOUT",
+        );
+
         opts.optopt(
             "",
             "max-udp-relay-packet-size",
@@ -248,6 +1040,61 @@ impl RawConfig {
             "MAX_UDP_RELAY_PACKET_SIZE",
         );
 
+        opts.optopt(
+            "",
+            "udp-session-idle-timeout",
+            "Set the time after which a UDP session without traffic in either direction is closed, in milliseconds. Default: 60000",
+            "UDP_SESSION_IDLE_TIMEOUT",
+        );
+
+        opts.optopt(
+            "",
+            "max-udp-sessions-per-connection",
+            "Set the maximum number of concurrent UDP sessions of a connection. Default: 256",
+            "MAX_UDP_SESSIONS_PER_CONNECTION",
+        );
+
+        opts.optopt(
+            "",
+            "max-udp-sockets",
+            "Set the maximum number of UDP sockets bound by all UDP sessions of the server. Default: 16384",
+            "MAX_UDP_SOCKETS",
+        );
+
+        opts.optopt(
+            "",
+            "udp-nat-filtering",
+            r#"Set which packets from the internet a UDP session lets through. Available: "endpoint_independent" (from any address), "address_dependent" (from IPs the session has sent to), "address_and_port_dependent" (from IP and port pairs the session has sent to). Default: "endpoint_independent""#,
+            "UDP_NAT_FILTERING",
+        );
+
+        opts.optflag(
+            "",
+            "udp-sticky-port",
+            "Keep the external port of a user's UDP session across sessions and reconnects when possible",
+        );
+
+        opts.optopt(
+            "",
+            "traffic-accounting-file",
+            "Set the file to persist the traffic quota usage of users in",
+            "TRAFFIC_ACCOUNTING_FILE",
+        );
+
+        opts.optopt(
+            "",
+            "drain-timeout",
+            "Set the maximum time to wait for relayed TCP connections to finish when shutting down on SIGTERM or SIGINT, in milliseconds. Default: 30000",
+            "DRAIN_TIMEOUT",
+        );
+
+        opts.optopt(
+            "",
+            "metrics",
+            "Set the address to serve Prometheus metrics on over HTTP, at /metrics. Disabled by default",
+            "METRICS_ADDR",
+        );
+
         opts.optopt(
             "",
             "log-level",
@@ -255,12 +1102,19 @@ impl RawConfig {
             "LOG_LEVEL",
         );
 
+        opts.optopt(
+            "",
+            "log-format",
+            r#"Set the log format. Available: "text", "json". Default: "text""#,
+            "LOG_FORMAT",
+        );
+
         #[cfg(unix)]
         opts.optflag("d", "daemon", "Daemonize");
         opts.optflag("v", "version", "Print the version");
         opts.optflag("h", "help", "Print this help menu");
 
-        let matches = opts.parse(args.skip(1))?;
+        let matches = opts.parse(args.into_iter().skip(1))?;
 
         if matches.opt_present("help") {
             return Err(ConfigError::Help(opts.usage(env!("CARGO_PKG_NAME"))));
@@ -274,64 +1128,100 @@ impl RawConfig {
             return Err(ConfigError::UnexpectedArguments(matches.free.join(", ")));
         }
 
-        #[cfg(all(unix, not(target_os = "android")))]
-        {
-            let _ = realm_syscall::bump_nofile_limit();
-            if let Ok((soft, hard)) = realm_syscall::get_nofile_limit() {
-                println!("fd limit: {soft}, {hard}")
-            }
-        }
-        
-        #[cfg(unix)]
-        if matches.opt_present("daemon") {
-            realm_syscall::daemonize("tuic is running in the background.");
-        }
-
         let port = matches.opt_str("port").map(|port| port.parse());
+        let listen = matches
+            .opt_strs("listen")
+            .into_iter()
+            .map(|addr| {
+                Ok(RawListenConfig {
+                    address: addr.parse()?,
+                    ipv6_only: false,
+                })
+            })
+            .collect::<Result<Vec<_>, ConfigError>>()?;
         let token = matches.opt_strs("token");
+        let users = matches
+            .opt_strs("user")
+            .into_iter()
+            .map(|user| parse_user(&user))
+            .collect::<Result<HashMap<_, _>, _>>()?;
         let certificate = matches.opt_str("certificate");
         let private_key = matches.opt_str("private-key");
 
-        let mut raw = if let Some(path) = matches.opt_str("config") {
+        let config = matches.opt_str("config");
+
+        let mut raw = if let Some(path) = config.clone() {
             let mut raw = RawConfig::from_file(path)?;
 
-            raw.port = Some(
-                port.transpose()?
-                    .or(raw.port)
-                    .ok_or(ConfigError::MissingOption("port"))?,
-            );
+            raw.port = port.transpose()?.or(raw.port);
+
+            if !listen.is_empty() {
+                raw.listen = listen;
+            }
+
+            if raw.port.is_none() && raw.listen.is_empty() {
+                return Err(ConfigError::MissingOption("port"));
+            }
 
             if !token.is_empty() {
                 raw.token = token;
-            } else if raw.token.is_empty() {
-                return Err(ConfigError::MissingOption("token"));
             }
 
-            raw.certificate = Some(
-                certificate
-                    .or(raw.certificate)
-                    .ok_or(ConfigError::MissingOption("certificate"))?,
-            );
+            if !users.is_empty() {
+                raw.users = users;
+            }
+
+            if raw.token.is_empty() && raw.users.is_empty() {
+                return Err(ConfigError::MissingOption("token or user"));
+            }
+
+            raw.certificate = certificate.or(raw.certificate);
+            raw.private_key = private_key.or(raw.private_key);
+
+            // certificates of ACME are kept in its cache directory
+            if raw.acme.is_none() {
+                if raw.certificate.is_none() {
+                    return Err(ConfigError::MissingOption("certificate"));
+                }
 
-            raw.private_key = Some(
-                private_key
-                    .or(raw.private_key)
-                    .ok_or(ConfigError::MissingOption("private key"))?,
-            );
+                if raw.private_key.is_none() {
+                    return Err(ConfigError::MissingOption("private key"));
+                }
+            }
 
             raw
         } else {
+            if token.is_empty() && users.is_empty() {
+                return Err(ConfigError::MissingOption("token or user"));
+            }
+
+            let port = port.transpose()?;
+
+            if port.is_none() && listen.is_empty() {
+                return Err(ConfigError::MissingOption("port"));
+            }
+
             RawConfig {
-                port: Some(port.ok_or(ConfigError::MissingOption("port"))??),
-                token: (!token.is_empty())
-                    .then(|| token)
-                    .ok_or(ConfigError::MissingOption("token"))?,
+                port,
+                listen,
+                token,
+                users,
                 certificate: Some(certificate.ok_or(ConfigError::MissingOption("certificate"))?),
                 private_key: Some(private_key.ok_or(ConfigError::MissingOption("private key"))?),
                 ..Default::default()
             }
         };
 
+        if let Some(dir) = matches.opt_str("self-signed-dir") {
+            raw.self_signed_dir = dir;
+        }
+
+        if let Some(path) = matches.opt_str("client-ca") {
+            raw.client_ca = Some(path);
+        }
+
+        raw.require_client_certificate |= matches.opt_present("require-client-certificate");
+
         if let Some(ip) = matches.opt_str("ip") {
             raw.ip = ip.parse()?;
         };
@@ -352,10 +1242,51 @@ impl RawConfig {
             raw.authentication_timeout = timeout.parse()?;
         };
 
+        raw.require_session_bound_authentication |=
+            matches.opt_present("require-session-bound-authentication");
+
+        if let Some(timeout) = matches.opt_str("connect-timeout") {
+            raw.connect_timeout = timeout.parse()?;
+        };
+
+        if let Some(timeout) = matches.opt_str("connect-attempt-timeout") {
+            raw.connect_attempt_timeout = timeout.parse()?;
+        };
+
         if let Some(size) = matches.opt_str("max-udp-relay-packet-size") {
             raw.max_udp_relay_packet_size = size.parse()?;
         };
 
+        if let Some(timeout) = matches.opt_str("udp-session-idle-timeout") {
+            raw.udp_session_idle_timeout = timeout.parse()?;
+        };
+
+        if let Some(max) = matches.opt_str("max-udp-sessions-per-connection") {
+            raw.max_udp_sessions_per_connection = max.parse()?;
+        };
+
+        if let Some(max) = matches.opt_str("max-udp-sockets") {
+            raw.max_udp_sockets = max.parse()?;
+        };
+
+        if let Some(filtering) = matches.opt_str("udp-nat-filtering") {
+            raw.udp_nat_filtering = filtering.parse()?;
+        };
+
+        raw.udp_sticky_port |= matches.opt_present("udp-sticky-port");
+
+        if let Some(path) = matches.opt_str("traffic-accounting-file") {
+            raw.traffic_accounting_file = Some(path);
+        }
+
+        if let Some(timeout) = matches.opt_str("drain-timeout") {
+            raw.drain_timeout = timeout.parse()?;
+        };
+
+        if let Some(addr) = matches.opt_str("metrics") {
+            raw.metrics = Some(addr.parse()?);
+        };
+
         let alpn = matches.opt_strs("alpn");
 
         if !alpn.is_empty() {
@@ -366,6 +1297,13 @@ impl RawConfig {
             raw.log_level = log_level.parse()?;
         };
 
+        if let Some(log_format) = matches.opt_str("log-format") {
+            raw.log_format = log_format.parse()?;
+        };
+
+        raw.config = config;
+        raw.daemon = cfg!(unix) && matches.opt_present("daemon");
+
         Ok(raw)
     }
 
@@ -398,6 +1336,174 @@ impl FromStr for CongestionController {
     }
 }
 
+enum OutboundType {
+    Socks5,
+}
+
+impl FromStr for OutboundType {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("socks5") {
+            Ok(OutboundType::Socks5)
+        } else {
+            Err(ConfigError::InvalidOutboundType)
+        }
+    }
+}
+
+enum UpstreamType {
+    Udp,
+    Tcp,
+    Https,
+    Tls,
+}
+
+impl FromStr for UpstreamType {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("udp") {
+            Ok(UpstreamType::Udp)
+        } else if s.eq_ignore_ascii_case("tcp") {
+            Ok(UpstreamType::Tcp)
+        } else if s.eq_ignore_ascii_case("https") {
+            Ok(UpstreamType::Https)
+        } else if s.eq_ignore_ascii_case("tls") {
+            Ok(UpstreamType::Tls)
+        } else {
+            Err(ConfigError::InvalidDnsUpstreamType)
+        }
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("prefer_v4") {
+            Ok(IpPreference::PreferV4)
+        } else if s.eq_ignore_ascii_case("prefer_v6") {
+            Ok(IpPreference::PreferV6)
+        } else if s.eq_ignore_ascii_case("v4_only") {
+            Ok(IpPreference::V4Only)
+        } else if s.eq_ignore_ascii_case("v6_only") {
+            Ok(IpPreference::V6Only)
+        } else {
+            Err(ConfigError::InvalidIpPreference)
+        }
+    }
+}
+
+impl FromStr for AcmeChallenge {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("tls-alpn-01") {
+            Ok(AcmeChallenge::TlsAlpn01)
+        } else if s.eq_ignore_ascii_case("http-01") {
+            Ok(AcmeChallenge::Http01)
+        } else {
+            Err(ConfigError::InvalidAcmeChallenge)
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr.parse()?, Some(prefix_len.parse()?)),
+            None => (s.parse()?, None),
+        };
+
+        let prefix_len = prefix_len.unwrap_or(match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        });
+
+        Cidr::new(addr, prefix_len).ok_or_else(|| ConfigError::InvalidCidr(s.to_owned()))
+    }
+}
+
+impl FromStr for NatFiltering {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("endpoint_independent") {
+            Ok(NatFiltering::EndpointIndependent)
+        } else if s.eq_ignore_ascii_case("address_dependent") {
+            Ok(NatFiltering::AddressDependent)
+        } else if s.eq_ignore_ascii_case("address_and_port_dependent") {
+            Ok(NatFiltering::AddressAndPortDependent)
+        } else {
+            Err(ConfigError::InvalidNatFiltering)
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("text") {
+            Ok(LogFormat::Text)
+        } else if s.eq_ignore_ascii_case("json") {
+            Ok(LogFormat::Json)
+        } else {
+            Err(ConfigError::InvalidLogFormat)
+        }
+    }
+}
+
+impl FromStr for QuotaPeriod {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("total") {
+            Ok(QuotaPeriod::Total)
+        } else if s.eq_ignore_ascii_case("monthly") {
+            Ok(QuotaPeriod::Monthly)
+        } else {
+            Err(ConfigError::InvalidQuotaPeriod)
+        }
+    }
+}
+
+fn parse_user(s: &str) -> Result<(String, RawUserConfig), ConfigError> {
+    let (name, cred) = s
+        .split_once(':')
+        .ok_or_else(|| ConfigError::InvalidUser(s.to_owned()))?;
+
+    let user = match cred
+        .split_once(':')
+        .and_then(|(uuid, password)| Some((uuid.parse().ok()?, password)))
+    {
+        Some((uuid, password)) => RawUserConfig {
+            uuid: Some(uuid),
+            password: Some(password.to_owned()),
+            ..Default::default()
+        },
+        None => RawUserConfig {
+            token: Some(cred.to_owned()),
+            ..Default::default()
+        },
+    };
+
+    Ok((name.to_owned(), user))
+}
+
+fn check_user_names(users: &Users, names: Vec<String>) -> Result<HashSet<String>, ConfigError> {
+    match names
+        .iter()
+        .find(|name| !users.iter().any(|user| user.name() == name.as_str()))
+    {
+        Some(name) => Err(ConfigError::UnknownUser(name.clone())),
+        None => Ok(names.into_iter().collect()),
+    }
+}
+
 fn deserialize_from_str<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
     T: FromStr,
@@ -431,13 +1537,69 @@ mod default {
         Vec::new()
     }
 
+    pub(super) const fn connect_timeout() -> u64 {
+        10000
+    }
+
+    pub(super) const fn connect_attempt_timeout() -> u64 {
+        4000
+    }
+
     pub(super) const fn max_udp_relay_packet_size() -> usize {
         1500
     }
 
+    pub(super) const fn udp_session_idle_timeout() -> u64 {
+        60000
+    }
+
+    pub(super) const fn max_udp_sessions_per_connection() -> usize {
+        256
+    }
+
+    pub(super) const fn max_udp_sockets() -> usize {
+        16384
+    }
+
+    pub(super) const fn udp_nat_filtering() -> NatFiltering {
+        NatFiltering::EndpointIndependent
+    }
+
+    pub(super) fn self_signed_dir() -> String {
+        String::from(".")
+    }
+
+    pub(super) fn acme_directory() -> String {
+        String::from("https://acme-v02.api.letsencrypt.org/directory")
+    }
+
+    pub(super) const fn acme_challenge() -> AcmeChallenge {
+        AcmeChallenge::TlsAlpn01
+    }
+
+    pub(super) const fn ip_preference() -> IpPreference {
+        IpPreference::PreferV4
+    }
+
+    pub(super) const fn dns_cache_size() -> usize {
+        1024
+    }
+
+    pub(super) const fn quota_period() -> QuotaPeriod {
+        QuotaPeriod::Total
+    }
+
+    pub(super) const fn drain_timeout() -> u64 {
+        30000
+    }
+
     pub(super) const fn log_level() -> LevelFilter {
         LevelFilter::Info
     }
+
+    pub(super) const fn log_format() -> LogFormat {
+        LogFormat::Text
+    }
 }
 
 #[derive(Error, Debug)]
@@ -460,6 +1622,42 @@ pub enum ConfigError {
     ParseInt(#[from] ParseIntError),
     #[error(transparent)]
     ParseAddr(#[from] AddrParseError),
+    #[error("Invalid user: {0}")]
+    InvalidUser(String),
+    #[error("Invalid quota period")]
+    InvalidQuotaPeriod,
+    #[error("Unknown user: {0}")]
+    UnknownUser(String),
+    #[error("Duplicate token or UUID of user: {0}")]
+    DuplicateCredential(String),
+    #[error("Invalid outbound type")]
+    InvalidOutboundType,
+    #[error("Invalid outbound: {0}")]
+    InvalidOutbound(String),
+    #[error("Unknown outbound: {0}")]
+    UnknownOutbound(String),
+    #[error("Invalid CIDR: {0}")]
+    InvalidCidr(String),
+    #[error("Invalid UDP NAT filtering")]
+    InvalidNatFiltering,
+    #[error("Invalid log format")]
+    InvalidLogFormat,
+    #[error("Server name {0} needs both a certificate and a private key")]
+    InvalidServerName(String),
+    #[error("No valid certificate in the client CA file: {0}")]
+    InvalidClientCa(String),
+    #[error("Invalid camouflage: {0}")]
+    InvalidCamouflage(&'static str),
+    #[error("Invalid ACME challenge")]
+    InvalidAcmeChallenge,
+    #[error("Invalid DNS upstream type")]
+    InvalidDnsUpstreamType,
+    #[error("Invalid DNS upstream: {0}")]
+    InvalidDnsUpstream(SocketAddr),
+    #[error("Invalid IP preference")]
+    InvalidIpPreference,
+    #[error("Failed to initialize the DNS resolver: {0}")]
+    Resolver(#[from] ResolveError),
     #[error("Invalid congestion controller")]
     InvalidCongestionController,
     #[error(transparent)]
@@ -1,19 +1,17 @@
 use super::IsClosed;
+use crate::user::User;
 use parking_lot::Mutex;
 use std::{
     future::Future,
     pin::Pin,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    sync::Arc,
     task::{Context, Poll, Waker},
 };
 
 #[derive(Clone)]
 pub struct IsAuthenticated {
     is_connection_closed: IsClosed,
-    is_authenticated: Arc<AtomicBool>,
+    user: Arc<Mutex<Option<Arc<User>>>>,
     broadcast: Arc<Mutex<Vec<Waker>>>,
 }
 
@@ -21,13 +19,24 @@ impl IsAuthenticated {
     pub fn new(is_closed: IsClosed) -> Self {
         Self {
             is_connection_closed: is_closed,
-            is_authenticated: Arc::new(AtomicBool::new(false)),
+            user: Arc::new(Mutex::new(None)),
             broadcast: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
-    pub fn set_authenticated(&self) {
-        self.is_authenticated.store(true, Ordering::Release);
+    pub fn set_authenticated(&self, user: Arc<User>) {
+        *self.user.lock() = Some(user);
+    }
+
+    /// Returns the authenticated user, if any
+    pub fn user(&self) -> Option<Arc<User>> {
+        self.user.lock().clone()
+    }
+
+    /// The name of the authenticated user for logging, or `-` before authentication
+    pub fn user_name(&self) -> String {
+        self.user()
+            .map_or_else(|| String::from("-"), |user| user.to_string())
     }
 
     pub fn wake(&self) {
@@ -37,14 +46,15 @@ impl IsAuthenticated {
     }
 }
 
+/// Resolves to the authenticated user, or `None` if the connection is closed before authentication
 impl Future for IsAuthenticated {
-    type Output = bool;
+    type Output = Option<Arc<User>>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         if self.is_connection_closed.check() {
-            Poll::Ready(false)
-        } else if self.is_authenticated.load(Ordering::Relaxed) {
-            Poll::Ready(true)
+            Poll::Ready(None)
+        } else if let Some(user) = self.user() {
+            Poll::Ready(Some(user))
         } else {
             self.broadcast.lock().push(cx.waker().clone());
             Poll::Pending
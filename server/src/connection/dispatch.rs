@@ -1,32 +1,318 @@
-use super::{task, Connection, UdpPacketSource};
+use super::{fallback::Recorded, task, udp::Fragment, Connection, UdpPacketSource};
+use crate::{camouflage::PendingStream, certificate, user::User};
 use bytes::Bytes;
-use quinn::{RecvStream, SendStream, VarInt};
-use std::io::Error as IoError;
+use quinn::{crypto::rustls::HandshakeData, RecvStream, SendStream, VarInt};
+use ring::constant_time;
+use rustls::Certificate;
+use std::{io::Error as IoError, sync::Arc, time::Instant};
 use thiserror::Error;
-use tuic_protocol::{Address, Command};
+use tracing::Span;
+use tuic_protocol::{
+    v5, Address, AnyCommand, Command, Decoded, ProtocolError,
+    SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN, SESSION_BOUND_AUTH_LABEL,
+};
+use uuid::Uuid;
 
 impl Connection {
     pub async fn process_uni_stream(&self, mut stream: RecvStream) -> Result<(), DispatchError> {
+        let mut recorded = Recorded::new(&mut stream);
+
+        let cmd = match AnyCommand::read_from(&mut recorded).await {
+            Ok(cmd) => cmd,
+            Err(_) if self.can_fall_back() => {
+                let read = recorded.into_read();
+                self.fall_back(Some(PendingStream::Uni(read, stream)));
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        self.check_protocol_version(cmd.version())?;
+
+        match cmd {
+            AnyCommand::V4(cmd) => self.process_uni_stream_v4(stream, cmd).await,
+            AnyCommand::V5(cmd) => self.process_uni_stream_v5(stream, cmd).await,
+        }
+    }
+
+    pub async fn process_bi_stream(
+        &self,
+        send: SendStream,
+        mut recv: RecvStream,
+    ) -> Result<(), DispatchError> {
+        let mut recorded = Recorded::new(&mut recv);
+
+        let cmd = match AnyCommand::read_from(&mut recorded).await {
+            Ok(cmd) => cmd,
+            Err(_) if self.can_fall_back() => {
+                let read = recorded.into_read();
+                self.fall_back(Some(PendingStream::Bi(read, send, recv)));
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        self.check_protocol_version(cmd.version())?;
         let rmt_addr = self.controller.remote_address();
-        let cmd = Command::read_from(&mut stream).await?;
 
-        if let Command::Authenticate { digest } = cmd {
-            if self.token.contains(&digest) {
-                log::debug!("[{rmt_addr}] [authentication]");
+        if let Some(user) = self.is_authenticated.clone().await {
+            check_quota(&user)?;
 
-                self.is_authenticated.set_authenticated();
-                self.is_authenticated.wake();
+            let (addr, method, fast) = match cmd {
+                AnyCommand::V4(Command::Connect { addr, fast }) => {
+                    (addr, if fast { "connect2" } else { "connect" }, fast)
+                }
+                AnyCommand::V5(v5::Command::Connect { addr }) => {
+                    (Address::try_from(addr)?, "connect", true)
+                }
+                _ => return Err(DispatchError::BadCommand),
+            };
+
+            let dst_addr = addr.to_string();
+            record_command(method, Some(&dst_addr), None);
+            log::info!("[{rmt_addr}] [{user}] [{method}] [{dst_addr}]");
+
+            let started = Instant::now();
+            let tunnel = self.tunnels.enter();
+            let res = task::connect(
+                send,
+                recv,
+                addr,
+                fast,
+                user.clone(),
+                &self.router,
+                &self.metrics,
+            )
+            .await;
+            drop(tunnel);
+
+            let lifetime = started.elapsed();
+            let duration_ms = lifetime.as_millis() as u64;
+
+            match res {
+                Ok((bytes_sent, bytes_received)) => tracing::debug!(
+                    bytes_sent, bytes_received, duration_ms,
+                    "[{rmt_addr}] [{user}] [close] [{dst_addr}] sent: {bytes_sent} bytes, received: {bytes_received} bytes, lifetime: {lifetime:?}"
+                ),
+                Err(err) => tracing::warn!(
+                    duration_ms,
+                    "[{rmt_addr}] [{user}] [{method}] [{dst_addr}] {err}"
+                ),
+            }
+
+            check_quota(&user)
+        } else {
+            Err(DispatchError::AuthenticationTimeout)
+        }
+    }
+
+    pub async fn process_datagram(&self, datagram: Bytes) -> Result<(), DispatchError> {
+        let (cmd, cmd_len) = match AnyCommand::decode(&datagram) {
+            Ok(Decoded::Complete(cmd, len)) => (cmd, len),
+            _ if self.can_fall_back() => {
+                self.fall_back(None);
                 return Ok(());
-            } else {
-                let err = DispatchError::AuthenticationFailed;
-                self.controller
-                    .close(err.as_error_code(), err.to_string().as_bytes());
-                self.is_authenticated.wake();
-                return Err(err);
+            }
+            Ok(Decoded::Incomplete(_)) => return Err(DispatchError::TruncatedDatagram),
+            Err(err) => return Err(err.into()),
+        };
+        self.check_protocol_version(cmd.version())?;
+        let rmt_addr = self.controller.remote_address();
+
+        if let Some(user) = self.is_authenticated.clone().await {
+            check_quota(&user)?;
+
+            match cmd {
+                AnyCommand::V4(Command::Packet { assoc_id, addr, .. }) => {
+                    if self.udp_packet_from.datagram() {
+                        let dst_addr = addr.to_string();
+                        record_command("packet-from-native", Some(&dst_addr), Some(assoc_id));
+                        log::debug!(
+                            "[{rmt_addr}] [{user}] [packet-from-native] [{assoc_id}] [{dst_addr}]"
+                        );
+
+                        let res = task::packet_from_datagram(
+                            datagram.slice(cmd_len..),
+                            self.udp_sessions.clone(),
+                            assoc_id,
+                            addr,
+                            rmt_addr,
+                            user.clone(),
+                        )
+                        .await;
+
+                        match res {
+                            Ok(()) => {}
+                            Err(err) => {
+                                log::warn!(
+                                    "[{rmt_addr}] [{user}] [packet-from-native] [{assoc_id}] [{dst_addr}] {err}"
+                                )
+                            }
+                        }
+
+                        Ok(())
+                    } else {
+                        Err(DispatchError::BadCommand)
+                    }
+                }
+                AnyCommand::V5(v5::Command::Packet {
+                    assoc_id,
+                    pkt_id,
+                    frag_total,
+                    frag_id,
+                    size,
+                    addr,
+                }) => {
+                    if self.udp_packet_from.datagram() {
+                        let payload = datagram.slice(cmd_len..);
+
+                        if payload.len() != size as usize {
+                            return Err(DispatchError::TruncatedDatagram);
+                        }
+
+                        let dst_addr = addr.to_string();
+                        record_command(
+                            "packet-from-native",
+                            Some(&dst_addr),
+                            Some(assoc_id as u32),
+                        );
+                        log::debug!(
+                            "[{rmt_addr}] [{user}] [packet-from-native] [{assoc_id}] [{dst_addr}]"
+                        );
+
+                        let frag = Fragment {
+                            assoc_id,
+                            pkt_id,
+                            frag_total,
+                            frag_id,
+                            addr,
+                        };
+
+                        let res = task::fragment_from_datagram(
+                            payload,
+                            self.udp_sessions.clone(),
+                            frag,
+                            rmt_addr,
+                            user.clone(),
+                        )
+                        .await;
+
+                        match res {
+                            Ok(()) => {}
+                            Err(err) => {
+                                log::warn!(
+                                    "[{rmt_addr}] [{user}] [packet-from-native] [{assoc_id}] [{dst_addr}] {err}"
+                                )
+                            }
+                        }
+
+                        Ok(())
+                    } else {
+                        Err(DispatchError::BadCommand)
+                    }
+                }
+                AnyCommand::V5(v5::Command::Heartbeat) => {
+                    record_command("heartbeat", None, None);
+                    log::debug!("[{rmt_addr}] [{user}] [heartbeat]");
+                    Ok(())
+                }
+                _ => Err(DispatchError::BadCommand),
+            }
+        } else {
+            Err(DispatchError::AuthenticationTimeout)
+        }
+    }
+
+    pub async fn process_received_udp_packet(
+        &self,
+        assoc_id: u32,
+        pkt: Bytes,
+        addr: Address,
+    ) -> Result<(), DispatchError> {
+        let user = self
+            .is_authenticated
+            .clone()
+            .await
+            .ok_or(DispatchError::AuthenticationTimeout)?;
+        check_quota(&user)?;
+
+        let rmt_addr = self.controller.remote_address();
+        let dst_addr = addr.to_string();
+        let is_v5 = self.protocol_version.check() == Some(v5::VERSION);
+
+        match self.udp_packet_from.check().unwrap() {
+            UdpPacketSource::UniStream => {
+                record_command("packet-to-quic", Some(&dst_addr), Some(assoc_id));
+                log::debug!("[{rmt_addr}] [{user}] [packet-to-quic] [{assoc_id}] [{dst_addr}]");
+
+                let res = if is_v5 {
+                    let pkt_id = self.udp_sessions.next_pkt_id();
+                    task::packet_to_uni_stream_v5(
+                        self.controller.clone(),
+                        assoc_id as u16,
+                        pkt_id,
+                        pkt,
+                        addr,
+                    )
+                    .await
+                } else {
+                    task::packet_to_uni_stream(self.controller.clone(), assoc_id, pkt, addr).await
+                };
+
+                match res {
+                    Ok(()) => {}
+                    Err(err) => {
+                        log::warn!("[{rmt_addr}] [{user}] [packet-to-quic] [{assoc_id}] [{dst_addr}] {err}")
+                    }
+                }
+            }
+            UdpPacketSource::Datagram => {
+                record_command("packet-to-native", Some(&dst_addr), Some(assoc_id));
+                log::debug!("[{rmt_addr}] [{user}] [packet-to-native] [{assoc_id}] [{dst_addr}]");
+
+                let res = if is_v5 {
+                    let pkt_id = self.udp_sessions.next_pkt_id();
+                    task::packet_to_datagram_v5(
+                        self.controller.clone(),
+                        assoc_id as u16,
+                        pkt_id,
+                        pkt,
+                        addr,
+                    )
+                    .await
+                } else {
+                    task::packet_to_datagram(self.controller.clone(), assoc_id, pkt, addr).await
+                };
+
+                match res {
+                    Ok(()) => {}
+                    Err(err) => {
+                        log::warn!(
+                            "[{rmt_addr}] [{user}] [packet-to-native] [{assoc_id}] [{dst_addr}] {err}"
+                        )
+                    }
+                }
             }
         }
 
-        if self.is_authenticated.clone().await {
+        Ok(())
+    }
+
+    async fn process_uni_stream_v4(
+        &self,
+        stream: RecvStream,
+        cmd: Command,
+    ) -> Result<(), DispatchError> {
+        let rmt_addr = self.controller.remote_address();
+
+        if let Command::Authenticate { digest } = cmd {
+            record_command("authentication", None, None);
+            return self.authenticate(self.find_v4_user(&digest));
+        }
+
+        if let Some(user) = self.is_authenticated.clone().await {
+            check_quota(&user)?;
+
             match cmd {
                 Command::Authenticate { .. } => unreachable!(),
                 Command::Packet {
@@ -36,7 +322,10 @@ impl Connection {
                 } => {
                     if self.udp_packet_from.uni_stream() {
                         let dst_addr = addr.to_string();
-                        log::debug!("[{rmt_addr}] [packet-from-quic] [{assoc_id}] [{dst_addr}]");
+                        record_command("packet-from-quic", Some(&dst_addr), Some(assoc_id));
+                        log::debug!(
+                            "[{rmt_addr}] [{user}] [packet-from-quic] [{assoc_id}] [{dst_addr}]"
+                        );
 
                         let res = task::packet_from_uni_stream(
                             stream,
@@ -45,13 +334,14 @@ impl Connection {
                             len,
                             addr,
                             rmt_addr,
+                            user.clone(),
                         )
                         .await;
 
                         match res {
                             Ok(()) => {}
                             Err(err) => log::warn!(
-                                "[{rmt_addr}] [packet-from-quic] [{assoc_id}] [{dst_addr}] {err}"
+                                "[{rmt_addr}] [{user}] [packet-from-quic] [{assoc_id}] [{dst_addr}] {err}"
                             ),
                         }
 
@@ -61,17 +351,22 @@ impl Connection {
                     }
                 }
                 Command::Dissociate { assoc_id } => {
-                    let res = task::dissociate(self.udp_sessions.clone(), assoc_id, rmt_addr).await;
+                    record_command("dissociate", None, Some(assoc_id));
+
+                    let res =
+                        task::dissociate(self.udp_sessions.clone(), assoc_id, rmt_addr, &user)
+                            .await;
 
                     match res {
                         Ok(()) => {}
-                        Err(err) => log::warn!("[{rmt_addr}] [dissociate] {err}"),
+                        Err(err) => log::warn!("[{rmt_addr}] [{user}] [dissociate] {err}"),
                     }
 
                     Ok(())
                 }
                 Command::Heartbeat => {
-                    log::debug!("[{rmt_addr}] [heartbeat]");
+                    record_command("heartbeat", None, None);
+                    log::debug!("[{rmt_addr}] [{user}] [heartbeat]");
                     Ok(())
                 }
                 _ => Err(DispatchError::BadCommand),
@@ -81,65 +376,61 @@ impl Connection {
         }
     }
 
-    pub async fn process_bi_stream(
+    async fn process_uni_stream_v5(
         &self,
-        send: SendStream,
-        mut recv: RecvStream,
+        stream: RecvStream,
+        cmd: v5::Command,
     ) -> Result<(), DispatchError> {
-        let cmd = Command::read_from(&mut recv).await?;
         let rmt_addr = self.controller.remote_address();
 
-        if self.is_authenticated.clone().await {
-            match cmd {
-                Command::Connect { addr, fast } => {
-                    let dst_addr = addr.to_string();
-                    let method = if fast { "connect2" } else { "connect" };
-                    log::info!("[{rmt_addr}] [{method}] [{dst_addr}]");
-
-                    let res = task::connect(send, recv, addr, fast).await;
-
-                    match res {
-                        Ok(()) => {}
-                        Err(err) => log::warn!("[{rmt_addr}] [{method}] [{dst_addr}] {err}"),
-                    }
-
-                    Ok(())
-                }
-                _ => Err(DispatchError::BadCommand),
-            }
-        } else {
-            Err(DispatchError::AuthenticationTimeout)
+        if let v5::Command::Authenticate { uuid, token } = cmd {
+            record_command("authentication", None, None);
+            return self.authenticate(self.find_v5_user(Uuid::from_bytes(uuid), token));
         }
-    }
 
-    pub async fn process_datagram(&self, datagram: Bytes) -> Result<(), DispatchError> {
-        let cmd = Command::read_from(&mut datagram.as_ref()).await?;
-        let rmt_addr = self.controller.remote_address();
-        let cmd_len = cmd.serialized_len();
+        if let Some(user) = self.is_authenticated.clone().await {
+            check_quota(&user)?;
 
-        if self.is_authenticated.clone().await {
             match cmd {
-                Command::Packet { assoc_id, addr, .. } => {
-                    if self.udp_packet_from.datagram() {
+                v5::Command::Authenticate { .. } => unreachable!(),
+                v5::Command::Packet {
+                    assoc_id,
+                    pkt_id,
+                    frag_total,
+                    frag_id,
+                    size,
+                    addr,
+                } => {
+                    if self.udp_packet_from.uni_stream() {
                         let dst_addr = addr.to_string();
-                        log::debug!("[{rmt_addr}] [packet-from-native] [{assoc_id}] [{dst_addr}]");
+                        record_command("packet-from-quic", Some(&dst_addr), Some(assoc_id as u32));
+                        log::debug!(
+                            "[{rmt_addr}] [{user}] [packet-from-quic] [{assoc_id}] [{dst_addr}]"
+                        );
 
-                        let res = task::packet_from_datagram(
-                            datagram.slice(cmd_len..),
-                            self.udp_sessions.clone(),
+                        let frag = Fragment {
                             assoc_id,
+                            pkt_id,
+                            frag_total,
+                            frag_id,
                             addr,
+                        };
+
+                        let res = task::fragment_from_uni_stream(
+                            stream,
+                            self.udp_sessions.clone(),
+                            frag,
+                            size,
                             rmt_addr,
+                            user.clone(),
                         )
                         .await;
 
                         match res {
                             Ok(()) => {}
-                            Err(err) => {
-                                log::warn!(
-                                    "[{rmt_addr}] [packet-from-native] [{assoc_id}] [{dst_addr}] {err}"
-                                )
-                            }
+                            Err(err) => log::warn!(
+                                "[{rmt_addr}] [{user}] [packet-from-quic] [{assoc_id}] [{dst_addr}] {err}"
+                            ),
                         }
 
                         Ok(())
@@ -147,6 +438,29 @@ impl Connection {
                         Err(DispatchError::BadCommand)
                     }
                 }
+                v5::Command::Dissociate { assoc_id } => {
+                    record_command("dissociate", None, Some(assoc_id as u32));
+
+                    let res = task::dissociate(
+                        self.udp_sessions.clone(),
+                        assoc_id as u32,
+                        rmt_addr,
+                        &user,
+                    )
+                    .await;
+
+                    match res {
+                        Ok(()) => {}
+                        Err(err) => log::warn!("[{rmt_addr}] [{user}] [dissociate] {err}"),
+                    }
+
+                    Ok(())
+                }
+                v5::Command::Heartbeat => {
+                    record_command("heartbeat", None, None);
+                    log::debug!("[{rmt_addr}] [{user}] [heartbeat]");
+                    Ok(())
+                }
                 _ => Err(DispatchError::BadCommand),
             }
         } else {
@@ -154,47 +468,131 @@ impl Connection {
         }
     }
 
-    pub async fn process_received_udp_packet(
-        &self,
-        assoc_id: u32,
-        pkt: Bytes,
-        addr: Address,
-    ) -> Result<(), DispatchError> {
+    fn authenticate(&self, user: Option<Arc<User>>) -> Result<(), DispatchError> {
         let rmt_addr = self.controller.remote_address();
-        let dst_addr = addr.to_string();
-
-        match self.udp_packet_from.check().unwrap() {
-            UdpPacketSource::UniStream => {
-                log::debug!("[{rmt_addr}] [packet-to-quic] [{assoc_id}] [{dst_addr}]");
 
-                let res =
-                    task::packet_to_uni_stream(self.controller.clone(), assoc_id, pkt, addr).await;
+        let err = match user {
+            Some(user) if !self.is_allowed(&user) => {
+                log::debug!("[{rmt_addr}] [{user}] [authentication] not allowed on this server name or ALPN protocol");
+                self.metrics.authentication(Some(&user), "not_allowed");
+                DispatchError::AuthenticationFailed
+            }
+            Some(user) if !user.traffic().is_quota_exhausted() => {
+                log::debug!("[{rmt_addr}] [{user}] [authentication]");
+                self.metrics.authentication(Some(&user), "success");
+                self.span.record("user", user.name());
 
-                match res {
-                    Ok(()) => {}
-                    Err(err) => {
-                        log::warn!("[{rmt_addr}] [packet-to-quic] [{assoc_id}] [{dst_addr}] {err}")
-                    }
-                }
+                self.is_authenticated.set_authenticated(user);
+                self.is_authenticated.wake();
+                return Ok(());
             }
-            UdpPacketSource::Datagram => {
-                log::debug!("[{rmt_addr}] [packet-to-native] [{assoc_id}] [{dst_addr}]");
+            Some(user) => {
+                self.metrics.authentication(Some(&user), "quota_exhausted");
+                DispatchError::QuotaExhausted
+            }
+            None => {
+                self.metrics.authentication(None, "failed");
+                DispatchError::AuthenticationFailed
+            }
+        };
 
-                let res =
-                    task::packet_to_datagram(self.controller.clone(), assoc_id, pkt, addr).await;
+        if matches!(err, DispatchError::AuthenticationFailed) && self.can_fall_back() {
+            log::debug!("[{rmt_addr}] [-] [authentication] {err}");
+            self.fall_back(None);
+            return Ok(());
+        }
 
-                match res {
-                    Ok(()) => {}
-                    Err(err) => {
-                        log::warn!(
-                            "[{rmt_addr}] [packet-to-native] [{assoc_id}] [{dst_addr}] {err}"
-                        )
-                    }
-                }
-            }
+        self.controller
+            .close(err.as_error_code(), err.to_string().as_bytes());
+        self.is_authenticated.wake();
+        Err(err)
+    }
+
+    /// Only connections not authenticated yet are handed over to the camouflage site
+    fn can_fall_back(&self) -> bool {
+        self.fallback.is_some() && self.is_authenticated.user().is_none()
+    }
+
+    fn fall_back(&self, stream: Option<PendingStream>) {
+        if let Some(fallback) = &self.fallback {
+            fallback.trigger(stream);
         }
+    }
 
-        Ok(())
+    /// Checks the user against the users allowed on the server name and the ALPN protocol the client connected with
+    fn is_allowed(&self, user: &User) -> bool {
+        let handshake_data = self
+            .controller
+            .handshake_data()
+            .and_then(|data| data.downcast::<HandshakeData>().ok());
+
+        let (server_name, alpn) = match &handshake_data {
+            Some(data) => (data.server_name.as_deref(), data.protocol.as_deref()),
+            None => (None, None),
+        };
+
+        self.users.is_allowed(user, server_name, alpn)
+    }
+
+    /// Authenticates the connection right away if the client certificate belongs to a user. A client with a certificate of no user can still authenticate with a token
+    pub(super) fn authenticate_by_certificate(&self) -> Result<(), DispatchError> {
+        let certs = match self
+            .controller
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<Certificate>>().ok())
+        {
+            Some(certs) => certs,
+            None => return Ok(()),
+        };
+
+        match certs.first().and_then(|cert| {
+            self.users
+                .find_by_certificate_names(&certificate::names(cert))
+        }) {
+            Some(user) => self.authenticate(Some(user)),
+            None => Ok(()),
+        }
+    }
+
+    /// Tries the digest as a session-bound digest first, then as a static one if allowed
+    fn find_v4_user(&self, digest: &[u8; 32]) -> Option<Arc<User>> {
+        let mut keying_material = [0; SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN];
+
+        let user = self
+            .controller
+            .export_keying_material(&mut keying_material, SESSION_BOUND_AUTH_LABEL, &[])
+            .ok()
+            .and_then(|()| {
+                self.users
+                    .find_by_session_bound_digest(digest, &keying_material)
+            });
+
+        if user.is_some() || self.require_session_bound_auth {
+            user
+        } else {
+            self.users.find_by_token(digest)
+        }
+    }
+
+    /// A TUIC v5 token is the TLS keying material exported with the UUID as the label and the password as the context
+    fn find_v5_user(&self, uuid: Uuid, token: [u8; 32]) -> Option<Arc<User>> {
+        let (password, user) = self.users.find_by_uuid(&uuid)?;
+        let mut expected = [0; 32];
+
+        self.controller
+            .export_keying_material(&mut expected, uuid.as_bytes(), password.as_bytes())
+            .ok()
+            .filter(|()| constant_time::verify_slices_are_equal(&expected, &token).is_ok())
+            .map(|()| user)
+    }
+
+    /// Locks the connection to the TUIC version of the first command received
+    fn check_protocol_version(&self, ver: u8) -> Result<(), DispatchError> {
+        if self.protocol_version.set(ver) {
+            Ok(())
+        } else {
+            Err(ProtocolError::UnsupportedVersion(ver).into())
+        }
     }
 }
 
@@ -202,12 +600,18 @@ impl Connection {
 pub enum DispatchError {
     #[error(transparent)]
     Io(#[from] IoError),
+    #[error(transparent)]
+    Protocol(ProtocolError),
+    #[error("truncated datagram")]
+    TruncatedDatagram,
     #[error("authentication failed")]
     AuthenticationFailed,
     #[error("authentication timeout")]
     AuthenticationTimeout,
     #[error("bad command")]
     BadCommand,
+    #[error("traffic quota exhausted")]
+    QuotaExhausted,
 }
 
 impl DispatchError {
@@ -215,13 +619,51 @@ impl DispatchError {
     const CODE_AUTHENTICATION_FAILED: VarInt = VarInt::from_u32(0xfffffff1);
     const CODE_AUTHENTICATION_TIMEOUT: VarInt = VarInt::from_u32(0xfffffff2);
     const CODE_BAD_COMMAND: VarInt = VarInt::from_u32(0xfffffff3);
+    const CODE_QUOTA_EXHAUSTED: VarInt = VarInt::from_u32(0xfffffff4);
+    // 0xfffffff5 is sent on shutdown
+    const CODE_UNSUPPORTED_VERSION: VarInt = VarInt::from_u32(0xfffffff6);
+    const CODE_UNKNOWN_COMMAND: VarInt = VarInt::from_u32(0xfffffff7);
 
     pub fn as_error_code(&self) -> VarInt {
         match self {
-            Self::Io(_) => Self::CODE_PROTOCOL,
+            Self::Protocol(ProtocolError::UnsupportedVersion(_)) => Self::CODE_UNSUPPORTED_VERSION,
+            Self::Protocol(ProtocolError::UnknownCommand(_)) => Self::CODE_UNKNOWN_COMMAND,
+            Self::Io(_) | Self::Protocol(_) | Self::TruncatedDatagram => Self::CODE_PROTOCOL,
             Self::AuthenticationFailed => Self::CODE_AUTHENTICATION_FAILED,
             Self::AuthenticationTimeout => Self::CODE_AUTHENTICATION_TIMEOUT,
             Self::BadCommand => Self::CODE_BAD_COMMAND,
+            Self::QuotaExhausted => Self::CODE_QUOTA_EXHAUSTED,
+        }
+    }
+}
+
+/// Records the command in the span of the stream or the datagram carrying it
+fn record_command(method: &str, dst_addr: Option<&str>, assoc_id: Option<u32>) {
+    let span = Span::current();
+    span.record("method", method);
+
+    if let Some(dst_addr) = dst_addr {
+        span.record("destination", dst_addr);
+    }
+
+    if let Some(assoc_id) = assoc_id {
+        span.record("assoc_id", assoc_id);
+    }
+}
+
+fn check_quota(user: &User) -> Result<(), DispatchError> {
+    if user.traffic().is_quota_exhausted() {
+        Err(DispatchError::QuotaExhausted)
+    } else {
+        Ok(())
+    }
+}
+
+impl From<ProtocolError> for DispatchError {
+    fn from(err: ProtocolError) -> Self {
+        match err {
+            ProtocolError::Io(err) => Self::Io(err),
+            err => Self::Protocol(err),
         }
     }
 }
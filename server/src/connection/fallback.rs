@@ -0,0 +1,99 @@
+use crate::camouflage::{Camouflage, PendingStream};
+use std::{
+    io::Result as IoResult,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Notify,
+    },
+};
+
+/// Hands a connection that fails authentication over to the camouflage site
+#[derive(Clone)]
+pub struct Fallback {
+    camouflage: Arc<Camouflage>,
+    pending_tx: UnboundedSender<PendingStream>,
+    is_triggered: Arc<AtomicBool>,
+    triggered: Arc<Notify>,
+}
+
+impl Fallback {
+    pub fn new(camouflage: Arc<Camouflage>) -> (Self, UnboundedReceiver<PendingStream>) {
+        let (pending_tx, pending_rx) = mpsc::unbounded_channel();
+
+        let fallback = Self {
+            camouflage,
+            pending_tx,
+            is_triggered: Arc::new(AtomicBool::new(false)),
+            triggered: Arc::new(Notify::new()),
+        };
+
+        (fallback, pending_rx)
+    }
+
+    pub fn camouflage(&self) -> &Camouflage {
+        &self.camouflage
+    }
+
+    /// Hands the connection over, along with a stream already read from. Can be called more than once
+    pub fn trigger(&self, stream: Option<PendingStream>) {
+        if let Some(stream) = stream {
+            let _ = self.pending_tx.send(stream);
+        }
+
+        if !self.is_triggered.swap(true, Ordering::AcqRel) {
+            self.triggered.notify_one();
+        }
+    }
+
+    /// Resolves once triggered. Only one task may wait
+    pub async fn triggered(&self) {
+        self.triggered.notified().await
+    }
+}
+
+/// Keeps the bytes read through it, so that they can be replayed to the camouflage site
+pub struct Recorded<'a, R> {
+    inner: &'a mut R,
+    read: Vec<u8>,
+}
+
+impl<'a, R> Recorded<'a, R> {
+    pub fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            read: Vec::new(),
+        }
+    }
+
+    pub fn into_read(self) -> Vec<u8> {
+        self.read
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Recorded<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+
+        let res = Pin::new(&mut *this.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = res {
+            this.read.extend_from_slice(&buf.filled()[filled..]);
+        }
+
+        res
+    }
+}
@@ -1,14 +1,23 @@
 use self::{
     authenticate::IsAuthenticated,
     dispatch::DispatchError,
+    fallback::Fallback,
     udp::{RecvPacketReceiver, UdpPacketFrom, UdpPacketSource, UdpSessionMap},
 };
 
+pub use self::udp::UdpRelayConfig;
+use crate::{
+    camouflage::Camouflage, metrics::Metrics, router::Router, shutdown::InFlight, user::Users,
+};
+
+use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
-use quinn::{Connecting, Connection as QuinnConnection, ConnectionError};
+use quinn::{
+    crypto::rustls::HandshakeData, Connecting, Connection as QuinnConnection, ConnectionError,
+    VarInt,
+};
 use std::{
-    collections::HashSet,
-    future::Future,
+    future::{self, Future},
     pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -17,69 +26,150 @@ use std::{
     task::{Context, Poll, Waker},
     time::Duration,
 };
-use tokio::time;
+use tokio::{sync::watch::Receiver as WatchReceiver, time};
+use tracing::{field::Empty, Instrument, Span};
 
 mod authenticate;
 mod dispatch;
-pub mod socks5_out;
+mod fallback;
 mod task;
 mod udp;
 
+/// Sent to clients when the server shuts down
+pub const CODE_SHUTTING_DOWN: VarInt = VarInt::from_u32(0xfffffff5);
+
 #[derive(Clone)]
 pub struct Connection {
     controller: QuinnConnection,
     udp_packet_from: UdpPacketFrom,
     udp_sessions: Arc<UdpSessionMap>,
-    token: Arc<HashSet<[u8; 32]>>,
+    users: Arc<Users>,
+    router: Arc<Router>,
+    metrics: Arc<Metrics>,
+    require_session_bound_auth: bool,
+    protocol_version: ProtocolVersion,
     is_authenticated: IsAuthenticated,
+    /// Relayed TCP connections, drained on shutdown
+    tunnels: InFlight,
+    fallback: Option<Fallback>,
+    /// The user is recorded in it once authenticated
+    span: Span,
 }
 
 impl Connection {
+    #[allow(clippy::too_many_arguments)]
     pub async fn handle(
         conn: Connecting,
-        token: Arc<HashSet<[u8; 32]>>,
+        users: Arc<Users>,
+        router: Arc<Router>,
+        metrics: Arc<Metrics>,
         auth_timeout: Duration,
-        max_pkt_size: usize,
+        require_session_bound_auth: bool,
+        udp_relay_config: UdpRelayConfig,
+        camouflage: Option<Arc<Camouflage>>,
+        shutdown: WatchReceiver<bool>,
     ) {
         let rmt_addr = conn.remote_address();
 
         match conn.await {
             Ok(connection) => {
+                Span::current().record("connection_id", connection.stable_id());
                 log::debug!("[{rmt_addr}] [establish]");
+                let _active = metrics.connection();
 
-                let (udp_sessions, recv_pkt_rx) = UdpSessionMap::new(max_pkt_size);
+                let (udp_sessions, recv_pkt_rx) =
+                    UdpSessionMap::new(router.clone(), udp_relay_config, metrics.clone());
                 let is_closed = IsClosed::new();
                 let is_authed = IsAuthenticated::new(is_closed.clone());
 
+                let (fallback, pending_rx) = match camouflage {
+                    Some(camouflage) => {
+                        let (fallback, pending_rx) = Fallback::new(camouflage);
+                        (Some(fallback), Some(pending_rx))
+                    }
+                    None => (None, None),
+                };
+
+                // clients negotiating an ALPN protocol of the camouflage site go there right away
+                let is_site_protocol = fallback.as_ref().map_or(false, |fallback| {
+                    connection
+                        .handshake_data()
+                        .and_then(|data| data.downcast::<HandshakeData>().ok())
+                        .and_then(|data| data.protocol)
+                        .map_or(false, |protocol| {
+                            fallback.camouflage().is_site_protocol(&protocol)
+                        })
+                });
+
                 let conn = Self {
                     controller: connection,
                     udp_packet_from: UdpPacketFrom::new(),
                     udp_sessions: Arc::new(udp_sessions),
-                    token,
-                    is_authenticated: is_authed,
+                    users,
+                    router,
+                    metrics,
+                    require_session_bound_auth,
+                    protocol_version: ProtocolVersion::new(),
+                    is_authenticated: is_authed.clone(),
+                    tunnels: InFlight::new(),
+                    fallback,
+                    span: Span::current(),
                 };
 
-                let res = tokio::select! {
-                    res = Self::listen_uni_streams(conn.clone()) => res,
-                    res = Self::listen_bi_streams(conn.clone()) => res,
-                    res = Self::listen_datagrams(conn.clone()) => res,
-                    res = Self::listen_received_udp_packet(conn.clone(), recv_pkt_rx) => res,
-                    Err(err) = Self::handle_authentication_timeout(conn, auth_timeout) => Err(err),
+                let res = if is_site_protocol {
+                    Ok(())
+                } else {
+                    if let Err(err) = conn.authenticate_by_certificate() {
+                        log::error!("[{rmt_addr}] [-] {err}");
+                    }
+
+                    let fallen_back = async {
+                        match &conn.fallback {
+                            Some(fallback) => fallback.triggered().await,
+                            None => future::pending().await,
+                        }
+                    };
+
+                    tokio::select! {
+                        res = Self::listen_uni_streams(conn.clone()) => res,
+                        res = Self::listen_bi_streams(conn.clone(), shutdown.clone()) => res,
+                        res = Self::listen_datagrams(conn.clone()) => res,
+                        res = Self::listen_received_udp_packet(conn.clone(), recv_pkt_rx) => res,
+                        Err(err) = Self::handle_authentication_timeout(conn.clone(), auth_timeout) => Err(err),
+                        Err(err) = Self::handle_shutdown(conn.clone(), shutdown.clone()) => Err(err),
+                        () = fallen_back => Ok(()),
+                    }
+                };
+
+                // the connection is handed over to the camouflage site
+                let res = match (res, &conn.fallback, pending_rx) {
+                    (Ok(()), Some(fallback), Some(pending_rx)) => {
+                        log::debug!("[{rmt_addr}] [-] [camouflage]");
+
+                        tokio::select! {
+                            err = fallback.camouflage().relay(conn.controller.clone(), pending_rx) => Err(err),
+                            Err(err) = Self::handle_shutdown(conn.clone(), shutdown) => Err(err),
+                        }
+                    }
+                    (res, _, _) => res,
                 };
 
                 match res {
                     Ok(()) => unreachable!(),
                     Err(err) => {
                         is_closed.set_closed();
+                        let user = is_authed.user_name();
 
                         match err {
                             ConnectionError::TimedOut => {
-                                log::debug!("[{rmt_addr}] [disconnect] [connection timeout]")
+                                log::debug!(
+                                    "[{rmt_addr}] [{user}] [disconnect] [connection timeout]"
+                                )
                             }
                             ConnectionError::LocallyClosed => {
-                                log::debug!("[{rmt_addr}] [disconnect] [locally closed]")
+                                log::debug!("[{rmt_addr}] [{user}] [disconnect] [locally closed]")
                             }
-                            err => log::error!("[{rmt_addr}] [disconnect] {err}"),
+                            err => log::error!("[{rmt_addr}] [{user}] [disconnect] {err}"),
                         }
                     }
                 }
@@ -92,7 +182,15 @@ impl Connection {
         while let Ok(stream) = self.controller.accept_uni().await {
             let conn = self.clone();
 
-            tokio::spawn(async move {
+            let span = tracing::info_span!(
+                "stream",
+                stream_id = VarInt::from(stream.id()).into_inner(),
+                method = Empty,
+                destination = Empty,
+                assoc_id = Empty,
+            );
+
+            let task = async move {
                 match conn.process_uni_stream(stream).await {
                     Ok(()) => {}
                     Err(err) => {
@@ -100,20 +198,38 @@ impl Connection {
                             .close(err.as_error_code(), err.to_string().as_bytes());
 
                         let rmt_addr = conn.controller.remote_address();
-                        log::error!("[{rmt_addr}] {err}");
+                        let user = conn.is_authenticated.user_name();
+                        log::error!("[{rmt_addr}] [{user}] {err}");
                     }
                 }
-            });
+            };
+
+            tokio::spawn(task.instrument(span));
         }
 
         Err(ConnectionError::LocallyClosed)
     }
 
-    async fn listen_bi_streams(self) -> Result<(), ConnectionError> {
-        while let Ok((send, recv)) = self.controller.accept_bi().await {
+    /// Bidirectional streams opened once the server is shutting down are refused, so that only the relayed TCP connections already open are drained
+    async fn listen_bi_streams(self, shutdown: WatchReceiver<bool>) -> Result<(), ConnectionError> {
+        while let Ok((mut send, mut recv)) = self.controller.accept_bi().await {
+            if *shutdown.borrow() {
+                let _ = send.reset(CODE_SHUTTING_DOWN);
+                let _ = recv.stop(CODE_SHUTTING_DOWN);
+                continue;
+            }
+
             let conn = self.clone();
 
-            tokio::spawn(async move {
+            let span = tracing::info_span!(
+                "stream",
+                stream_id = VarInt::from(send.id()).into_inner(),
+                method = Empty,
+                destination = Empty,
+                assoc_id = Empty,
+            );
+
+            let task = async move {
                 match conn.process_bi_stream(send, recv).await {
                     Ok(()) => {}
                     Err(err) => {
@@ -121,10 +237,13 @@ impl Connection {
                             .close(err.as_error_code(), err.to_string().as_bytes());
 
                         let rmt_addr = conn.controller.remote_address();
-                        log::error!("[{rmt_addr}] {err}");
+                        let user = conn.is_authenticated.user_name();
+                        log::error!("[{rmt_addr}] [{user}] {err}");
                     }
                 }
-            });
+            };
+
+            tokio::spawn(task.instrument(span));
         }
 
         Err(ConnectionError::LocallyClosed)
@@ -134,7 +253,14 @@ impl Connection {
         while let Ok(datagram) = self.controller.read_datagram().await {
             let conn = self.clone();
 
-            tokio::spawn(async move {
+            let span = tracing::info_span!(
+                "datagram",
+                method = Empty,
+                destination = Empty,
+                assoc_id = Empty,
+            );
+
+            let task = async move {
                 match conn.process_datagram(datagram).await {
                     Ok(()) => {}
                     Err(err) => {
@@ -142,10 +268,13 @@ impl Connection {
                             .close(err.as_error_code(), err.to_string().as_bytes());
 
                         let rmt_addr = conn.controller.remote_address();
-                        log::error!("[{rmt_addr}] {err}");
+                        let user = conn.is_authenticated.user_name();
+                        log::error!("[{rmt_addr}] [{user}] {err}");
                     }
                 }
-            });
+            };
+
+            tokio::spawn(task.instrument(span));
         }
 
         Err(ConnectionError::LocallyClosed)
@@ -158,7 +287,14 @@ impl Connection {
         while let Some((assoc_id, pkt, addr)) = recv_pkt_rx.recv().await {
             let conn = self.clone();
 
-            tokio::spawn(async move {
+            let span = tracing::info_span!(
+                "packet",
+                method = Empty,
+                destination = Empty,
+                assoc_id = Empty,
+            );
+
+            let task = async move {
                 match conn.process_received_udp_packet(assoc_id, pkt, addr).await {
                     Ok(()) => {}
                     Err(err) => {
@@ -166,10 +302,13 @@ impl Connection {
                             .close(err.as_error_code(), err.to_string().as_bytes());
 
                         let rmt_addr = conn.controller.remote_address();
-                        log::error!("[{rmt_addr}] {err}");
+                        let user = conn.is_authenticated.user_name();
+                        log::error!("[{rmt_addr}] [{user}] {err}");
                     }
                 }
-            });
+            };
+
+            tokio::spawn(task.instrument(span));
         }
 
         Err(ConnectionError::LocallyClosed)
@@ -182,6 +321,13 @@ impl Connection {
         };
 
         if !is_timeout {
+            return Ok(());
+        }
+
+        self.metrics.authentication(None, "timeout");
+
+        if let Some(fallback) = &self.fallback {
+            fallback.trigger(None);
             Ok(())
         } else {
             let err = DispatchError::AuthenticationTimeout;
@@ -191,11 +337,55 @@ impl Connection {
             self.is_authenticated.wake();
 
             let rmt_addr = self.controller.remote_address();
-            log::error!("[{rmt_addr}] {err}");
+            let user = self.is_authenticated.user_name();
+            log::error!("[{rmt_addr}] [{user}] {err}");
 
             Err(ConnectionError::LocallyClosed)
         }
     }
+
+    /// Closes the connection once the server is shutting down and no relayed TCP connection is left
+    async fn handle_shutdown(
+        self,
+        mut shutdown: WatchReceiver<bool>,
+    ) -> Result<(), ConnectionError> {
+        while !*shutdown.borrow() {
+            // the server never drops the sender before shutting down
+            if shutdown.changed().await.is_err() {
+                return Ok(());
+            }
+        }
+
+        self.tunnels.wait_idle().await;
+        self.controller
+            .close(CODE_SHUTTING_DOWN, b"server shutting down");
+
+        let rmt_addr = self.controller.remote_address();
+        let user = self.is_authenticated.user_name();
+        log::debug!("[{rmt_addr}] [{user}] [shutdown]");
+
+        Err(ConnectionError::LocallyClosed)
+    }
+}
+
+/// The TUIC version a connection speaks, fixed by the first command received
+#[derive(Clone)]
+pub struct ProtocolVersion(Arc<AtomicCell<Option<u8>>>);
+
+impl ProtocolVersion {
+    fn new() -> Self {
+        Self(Arc::new(AtomicCell::new(None)))
+    }
+
+    pub fn check(&self) -> Option<u8> {
+        self.0.load()
+    }
+
+    pub fn set(&self, ver: u8) -> bool {
+        self.0
+            .compare_exchange(None, Some(ver))
+            .map_or_else(|cur| cur == Some(ver), |_| true)
+    }
 }
 
 #[derive(Clone)]
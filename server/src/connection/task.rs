@@ -1,67 +1,79 @@
-use super::socks5_out;
-use super::udp::UdpSessionMap;
+use super::udp::{Fragment, UdpSessionMap};
+use crate::{
+    metrics::{Active, Counter, Metrics},
+    outbound::Unreachable,
+    router::{Route, Router},
+    user::User,
+};
 use bytes::{Bytes, BytesMut};
+use futures_util::ready;
 use quinn::{
     Connection as QuinnConnection, ConnectionError, ReadExactError, RecvStream, SendDatagramError,
     SendStream, WriteError,
 };
 use std::{
-    io::{Error as IoError, IoSlice},
+    future::Future,
+    io::{Error as IoError, ErrorKind, IoSlice},
     net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::{self, TcpStream},
+    time::{self, Sleep},
 };
-use tuic_protocol::{Address, Command};
+use tuic_protocol::{v5, Address, Command, ProtocolError, Reply};
 
+/// Returns the bytes sent to and received from the destination
 pub async fn connect(
     mut send: SendStream,
     recv: RecvStream,
     addr: Address,
     fast: bool,
-) -> Result<(), TaskError> {
-    let mut target = None;
-
-    if !socks5_out::is_inited() {
-        let addrs = match addr {
-            Address::SocketAddress(addr) => Ok(vec![addr]),
-            Address::DomainAddress(domain, port) => net::lookup_host((domain.as_str(), port))
-                .await
-                .map(|res| res.collect()),
-        }?;
-
-        for addr in addrs {
-            if let Ok(target_stream) = TcpStream::connect(addr).await {
-                let _ = target_stream.set_nodelay(true);
-                target = Some(target_stream);
-                break;
+    user: Arc<User>,
+    router: &Router,
+    metrics: &Metrics,
+) -> Result<(u64, u64), TaskError> {
+    let started = Instant::now();
+
+    let target = match router.route(&addr, &user) {
+        Route::Outbound(outbound) => outbound.connect_tcp(addr).await.map_err(TaskError::from),
+        Route::Reject => Err(TaskError::Rejected),
+    };
+
+    let outcome = match &target {
+        Ok(_) => "success",
+        Err(TaskError::Rejected) => "rejected",
+        Err(_) => "failed",
+    };
+
+    metrics.tcp_connect(&user, outcome, started.elapsed());
+
+    match target {
+        Ok(target) => {
+            if !fast {
+                let resp = Command::new_response(Reply::Succeeded);
+                resp.write_to(&mut send).await?;
             }
-        }
-    } else {
-        target = socks5_out::connect(addr).await.ok();
-    }
+            let mut target = Metered::new(target, user, metrics);
+            let mut tunnel = BiStream(send, recv);
+            let (received, sent) = realm_io::bidi_copy(&mut target, &mut tunnel).await?;
 
-    if let Some(mut target) = target {
-        if !fast {
-            let resp = Command::new_response(true);
-            resp.write_to(&mut send).await?;
+            Ok((sent, received))
         }
-        let mut tunnel = BiStream(send, recv);
-        realm_io::bidi_copy(&mut target, &mut tunnel).await?;
-    } else {
-        if !fast {
-            let resp = Command::new_response(false);
-            resp.write_to(&mut send).await?;
-        }
-        send.finish().await?;
-    };
+        Err(err) => {
+            if !fast {
+                let resp = Command::new_response(err.reply());
+                resp.write_to(&mut send).await?;
+            }
+            send.finish().await?;
 
-    Ok(())
+            Err(err)
+        }
+    }
 }
 
 pub async fn packet_from_uni_stream(
@@ -71,12 +83,15 @@ pub async fn packet_from_uni_stream(
     len: u16,
     addr: Address,
     src_addr: SocketAddr,
+    user: Arc<User>,
 ) -> Result<(), TaskError> {
     let mut buf = vec![0; len as usize];
     stream.read_exact(&mut buf).await?;
 
     let pkt = Bytes::from(buf);
-    udp_sessions.send(assoc_id, pkt, addr, src_addr).await?;
+    udp_sessions
+        .send(assoc_id, pkt, addr, src_addr, user)
+        .await?;
 
     Ok(())
 }
@@ -87,8 +102,51 @@ pub async fn packet_from_datagram(
     assoc_id: u32,
     addr: Address,
     src_addr: SocketAddr,
+    user: Arc<User>,
+) -> Result<(), TaskError> {
+    udp_sessions
+        .send(assoc_id, pkt, addr, src_addr, user)
+        .await?;
+    Ok(())
+}
+
+pub async fn fragment_from_uni_stream(
+    mut stream: RecvStream,
+    udp_sessions: Arc<UdpSessionMap>,
+    frag: Fragment,
+    size: u16,
+    src_addr: SocketAddr,
+    user: Arc<User>,
+) -> Result<(), TaskError> {
+    // checked before allocating, as the reassembler only sees the fragment once it is read
+    if size as usize > udp_sessions.max_pkt_size() {
+        return Err(TaskError::Protocol(ProtocolError::PacketTooLong(
+            size as usize,
+            udp_sessions.max_pkt_size(),
+        )));
+    }
+
+    let mut buf = vec![0; size as usize];
+    stream.read_exact(&mut buf).await?;
+
+    let payload = Bytes::from(buf);
+    udp_sessions
+        .send_fragment(frag, payload, src_addr, user)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn fragment_from_datagram(
+    payload: Bytes,
+    udp_sessions: Arc<UdpSessionMap>,
+    frag: Fragment,
+    src_addr: SocketAddr,
+    user: Arc<User>,
 ) -> Result<(), TaskError> {
-    udp_sessions.send(assoc_id, pkt, addr, src_addr).await?;
+    udp_sessions
+        .send_fragment(frag, payload, src_addr, user)
+        .await?;
     Ok(())
 }
 
@@ -117,7 +175,7 @@ pub async fn packet_to_datagram(
     let cmd = Command::new_packet(assoc_id, pkt.len() as u16, addr);
 
     let mut buf = BytesMut::with_capacity(cmd.serialized_len());
-    cmd.write_to_buf(&mut buf);
+    cmd.write_to_buf(&mut buf)?;
     buf.extend_from_slice(&pkt);
 
     let pkt = buf.freeze();
@@ -126,12 +184,52 @@ pub async fn packet_to_datagram(
     Ok(())
 }
 
+pub async fn packet_to_uni_stream_v5(
+    conn: QuinnConnection,
+    assoc_id: u16,
+    pkt_id: u16,
+    pkt: Bytes,
+    addr: Address,
+) -> Result<(), TaskError> {
+    let mut stream = conn.open_uni().await?;
+
+    let cmd = v5::Command::new_packet(assoc_id, pkt_id, 1, 0, pkt.len() as u16, addr.into());
+    cmd.write_to(&mut stream).await?;
+    stream.write_all(&pkt).await?;
+    stream.finish().await?;
+
+    Ok(())
+}
+
+pub async fn packet_to_datagram_v5(
+    conn: QuinnConnection,
+    assoc_id: u16,
+    pkt_id: u16,
+    pkt: Bytes,
+    addr: Address,
+) -> Result<(), TaskError> {
+    let max_len = conn
+        .max_datagram_size()
+        .ok_or(SendDatagramError::UnsupportedByPeer)?;
+
+    for (cmd, frag) in v5::fragment(assoc_id, pkt_id, addr.into(), pkt, max_len)? {
+        let mut buf = BytesMut::with_capacity(cmd.serialized_len() + frag.len());
+        cmd.write_to_buf(&mut buf)?;
+        buf.extend_from_slice(&frag);
+
+        conn.send_datagram(buf.freeze())?;
+    }
+
+    Ok(())
+}
+
 pub async fn dissociate(
     udp_sessions: Arc<UdpSessionMap>,
     assoc_id: u32,
     src_addr: SocketAddr,
+    user: &User,
 ) -> Result<(), TaskError> {
-    udp_sessions.dissociate(assoc_id, src_addr);
+    udp_sessions.dissociate(assoc_id, src_addr, user);
     Ok(())
 }
 
@@ -182,6 +280,109 @@ impl AsyncWrite for BiStream {
     }
 }
 
+/// Charges the traffic of a TCP relay to its user, pausing while the rate limit is exceeded, and counts it in the metrics
+struct Metered<S> {
+    inner: S,
+    user: Arc<User>,
+    uploaded: Arc<Counter>,
+    downloaded: Arc<Counter>,
+    _active: Active,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Metered<S> {
+    fn new(inner: S, user: Arc<User>, metrics: &Metrics) -> Self {
+        Self {
+            inner,
+            uploaded: metrics.bytes(&user, "tcp", "upload"),
+            downloaded: metrics.bytes(&user, "tcp", "download"),
+            _active: metrics.tcp_tunnel(&user),
+            user,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    fn check_quota(&self) -> Result<(), IoError> {
+        if self.user.traffic().is_quota_exhausted() {
+            Err(IoError::new(ErrorKind::Other, "traffic quota exhausted"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn poll_delay(delay: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+
+        Poll::Ready(())
+    }
+
+    fn set_delay(delay: &mut Option<Pin<Box<Sleep>>>, duration: Duration) {
+        if !duration.is_zero() {
+            *delay = Some(Box::pin(time::sleep(duration)));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), IoError>> {
+        let this = &mut *self;
+
+        ready!(Self::poll_delay(&mut this.read_delay, cx));
+        this.check_quota()?;
+
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+
+        let len = buf.filled().len() - filled;
+        this.downloaded.inc(len as u64);
+
+        let delay = this.user.traffic().charge_tcp(len);
+        Self::set_delay(&mut this.read_delay, delay);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        let this = &mut *self;
+
+        ready!(Self::poll_delay(&mut this.write_delay, cx));
+        this.check_quota()?;
+
+        let len = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.uploaded.inc(len as u64);
+
+        let delay = this.user.traffic().charge_tcp(len);
+        Self::set_delay(&mut this.write_delay, delay);
+
+        Poll::Ready(Ok(len))
+    }
+
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum TaskError {
     #[error(transparent)]
@@ -194,4 +395,69 @@ pub enum TaskError {
     WriteStream(#[from] WriteError),
     #[error(transparent)]
     SendDatagram(#[from] SendDatagramError),
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error("rejected by routing rules")]
+    Rejected,
+}
+
+impl TaskError {
+    /// Tells the client why connecting to the destination failed
+    fn reply(&self) -> Reply {
+        match self {
+            Self::Rejected => Reply::Blocked,
+            Self::Io(err) => match err.kind() {
+                ErrorKind::NotFound => Reply::DnsFailure,
+                ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+                ErrorKind::TimedOut => Reply::TimedOut,
+                ErrorKind::PermissionDenied => Reply::Blocked,
+                _ if is_unreachable(err) => Reply::HostUnreachable,
+                _ => Reply::Failed,
+            },
+            _ => Reply::Failed,
+        }
+    }
+}
+
+/// `ErrorKind::HostUnreachable` and `ErrorKind::NetworkUnreachable` are not stable yet
+fn is_unreachable(err: &IoError) -> bool {
+    if err.get_ref().map_or(false, |err| err.is::<Unreachable>()) {
+        return true;
+    }
+
+    #[cfg(unix)]
+    let codes = [libc::EHOSTUNREACH, libc::ENETUNREACH];
+
+    // WSAEHOSTUNREACH and WSAENETUNREACH
+    #[cfg(windows)]
+    let codes = [10065, 10051];
+
+    err.raw_os_error()
+        .map_or(false, |code| codes.contains(&code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_errors_map_to_replies() {
+        let reply = |err| TaskError::Io(err).reply();
+
+        assert_eq!(
+            reply(IoError::new(
+                ErrorKind::Other,
+                Unreachable(String::from("host unreachable"))
+            )),
+            Reply::HostUnreachable
+        );
+        assert_eq!(reply(IoError::from(ErrorKind::TimedOut)), Reply::TimedOut);
+        assert_eq!(
+            reply(IoError::from(ErrorKind::ConnectionRefused)),
+            Reply::ConnectionRefused
+        );
+        assert_eq!(reply(IoError::from(ErrorKind::NotFound)), Reply::DnsFailure);
+        assert_eq!(reply(IoError::from(ErrorKind::Other)), Reply::Failed);
+        assert_eq!(TaskError::Rejected.reply(), Reply::Blocked);
+    }
 }
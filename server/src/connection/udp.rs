@@ -1,18 +1,33 @@
+use crate::{
+    metrics::{Counter, Metrics},
+    outbound::{Outbound, OutboundUdpSocket},
+    router::{Route, Router},
+    user::User,
+};
 use bytes::Bytes;
 use crossbeam_utils::atomic::AtomicCell;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 
 use std::{
     collections::HashMap,
-    io::Result,
-    net::{Ipv6Addr, SocketAddr},
-    sync::Arc,
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 use tokio::{
-    net::UdpSocket,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    task::JoinHandle,
+    time::{self, Instant},
 };
-use tuic_protocol::Address;
+use tracing::{Instrument, Span};
+use tuic_protocol::{v5, Address};
 
 #[derive(Clone)]
 pub struct UdpPacketFrom(Arc<AtomicCell<Option<UdpPacketSource>>>);
@@ -45,134 +60,555 @@ pub enum UdpPacketSource {
     Datagram,
 }
 
-pub type SendPacketSender = Sender<(Bytes, Address)>;
-pub type SendPacketReceiver = Receiver<(Bytes, Address)>;
+pub type SendPacketSender = Sender<(Bytes, Address, Arc<dyn Outbound>)>;
+pub type SendPacketReceiver = Receiver<(Bytes, Address, Arc<dyn Outbound>)>;
 pub type RecvPacketSender = Sender<(u32, Bytes, Address)>;
 pub type RecvPacketReceiver = Receiver<(u32, Bytes, Address)>;
 
+/// The header of a TUIC v5 UDP packet fragment
+pub struct Fragment {
+    pub assoc_id: u16,
+    pub pkt_id: u16,
+    pub frag_total: u8,
+    pub frag_id: u8,
+    pub addr: v5::Address,
+}
+
+const MAX_PARTIAL_PACKETS: usize = 256;
+const MAX_PARTIAL_PACKET_BYTES: usize = 1024 * 1024;
+const PARTIAL_PACKET_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_DOMAINS_PER_SESSION: usize = 256;
+
+/// Settings of the UDP relay, shared by all connections
+#[derive(Clone)]
+pub struct UdpRelayConfig {
+    pub max_pkt_size: usize,
+    pub session_idle_timeout: Duration,
+    pub max_sessions_per_connection: usize,
+    /// Limits the number of sockets bound by all UDP sessions of the server
+    pub socket_budget: Arc<Semaphore>,
+}
+
 pub struct UdpSessionMap {
     map: Mutex<HashMap<u32, UdpSession>>,
+    reassembler: Mutex<v5::Reassembler>,
+    next_pkt_id: AtomicU16,
     recv_pkt_tx_for_clone: RecvPacketSender,
-    max_pkt_size: usize,
+    router: Arc<Router>,
+    config: UdpRelayConfig,
+    metrics: Arc<Metrics>,
+    /// The span of the connection, the parent of the spans of the sessions
+    span: Span,
 }
 
 impl UdpSessionMap {
-    pub fn new(max_pkt_size: usize) -> (Self, RecvPacketReceiver) {
+    pub fn new(
+        router: Arc<Router>,
+        config: UdpRelayConfig,
+        metrics: Arc<Metrics>,
+    ) -> (Self, RecvPacketReceiver) {
         let (recv_pkt_tx, recv_pkt_rx) = mpsc::channel(1);
 
         (
             Self {
                 map: Mutex::new(HashMap::new()),
+                reassembler: Mutex::new(v5::Reassembler::new(
+                    MAX_PARTIAL_PACKETS,
+                    config.max_pkt_size,
+                    MAX_PARTIAL_PACKET_BYTES.max(config.max_pkt_size),
+                    PARTIAL_PACKET_TIMEOUT,
+                )),
+                next_pkt_id: AtomicU16::new(0),
                 recv_pkt_tx_for_clone: recv_pkt_tx,
-                max_pkt_size,
+                router,
+                config,
+                metrics,
+                span: Span::current(),
             },
             recv_pkt_rx,
         )
     }
 
-    #[allow(clippy::await_holding_lock)]
     pub async fn send(
         &self,
         assoc_id: u32,
         pkt: Bytes,
         addr: Address,
         src_addr: SocketAddr,
+        user: Arc<User>,
     ) -> Result<()> {
-        let map = self.map.lock();
+        let outbound = match self.router.route(&addr, &user) {
+            Route::Outbound(outbound) => outbound.clone(),
+            Route::Reject => {
+                log::debug!(
+                    "[{src_addr}] [{user}] [packet] [{assoc_id}] [{addr}] rejected by routing rules"
+                );
+                return Ok(());
+            }
+        };
 
-        let send_pkt_tx = if let Some(session) = map.get(&assoc_id) {
-            let send_pkt_tx = session.0.clone();
-            drop(map);
-            send_pkt_tx
-        } else {
-            log::info!("[{src_addr}] [associate] [{assoc_id}]");
-            drop(map);
+        let send_pkt_tx = {
+            let mut map = self.map.lock();
 
-            let assoc = UdpSession::new(
-                assoc_id,
-                self.recv_pkt_tx_for_clone.clone(),
-                src_addr,
-                self.max_pkt_size,
-            )
-            .await?;
+            match map.get(&assoc_id) {
+                Some(session) if !session.0.is_closed() => session.0.clone(),
+                _ => {
+                    // sessions closed by idle expiry or errors no longer count
+                    map.retain(|_, session| !session.0.is_closed());
 
-            let send_pkt_tx = assoc.0.clone();
+                    if map.len() >= self.config.max_sessions_per_connection {
+                        log::warn!(
+                            "[{src_addr}] [{user}] [associate] [{assoc_id}] too many UDP sessions"
+                        );
+                        return Ok(());
+                    }
 
-            let mut map = self.map.lock();
-            map.insert(assoc_id, assoc);
+                    log::info!("[{src_addr}] [{user}] [associate] [{assoc_id}]");
+
+                    let span = tracing::info_span!(parent: &self.span, "udp_session", assoc_id);
+
+                    let session = UdpSession::new(
+                        assoc_id,
+                        self.recv_pkt_tx_for_clone.clone(),
+                        src_addr,
+                        user,
+                        self.config.clone(),
+                        self.metrics.clone(),
+                        span,
+                    );
 
-            send_pkt_tx
+                    let send_pkt_tx = session.0.clone();
+                    map.insert(assoc_id, session);
+                    send_pkt_tx
+                }
+            }
         };
 
-        let _ = send_pkt_tx.send((pkt, addr)).await;
+        let _ = send_pkt_tx.send((pkt, addr, outbound)).await;
+
+        Ok(())
+    }
+
+    /// Feeds a TUIC v5 packet fragment, sending the packet once it is fully reassembled
+    pub async fn send_fragment(
+        &self,
+        frag: Fragment,
+        payload: Bytes,
+        src_addr: SocketAddr,
+        user: Arc<User>,
+    ) -> Result<()> {
+        let pkt = self.reassembler.lock().insert(
+            frag.assoc_id,
+            frag.pkt_id,
+            frag.frag_total,
+            frag.frag_id,
+            frag.addr,
+            payload,
+        )?;
+
+        if let Some((addr, pkt)) = pkt {
+            self.send(frag.assoc_id as u32, pkt, addr, src_addr, user)
+                .await?;
+        }
 
         Ok(())
     }
 
-    pub fn dissociate(&self, assoc_id: u32, src_addr: SocketAddr) {
-        log::info!("[{src_addr}] [dissociate] [{assoc_id}]");
+    /// The maximum size of a UDP packet relayed for the client
+    pub fn max_pkt_size(&self) -> usize {
+        self.config.max_pkt_size
+    }
+
+    /// Returns the packet ID for the next TUIC v5 packet sent to the client
+    pub fn next_pkt_id(&self) -> u16 {
+        self.next_pkt_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn dissociate(&self, assoc_id: u32, src_addr: SocketAddr, user: &User) {
+        log::info!("[{src_addr}] [{user}] [dissociate] [{assoc_id}]");
         self.map.lock().remove(&assoc_id);
+
+        if let Ok(assoc_id) = u16::try_from(assoc_id) {
+            self.reassembler.lock().remove(assoc_id);
+        }
     }
 }
 
 struct UdpSession(SendPacketSender);
 
 impl UdpSession {
-    async fn new(
+    fn new(
         assoc_id: u32,
         recv_pkt_tx: RecvPacketSender,
         src_addr: SocketAddr,
-        max_pkt_size: usize,
-    ) -> Result<Self> {
-        let socket = Arc::new(UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))).await?);
+        user: Arc<User>,
+        config: UdpRelayConfig,
+        metrics: Arc<Metrics>,
+        span: Span,
+    ) -> Self {
         let (send_pkt_tx, send_pkt_rx) = mpsc::channel(1);
 
-        tokio::spawn(async move {
-            match tokio::select! {
-                res = Self::listen_send_packet(socket.clone(), send_pkt_rx) => res,
-                res = Self::listen_receive_packet(socket, assoc_id, recv_pkt_tx, max_pkt_size) => res,
-            } {
+        let task = async move {
+            let _active = metrics.udp_session(&user);
+
+            match Self::listen_send_packet(
+                send_pkt_rx,
+                assoc_id,
+                recv_pkt_tx,
+                src_addr,
+                user.clone(),
+                config,
+                &metrics,
+            )
+            .await
+            {
                 Ok(()) => (),
-                Err(err) => log::warn!("[{src_addr}] [udp-session] [{assoc_id}] {err}"),
+                Err(err) => log::warn!("[{src_addr}] [{user}] [udp-session] [{assoc_id}] {err}"),
             }
-        });
+        };
+
+        tokio::spawn(task.instrument(span));
 
-        Ok(Self(send_pkt_tx))
+        Self(send_pkt_tx)
     }
 
+    /// Binds a socket through each outbound on its first packet. An error of any socket ends the session
     async fn listen_send_packet(
-        socket: Arc<UdpSocket>,
         mut send_pkt_rx: SendPacketReceiver,
+        assoc_id: u32,
+        recv_pkt_tx: RecvPacketSender,
+        src_addr: SocketAddr,
+        user: Arc<User>,
+        config: UdpRelayConfig,
+        metrics: &Metrics,
     ) -> Result<()> {
-        while let Some((pkt, addr)) = send_pkt_rx.recv().await {
-            match addr {
-                Address::DomainAddress(hostname, port) => {
-                    socket.send_to(&pkt, (hostname, port)).await?;
+        let mut sockets: Vec<BoundSocket> = Vec::new();
+        let (err_tx, mut err_rx) = mpsc::channel(1);
+        let stats = Arc::new(SessionStats::new(&user, metrics));
+        let domains = Arc::new(RwLock::new(DomainMap::new(
+            MAX_DOMAINS_PER_SESSION,
+            config.session_idle_timeout,
+        )));
+
+        loop {
+            let (pkt, addr, outbound) = tokio::select! {
+                pkt = send_pkt_rx.recv() => match pkt {
+                    Some(pkt) => pkt,
+                    None => return Ok(()),
+                },
+                Some(err) = err_rx.recv() => return Err(err),
+                () = time::sleep_until(stats.last_active() + config.session_idle_timeout) => {
+                    if stats.last_active().elapsed() < config.session_idle_timeout {
+                        continue;
+                    }
+
+                    let bytes_sent = stats.bytes_sent.load(Ordering::Relaxed);
+                    let bytes_received = stats.bytes_received.load(Ordering::Relaxed);
+                    let lifetime = stats.created.elapsed();
+                    let duration_ms = lifetime.as_millis() as u64;
+
+                    tracing::info!(
+                        bytes_sent, bytes_received, duration_ms,
+                        "[{src_addr}] [{user}] [expire] [{assoc_id}] sent: {bytes_sent} bytes, received: {bytes_received} bytes, lifetime: {lifetime:?}"
+                    );
+
+                    return Ok(());
                 }
-                Address::SocketAddress(addr) => {
-                    socket.send_to(&pkt, addr).await?;
+            };
+
+            Self::charge(&user, pkt.len()).await?;
+
+            let socket = match sockets.iter().find(|socket| socket.is_bound_by(&outbound)) {
+                Some(socket) => socket.socket.clone(),
+                None => {
+                    let permit = match config.socket_budget.clone().try_acquire_owned() {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            log::warn!("[{src_addr}] [{user}] [packet] [{assoc_id}] [{addr}] UDP socket budget exhausted");
+                            continue;
+                        }
+                    };
+
+                    let socket: Arc<dyn OutboundUdpSocket> =
+                        Arc::from(outbound.bind_udp(&user).await?);
+
+                    let task = tokio::spawn({
+                        let socket = socket.clone();
+                        let recv_pkt_tx = recv_pkt_tx.clone();
+                        let user = user.clone();
+                        let stats = stats.clone();
+                        let domains = domains.clone();
+                        let err_tx = err_tx.clone();
+                        let max_pkt_size = config.max_pkt_size;
+
+                        let task = async move {
+                            if let Err(err) = Self::listen_receive_packet(
+                                socket,
+                                assoc_id,
+                                recv_pkt_tx,
+                                max_pkt_size,
+                                &user,
+                                &stats,
+                                &domains,
+                            )
+                            .await
+                            {
+                                let _ = err_tx.send(err).await;
+                            }
+                        };
+
+                        task.in_current_span()
+                    });
+
+                    sockets.push(BoundSocket {
+                        outbound,
+                        socket: socket.clone(),
+                        task,
+                        _permit: permit,
+                    });
+
+                    socket
                 }
+            };
+
+            match socket.send_to(&pkt, &addr).await {
+                Ok(sent_to) => {
+                    stats.record_sent(pkt.len());
+
+                    // replies from the resolved address are reported as from the domain
+                    if let (Address::DomainAddress(..), Address::SocketAddress(resolved)) =
+                        (&addr, sent_to)
+                    {
+                        if domains
+                            .write()
+                            .insert(resolved, addr.clone(), Instant::now())
+                        {
+                            log::debug!("[{src_addr}] [{user}] [packet] [{assoc_id}] [{addr}] resolved to {resolved}");
+                        }
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                    log::warn!("[{src_addr}] [{user}] [packet] [{assoc_id}] [{addr}] {err}")
+                }
+                Err(err) => return Err(err),
             }
         }
-
-        Ok(())
     }
 
     async fn listen_receive_packet(
-        socket: Arc<UdpSocket>,
+        socket: Arc<dyn OutboundUdpSocket>,
         assoc_id: u32,
         recv_pkt_tx: RecvPacketSender,
         max_pkt_size: usize,
+        user: &User,
+        stats: &SessionStats,
+        domains: &RwLock<DomainMap>,
     ) -> Result<()> {
         loop {
             let mut buf = vec![0; max_pkt_size];
             let (len, addr) = socket.recv_from(&mut buf).await?;
             buf.truncate(len);
 
+            let addr = match addr {
+                Address::SocketAddress(addr) => domains
+                    .read()
+                    .get(&addr, Instant::now())
+                    .unwrap_or(Address::SocketAddress(addr)),
+                addr => addr,
+            };
+
+            Self::charge(user, len).await?;
+            stats.record_received(len);
+
             let pkt = Bytes::from(buf);
-            let _ = recv_pkt_tx
-                .send((assoc_id, pkt, Address::SocketAddress(addr)))
-                .await;
+            let _ = recv_pkt_tx.send((assoc_id, pkt, addr)).await;
+        }
+    }
+
+    /// Charges a packet to the user, pausing while the rate limit is exceeded
+    async fn charge(user: &User, len: usize) -> Result<()> {
+        if user.traffic().is_quota_exhausted() {
+            return Err(Error::new(ErrorKind::Other, "traffic quota exhausted"));
+        }
+
+        let delay = user.traffic().charge_udp(len);
+
+        if !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+
+        Ok(())
+    }
+}
+
+/// A socket of a UDP session bound through an outbound. Its receiving task is aborted on drop
+struct BoundSocket {
+    outbound: Arc<dyn Outbound>,
+    socket: Arc<dyn OutboundUdpSocket>,
+    task: JoinHandle<()>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl BoundSocket {
+    fn is_bound_by(&self, outbound: &Arc<dyn Outbound>) -> bool {
+        Arc::as_ptr(&self.outbound) as *const () == Arc::as_ptr(outbound) as *const ()
+    }
+}
+
+impl Drop for BoundSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The domains a UDP session sent to by their resolved addresses, so that replies are reported as from the domains
+///
+/// An entry expires once the domain has not been sent to for `ttl`. When full, the domain sent to least recently is dropped.
+struct DomainMap {
+    domains: HashMap<SocketAddr, (Address, Instant)>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl DomainMap {
+    fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            domains: HashMap::new(),
+            capacity,
+            ttl,
+        }
+    }
+
+    fn get(&self, resolved: &SocketAddr, now: Instant) -> Option<Address> {
+        self.domains
+            .get(resolved)
+            .filter(|(_, sent)| now.saturating_duration_since(*sent) < self.ttl)
+            .map(|(addr, _)| addr.clone())
+    }
+
+    /// Records a packet sent to the domain, returning whether the domain was not mapped to the address yet
+    fn insert(&mut self, resolved: SocketAddr, addr: Address, now: Instant) -> bool {
+        if let Some(entry) = self.domains.get_mut(&resolved) {
+            let is_new = entry.0 != addr || now.saturating_duration_since(entry.1) >= self.ttl;
+            *entry = (addr, now);
+            return is_new;
         }
+
+        if self.domains.len() >= self.capacity {
+            let ttl = self.ttl;
+            self.domains
+                .retain(|_, (_, sent)| now.saturating_duration_since(*sent) < ttl);
+        }
+
+        if self.domains.len() >= self.capacity {
+            let oldest = self
+                .domains
+                .iter()
+                .min_by_key(|(_, (_, sent))| *sent)
+                .map(|(resolved, _)| *resolved);
+
+            if let Some(oldest) = oldest {
+                self.domains.remove(&oldest);
+            }
+        }
+
+        self.domains.insert(resolved, (addr, now));
+        true
+    }
+}
+
+/// Traffic of a UDP session over its lifetime, also counted in the metrics of its user
+struct SessionStats {
+    created: Instant,
+    last_active: AtomicCell<Instant>,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    uploaded: Arc<Counter>,
+    downloaded: Arc<Counter>,
+}
+
+impl SessionStats {
+    fn new(user: &User, metrics: &Metrics) -> Self {
+        let now = Instant::now();
+
+        Self {
+            created: now,
+            last_active: AtomicCell::new(now),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            uploaded: metrics.bytes(user, "udp", "upload"),
+            downloaded: metrics.bytes(user, "udp", "download"),
+        }
+    }
+
+    fn last_active(&self) -> Instant {
+        self.last_active.load()
+    }
+
+    fn record_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        self.uploaded.inc(len as u64);
+        self.last_active.store(Instant::now());
+    }
+
+    fn record_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        self.downloaded.inc(len as u64);
+        self.last_active.store(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn domain(name: &str) -> Address {
+        Address::DomainAddress(name.to_owned(), 53)
+    }
+
+    #[test]
+    fn domain_map_evicts_least_recently_sent() {
+        let mut domains = DomainMap::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let a = SocketAddr::from(([192, 0, 2, 1], 53));
+        let b = SocketAddr::from(([192, 0, 2, 2], 53));
+        let c = SocketAddr::from(([192, 0, 2, 3], 53));
+
+        assert!(domains.insert(a, domain("a.example"), start));
+        assert!(domains.insert(b, domain("b.example"), start + Duration::from_secs(1)));
+        assert!(!domains.insert(a, domain("a.example"), start + Duration::from_secs(2)));
+
+        // b was sent to least recently
+        assert!(domains.insert(c, domain("c.example"), start + Duration::from_secs(3)));
+
+        let now = start + Duration::from_secs(4);
+        assert_eq!(domains.get(&a, now), Some(domain("a.example")));
+        assert_eq!(domains.get(&b, now), None);
+        assert_eq!(domains.get(&c, now), Some(domain("c.example")));
+        assert_eq!(domains.domains.len(), 2);
+    }
+
+    #[test]
+    fn domain_map_expires_entries() {
+        let mut domains = DomainMap::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        let a = SocketAddr::from(([192, 0, 2, 1], 53));
+        let b = SocketAddr::from(([192, 0, 2, 2], 53));
+        let c = SocketAddr::from(([192, 0, 2, 3], 53));
+
+        domains.insert(a, domain("a.example"), start);
+        domains.insert(b, domain("b.example"), start + Duration::from_secs(30));
+
+        let now = start + Duration::from_secs(60);
+        assert_eq!(domains.get(&a, now), None);
+        assert_eq!(domains.get(&b, now), Some(domain("b.example")));
+
+        // the expired entry makes room, and b is kept
+        domains.insert(c, domain("c.example"), now);
+        assert!(!domains.domains.contains_key(&a));
+        assert_eq!(domains.get(&b, now), Some(domain("b.example")));
+
+        // sending again after expiry maps the domain anew
+        assert!(domains.insert(b, domain("b.example"), start + Duration::from_secs(100)));
     }
 }
@@ -0,0 +1,29 @@
+use log::LevelFilter;
+use tracing_log::AsTrace;
+
+/// How log lines are written to stderr
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    /// `{timestamp} {level} {spans}: [{remote address}] [{user}] [{method}] ...`
+    Text,
+    /// One JSON object per line, with the fields of the spans the line was logged in
+    Json,
+}
+
+pub fn init(level: LevelFilter, format: LogFormat) {
+    // log records are turned into tracing events, so that they are written with the spans they were logged in
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level.as_trace())
+        .with_target(false)
+        .with_writer(std::io::stderr);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .init(),
+    }
+}
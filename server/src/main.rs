@@ -2,13 +2,27 @@ use crate::{
     config::{Config, ConfigError},
     server::Server,
 };
-use std::{env, process};
+use std::env;
 use mimalloc::MiMalloc;
 
+mod acl;
+mod acme;
+mod camouflage;
 mod certificate;
+mod cidr;
 mod config;
 mod connection;
+mod logging;
+mod metrics;
+mod outbound;
+mod reload;
+mod resolver;
+mod router;
 mod server;
+mod shutdown;
+mod static_site;
+mod traffic;
+mod user;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -28,12 +42,7 @@ fn main() {
         }
     };
 
-    env_logger::builder()
-        .filter_level(config.log_level)
-        .format_level(true)
-        .format_target(false)
-        .format_module_path(false)
-        .init();
+    logging::init(config.log_level, config.log_format);
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -45,10 +54,20 @@ fn main() {
 async fn run(config: Config) {
     let server = match Server::init(
         config.server_config,
-        config.listen_addr,
-        config.token,
+        config.listeners,
+        config.users,
+        config.reloader,
+        config.watched_files,
+        config.acme,
+        config.camouflage,
+        config.camouflage_alpn,
+        config.router,
         config.authentication_timeout,
-        config.max_udp_relay_packet_size,
+        config.require_session_bound_auth,
+        config.udp_relay_config,
+        config.traffic_accounting_file,
+        config.drain_timeout,
+        config.metrics,
     ) {
         Ok(server) => server,
         Err(err) => {
@@ -58,5 +77,4 @@ async fn run(config: Config) {
     };
 
     server.run().await;
-    process::exit(1);
 }
@@ -0,0 +1,460 @@
+use crate::user::User;
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+const MAX_HTTP_REQUEST_HEAD_SIZE: usize = 8192;
+const SCRAPE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bounds of the connect latency buckets, in seconds
+const CONNECT_DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Counters, gauges and histograms of the server, exposed in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    connections: Arc<Gauge>,
+    connections_total: Counter,
+    authentications: Family<Counter>,
+    tcp_connects: Family<Counter>,
+    tcp_connect_duration: Family<Histogram>,
+    tcp_tunnels: Family<Gauge>,
+    udp_sessions: Family<Gauge>,
+    udp_sessions_total: Family<Counter>,
+    bytes: Family<Counter>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts the connection as active until the guard is dropped
+    pub fn connection(&self) -> Active {
+        self.connections_total.inc(1);
+        Active::new(self.connections.clone())
+    }
+
+    /// The user is only known if the credentials matched one
+    pub fn authentication(&self, user: Option<&User>, outcome: &'static str) {
+        let labels = match user {
+            Some(user) => vec![("user", user.name()), ("outcome", outcome)],
+            None => vec![("outcome", outcome)],
+        };
+
+        self.authentications.get(&labels).inc(1);
+    }
+
+    /// Records an attempt to connect to a TCP destination, and how long the outbound took
+    pub fn tcp_connect(&self, user: &User, outcome: &'static str, elapsed: Duration) {
+        let labels = [("user", user.name()), ("outcome", outcome)];
+        self.tcp_connects.get(&labels).inc(1);
+        self.tcp_connect_duration.get(&labels).observe(elapsed);
+    }
+
+    pub fn tcp_tunnel(&self, user: &User) -> Active {
+        Active::new(self.tcp_tunnels.get(&[("user", user.name())]))
+    }
+
+    pub fn udp_session(&self, user: &User) -> Active {
+        let labels = [("user", user.name())];
+        self.udp_sessions_total.get(&labels).inc(1);
+        Active::new(self.udp_sessions.get(&labels))
+    }
+
+    /// The counter of the relayed bytes of a user. `protocol` is `tcp` or `udp`, `direction` is `upload` or `download`
+    pub fn bytes(
+        &self,
+        user: &User,
+        protocol: &'static str,
+        direction: &'static str,
+    ) -> Arc<Counter> {
+        self.bytes.get(&[
+            ("user", user.name()),
+            ("protocol", protocol),
+            ("direction", direction),
+        ])
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "tuic_connections_active",
+            "gauge",
+            "QUIC connections currently open",
+        );
+        let _ = writeln!(out, "tuic_connections_active {}", self.connections.get());
+
+        write_header(
+            &mut out,
+            "tuic_connections_total",
+            "counter",
+            "QUIC connections accepted",
+        );
+        let _ = writeln!(
+            out,
+            "tuic_connections_total {}",
+            self.connections_total.get()
+        );
+
+        self.authentications.render(
+            &mut out,
+            "tuic_authentications_total",
+            "counter",
+            "Authentication attempts by outcome",
+        );
+        self.tcp_connects.render(
+            &mut out,
+            "tuic_tcp_connects_total",
+            "counter",
+            "Attempts to connect to TCP destinations by outcome",
+        );
+        self.tcp_connect_duration.render(
+            &mut out,
+            "tuic_tcp_connect_duration_seconds",
+            "histogram",
+            "Time taken to connect to TCP destinations",
+        );
+        self.tcp_tunnels.render(
+            &mut out,
+            "tuic_tcp_tunnels_active",
+            "gauge",
+            "TCP connections currently relayed",
+        );
+        self.udp_sessions.render(
+            &mut out,
+            "tuic_udp_sessions_active",
+            "gauge",
+            "UDP sessions currently open",
+        );
+        self.udp_sessions_total.render(
+            &mut out,
+            "tuic_udp_sessions_total",
+            "counter",
+            "UDP sessions opened",
+        );
+        self.bytes.render(
+            &mut out,
+            "tuic_bytes_total",
+            "counter",
+            "Bytes relayed by protocol and direction",
+        );
+
+        out
+    }
+}
+
+/// Serves `GET /metrics` over HTTP/1.1. Returns only if accepting fails
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> IoError {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => return err,
+        };
+
+        let metrics = metrics.clone();
+
+        tokio::spawn(async move {
+            match time::timeout(SCRAPE_CONNECTION_TIMEOUT, respond(stream, &metrics)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => log::debug!("[metrics] Request from {addr}: {err}"),
+                Err(_) => log::debug!("[metrics] Request from {addr} timed out"),
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> IoResult<()> {
+    let mut buf = vec![0; MAX_HTTP_REQUEST_HEAD_SIZE];
+    let mut len = 0;
+
+    while !buf[..len].windows(4).any(|window| window == b"\r\n\r\n") {
+        if len == buf.len() {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                "request head too long",
+            ));
+        }
+
+        match stream.read(&mut buf[len..]).await? {
+            0 => return Err(IoError::from(ErrorKind::UnexpectedEof)),
+            n => len += n,
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = head.split_whitespace();
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let resp = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Formats the labels as `{name="value",...}`, with `extra` appended
+fn format_labels(labels: &[(&'static str, String)], extra: Option<(&str, &str)>) -> String {
+    let mut out = String::new();
+
+    for (name, value) in labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(extra)
+    {
+        let value = value
+            .replace('\\', r"\\")
+            .replace('"', "\\\"")
+            .replace('\n', r"\n");
+
+        let sep = if out.is_empty() { '{' } else { ',' };
+        let _ = write!(out, "{sep}{name}=\"{value}\"");
+    }
+
+    if !out.is_empty() {
+        out.push('}');
+    }
+
+    out
+}
+
+/// The series of a metric, one for each set of labels seen
+struct Family<T>(Mutex<BTreeMap<Labels, Arc<T>>>);
+
+impl<T: Default + Sample> Family<T> {
+    fn get(&self, labels: &[(&'static str, &str)]) -> Arc<T> {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| (*name, (*value).to_owned()))
+            .collect();
+
+        self.0.lock().entry(labels).or_default().clone()
+    }
+
+    fn render(&self, out: &mut String, name: &str, kind: &str, help: &str) {
+        write_header(out, name, kind, help);
+
+        for (labels, series) in self.0.lock().iter() {
+            series.render(out, name, labels);
+        }
+    }
+}
+
+impl<T> Default for Family<T> {
+    fn default() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+}
+
+trait Sample {
+    fn render(&self, out: &mut String, name: &str, labels: &[(&'static str, String)]);
+}
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Sample for Counter {
+    fn render(&self, out: &mut String, name: &str, labels: &[(&'static str, String)]) {
+        let _ = writeln!(out, "{name}{} {}", format_labels(labels, None), self.get());
+    }
+}
+
+#[derive(Default)]
+struct Gauge(AtomicI64);
+
+impl Gauge {
+    fn add(&self, n: i64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Sample for Gauge {
+    fn render(&self, out: &mut String, name: &str, labels: &[(&'static str, String)]) {
+        let _ = writeln!(out, "{name}{} {}", format_labels(labels, None), self.get());
+    }
+}
+
+/// Keeps a gauge incremented while alive
+pub struct Active(Arc<Gauge>);
+
+impl Active {
+    fn new(gauge: Arc<Gauge>) -> Self {
+        gauge.add(1);
+        Self(gauge)
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.0.add(-1);
+    }
+}
+
+struct Histogram {
+    /// Observations in each bucket, the last one being `+Inf`
+    buckets: [AtomicU64; CONNECT_DURATION_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let idx = CONNECT_DURATION_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(CONNECT_DURATION_BUCKETS.len());
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: Default::default(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Sample for Histogram {
+    fn render(&self, out: &mut String, name: &str, labels: &[(&'static str, String)]) {
+        let mut count = 0;
+
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+
+            let bound = CONNECT_DURATION_BUCKETS
+                .get(idx)
+                .map_or_else(|| String::from("+Inf"), f64::to_string);
+
+            let labels = format_labels(labels, Some(("le", &bound)));
+            let _ = writeln!(out, "{name}_bucket{labels} {count}");
+        }
+
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let labels = format_labels(labels, None);
+        let _ = writeln!(out, "{name}_sum{labels} {sum}");
+        let _ = writeln!(out, "{name}_count{labels} {count}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::traffic::Traffic;
+
+    fn user(name: &str) -> User {
+        User::new(name.to_owned(), Traffic::default())
+    }
+
+    #[test]
+    fn renders_labelled_counter() {
+        let metrics = Metrics::new();
+        metrics.bytes(&user("alice"), "tcp", "upload").inc(42);
+
+        let out = metrics.render();
+        assert!(out.contains("# TYPE tuic_bytes_total counter\n"));
+        assert!(out.contains(
+            "tuic_bytes_total{user=\"alice\",protocol=\"tcp\",direction=\"upload\"} 42\n"
+        ));
+    }
+
+    #[test]
+    fn renders_gauge_while_active() {
+        let metrics = Metrics::new();
+        let alice = user("alice");
+
+        let tunnel = metrics.tcp_tunnel(&alice);
+        let other = metrics.tcp_tunnel(&alice);
+        assert!(metrics
+            .render()
+            .contains("tuic_tcp_tunnels_active{user=\"alice\"} 2\n"));
+
+        drop(tunnel);
+        assert!(metrics
+            .render()
+            .contains("tuic_tcp_tunnels_active{user=\"alice\"} 1\n"));
+
+        drop(other);
+        assert!(metrics
+            .render()
+            .contains("tuic_tcp_tunnels_active{user=\"alice\"} 0\n"));
+    }
+
+    #[test]
+    fn renders_histogram() {
+        let metrics = Metrics::new();
+        let alice = user("alice");
+        metrics.tcp_connect(&alice, "success", Duration::from_millis(20));
+        metrics.tcp_connect(&alice, "success", Duration::from_secs(30));
+
+        let out = metrics.render();
+        let series = "tuic_tcp_connect_duration_seconds";
+        let labels = "user=\"alice\",outcome=\"success\"";
+
+        assert!(out.contains(&format!("# TYPE {series} histogram\n")));
+        assert!(out.contains(&format!("{series}_bucket{{{labels},le=\"0.01\"}} 0\n")));
+        assert!(out.contains(&format!("{series}_bucket{{{labels},le=\"0.025\"}} 1\n")));
+        assert!(out.contains(&format!("{series}_bucket{{{labels},le=\"10\"}} 1\n")));
+        assert!(out.contains(&format!("{series}_bucket{{{labels},le=\"+Inf\"}} 2\n")));
+        assert!(out.contains(&format!("{series}_sum{{{labels}}} 30.02\n")));
+        assert!(out.contains(&format!("{series}_count{{{labels}}} 2\n")));
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        let metrics = Metrics::new();
+        metrics.authentication(Some(&user("a\"b\\c\nd")), "success");
+
+        let out = metrics.render();
+        assert!(out.contains(
+            "tuic_authentications_total{user=\"a\\\"b\\\\c\\nd\",outcome=\"success\"} 1\n"
+        ));
+    }
+}
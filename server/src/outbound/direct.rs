@@ -0,0 +1,437 @@
+use super::{BoxFuture, Outbound, OutboundUdpSocket};
+use crate::{acl::Acl, resolver::Resolver, user::User};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tuic_protocol::Address;
+
+/// How long a pending connection attempt has before the next address is raced against it (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long a UDP socket lets replies through from a peer after last sending to it (RFC 4787 REQ-5)
+const NAT_FILTER_TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_NAT_FILTER_PEERS: usize = 1024;
+
+/// Which packets from the internet a UDP session lets through to the client
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum NatFiltering {
+    /// From any address
+    EndpointIndependent,
+    /// From IPs the session has sent packets to
+    AddressDependent,
+    /// From IP and port pairs the session has sent packets to
+    AddressAndPortDependent,
+}
+
+/// Connects to destinations from the server itself, as long as the ACL allows them
+pub struct Direct {
+    acl: Arc<Acl>,
+    resolver: Arc<Resolver>,
+    /// Covers resolving and all connection attempts
+    connect_timeout: Duration,
+    connect_attempt_timeout: Duration,
+    nat_filtering: NatFiltering,
+    /// The external UDP port of each user, if kept across sessions
+    sticky_ports: Option<Mutex<HashMap<String, u16>>>,
+}
+
+impl Direct {
+    pub fn new(
+        acl: Acl,
+        resolver: Arc<Resolver>,
+        connect_timeout: Duration,
+        connect_attempt_timeout: Duration,
+        nat_filtering: NatFiltering,
+        sticky_port: bool,
+    ) -> Self {
+        Self {
+            acl: Arc::new(acl),
+            resolver,
+            connect_timeout,
+            connect_attempt_timeout,
+            nat_filtering,
+            sticky_ports: sticky_port.then(|| Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Binds the port the user had before if possible
+    async fn bind_sticky(&self, user: &User) -> Result<UdpSocket> {
+        let unspecified = SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0));
+
+        let sticky_ports = match &self.sticky_ports {
+            Some(sticky_ports) => sticky_ports,
+            None => return UdpSocket::bind(unspecified).await,
+        };
+
+        let port = sticky_ports.lock().get(user.name()).copied();
+
+        if let Some(port) = port {
+            if let Ok(socket) =
+                UdpSocket::bind(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))).await
+            {
+                return Ok(socket);
+            }
+        }
+
+        let socket = UdpSocket::bind(unspecified).await?;
+        let port = socket.local_addr()?.port();
+
+        sticky_ports
+            .lock()
+            .entry(user.name().to_owned())
+            .or_insert(port);
+
+        Ok(socket)
+    }
+}
+
+impl Outbound for Direct {
+    fn connect_tcp(&self, addr: Address) -> BoxFuture<'_, Result<TcpStream>> {
+        Box::pin(async move {
+            let connect = async {
+                let addrs = resolve(&addr, &self.acl, &self.resolver).await?;
+                let stream = happy_eyeballs(addrs, self.connect_attempt_timeout).await?;
+                let _ = stream.set_nodelay(true);
+                Ok(stream)
+            };
+
+            match time::timeout(self.connect_timeout, connect).await {
+                Ok(res) => res,
+                Err(_) => Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("connecting to {addr} timed out"),
+                )),
+            }
+        })
+    }
+
+    fn bind_udp<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<Box<dyn OutboundUdpSocket>>> {
+        Box::pin(async move {
+            let socket = self.bind_sticky(user).await?;
+
+            Ok(Box::new(DirectUdpSocket {
+                socket,
+                acl: self.acl.clone(),
+                resolver: self.resolver.clone(),
+                peers: Mutex::new(Peers::new(
+                    self.nat_filtering,
+                    MAX_NAT_FILTER_PEERS,
+                    NAT_FILTER_TIMEOUT,
+                )),
+            }) as Box<dyn OutboundUdpSocket>)
+        })
+    }
+}
+
+struct DirectUdpSocket {
+    socket: UdpSocket,
+    acl: Arc<Acl>,
+    resolver: Arc<Resolver>,
+    /// Destinations packets have been sent to, for filtering
+    peers: Mutex<Peers>,
+}
+
+/// The peers a UDP socket lets packets through from, keyed by IP for address-dependent filtering
+///
+/// A peer expires once it has not been sent to for `timeout`. When full, the peer sent to least recently is dropped.
+struct Peers {
+    filtering: NatFiltering,
+    peers: HashMap<SocketAddr, Instant>,
+    capacity: usize,
+    timeout: Duration,
+}
+
+impl Peers {
+    fn new(filtering: NatFiltering, capacity: usize, timeout: Duration) -> Self {
+        Self {
+            filtering,
+            peers: HashMap::new(),
+            capacity,
+            timeout,
+        }
+    }
+
+    fn key(&self, addr: SocketAddr) -> SocketAddr {
+        match self.filtering {
+            NatFiltering::AddressDependent => SocketAddr::new(addr.ip(), 0),
+            _ => addr,
+        }
+    }
+
+    fn insert(&mut self, addr: SocketAddr, now: Instant) {
+        if self.filtering == NatFiltering::EndpointIndependent {
+            return;
+        }
+
+        let key = self.key(addr);
+
+        if !self.peers.contains_key(&key) && self.peers.len() >= self.capacity {
+            let timeout = self.timeout;
+            self.peers
+                .retain(|_, sent| now.saturating_duration_since(*sent) < timeout);
+
+            if self.peers.len() >= self.capacity {
+                let oldest = self
+                    .peers
+                    .iter()
+                    .min_by_key(|(_, sent)| **sent)
+                    .map(|(peer, _)| *peer);
+
+                if let Some(oldest) = oldest {
+                    self.peers.remove(&oldest);
+                }
+            }
+        }
+
+        self.peers.insert(key, now);
+    }
+
+    fn is_allowed(&self, addr: SocketAddr, now: Instant) -> bool {
+        if self.filtering == NatFiltering::EndpointIndependent {
+            return true;
+        }
+
+        self.peers.get(&self.key(addr)).map_or(false, |sent| {
+            now.saturating_duration_since(*sent) < self.timeout
+        })
+    }
+}
+
+impl OutboundUdpSocket for DirectUdpSocket {
+    fn send_to<'a>(&'a self, pkt: &'a [u8], addr: &'a Address) -> BoxFuture<'a, Result<Address>> {
+        Box::pin(async move {
+            let addr = resolve(addr, &self.acl, &self.resolver)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(no_address)?;
+
+            self.peers.lock().insert(unmap(addr), Instant::now());
+
+            self.socket.send_to(pkt, addr).await?;
+            Ok(Address::SocketAddress(unmap(addr)))
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(usize, Address)>> {
+        Box::pin(async move {
+            loop {
+                let (len, addr) = self.socket.recv_from(buf).await?;
+                let addr = unmap(addr);
+
+                if self.peers.lock().is_allowed(addr, Instant::now()) {
+                    return Ok((len, Address::SocketAddress(addr)));
+                }
+            }
+        })
+    }
+}
+
+/// Converts an IPv4-mapped IPv6 address received on the dual-stack socket back to IPv4
+fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => {
+                SocketAddr::new(IpAddr::V4(ip.to_ipv4().unwrap()), addr.port())
+            }
+            _ => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Races connection attempts to the addresses in `interleave` order (RFC 8305).
+/// The next attempt starts when one fails, or after `CONNECTION_ATTEMPT_DELAY` while all are pending
+async fn happy_eyeballs(addrs: Vec<SocketAddr>, attempt_timeout: Duration) -> Result<TcpStream> {
+    let mut addrs = interleave(addrs).into_iter();
+    let (res_tx, mut res_rx) = mpsc::unbounded_channel();
+    let mut attempts = Attempts(Vec::new());
+    let mut pending = 0;
+    let mut last_err = None;
+
+    loop {
+        if let Some(addr) = addrs.next() {
+            let res_tx = res_tx.clone();
+
+            attempts.0.push(tokio::spawn(async move {
+                let res = match time::timeout(attempt_timeout, TcpStream::connect(addr)).await {
+                    Ok(res) => res,
+                    Err(_) => Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!("connecting to {addr} timed out"),
+                    )),
+                };
+
+                let _ = res_tx.send(res);
+            }));
+
+            pending += 1;
+        } else if pending == 0 {
+            return Err(last_err.unwrap_or_else(no_address));
+        }
+
+        let res = if addrs.len() > 0 {
+            match time::timeout(CONNECTION_ATTEMPT_DELAY, res_rx.recv()).await {
+                Ok(res) => res,
+                Err(_) => continue,
+            }
+        } else {
+            res_rx.recv().await
+        };
+
+        // never `None`, as `res_tx` is still held
+        if let Some(res) = res {
+            pending -= 1;
+
+            match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+    }
+}
+
+/// Alternates between address families, starting with the family of the first address
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().map_or(false, SocketAddr::is_ipv6);
+    let (first, second): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connection attempts in flight, aborted once the race is over
+struct Attempts(Vec<JoinHandle<()>>);
+
+impl Drop for Attempts {
+    fn drop(&mut self) {
+        for attempt in &self.0 {
+            attempt.abort();
+        }
+    }
+}
+
+/// Resolves the address and filters it by the ACL
+async fn resolve(addr: &Address, acl: &Acl, resolver: &Resolver) -> Result<Vec<SocketAddr>> {
+    let addrs = match addr {
+        Address::SocketAddress(addr) => vec![*addr],
+        Address::DomainAddress(domain, port) => resolver.resolve(domain, *port).await?,
+    };
+
+    check_acl(addrs, acl)
+}
+
+/// Keeps the addresses allowed by the ACL. Fails with `PermissionDenied` if every address is denied
+fn check_acl(addrs: Vec<SocketAddr>, acl: &Acl) -> Result<Vec<SocketAddr>> {
+    let (allowed, denied): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|addr| acl.is_allowed(*addr));
+
+    match denied.first() {
+        Some(addr) if allowed.is_empty() => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("destination {addr} denied by ACL"),
+        )),
+        _ => Ok(allowed),
+    }
+}
+
+fn no_address() -> Error {
+    Error::new(ErrorKind::NotFound, "no address resolved")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_dependent_peers_are_keyed_by_ip() {
+        let mut peers = Peers::new(NatFiltering::AddressDependent, 16, Duration::from_secs(60));
+        let now = Instant::now();
+
+        peers.insert(SocketAddr::from(([192, 0, 2, 1], 53)), now);
+
+        assert!(peers.is_allowed(SocketAddr::from(([192, 0, 2, 1], 5353)), now));
+        assert!(!peers.is_allowed(SocketAddr::from(([192, 0, 2, 2], 53)), now));
+        assert_eq!(peers.peers.len(), 1);
+
+        peers.insert(SocketAddr::from(([192, 0, 2, 1], 5353)), now);
+        assert_eq!(peers.peers.len(), 1);
+    }
+
+    #[test]
+    fn address_and_port_dependent_peers_match_ports() {
+        let mut peers = Peers::new(
+            NatFiltering::AddressAndPortDependent,
+            16,
+            Duration::from_secs(60),
+        );
+        let now = Instant::now();
+
+        peers.insert(SocketAddr::from(([192, 0, 2, 1], 53)), now);
+
+        assert!(peers.is_allowed(SocketAddr::from(([192, 0, 2, 1], 53)), now));
+        assert!(!peers.is_allowed(SocketAddr::from(([192, 0, 2, 1], 5353)), now));
+    }
+
+    #[test]
+    fn peers_expire_and_are_bounded() {
+        let mut peers = Peers::new(
+            NatFiltering::AddressAndPortDependent,
+            2,
+            Duration::from_secs(60),
+        );
+        let start = Instant::now();
+        let a = SocketAddr::from(([192, 0, 2, 1], 53));
+        let b = SocketAddr::from(([192, 0, 2, 2], 53));
+        let c = SocketAddr::from(([192, 0, 2, 3], 53));
+
+        peers.insert(a, start);
+        peers.insert(b, start + Duration::from_secs(1));
+        assert!(!peers.is_allowed(a, start + Duration::from_secs(60)));
+
+        // a was sent to least recently
+        peers.insert(a, start + Duration::from_secs(2));
+        peers.insert(c, start + Duration::from_secs(3));
+
+        let now = start + Duration::from_secs(4);
+        assert!(peers.is_allowed(a, now));
+        assert!(!peers.is_allowed(b, now));
+        assert!(peers.is_allowed(c, now));
+        assert_eq!(peers.peers.len(), 2);
+    }
+
+    #[test]
+    fn endpoint_independent_keeps_no_peers() {
+        let mut peers = Peers::new(
+            NatFiltering::EndpointIndependent,
+            16,
+            Duration::from_secs(60),
+        );
+        let now = Instant::now();
+
+        peers.insert(SocketAddr::from(([192, 0, 2, 1], 53)), now);
+
+        assert!(peers.is_allowed(SocketAddr::from(([192, 0, 2, 2], 53)), now));
+        assert!(peers.peers.is_empty());
+    }
+}
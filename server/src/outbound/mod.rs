@@ -0,0 +1,36 @@
+use crate::user::User;
+use std::{future::Future, io::Result, pin::Pin};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tuic_protocol::Address;
+
+pub use self::{
+    direct::{Direct, NatFiltering},
+    socks5::Socks5,
+};
+
+mod direct;
+mod socks5;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A way out of the server for relayed TCP connections and UDP packets
+pub trait Outbound: Send + Sync {
+    fn connect_tcp(&self, addr: Address) -> BoxFuture<'_, Result<TcpStream>>;
+
+    fn bind_udp<'a>(&'a self, user: &'a User) -> BoxFuture<'a, Result<Box<dyn OutboundUdpSocket>>>;
+}
+
+/// The source of an `io::Error` of an upstream proxy reporting the destination unreachable, as `ErrorKind::HostUnreachable` is not stable yet
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct Unreachable(pub String);
+
+/// A UDP socket bound through an `Outbound`
+pub trait OutboundUdpSocket: Send + Sync {
+    /// Returns the address the packet was sent to, which is resolved if the outbound resolves domains itself
+    fn send_to<'a>(&'a self, pkt: &'a [u8], addr: &'a Address) -> BoxFuture<'a, Result<Address>>;
+
+    /// Payloads longer than `buf` are truncated
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(usize, Address)>>;
+}
@@ -0,0 +1,507 @@
+use super::{BoxFuture, Outbound, OutboundUdpSocket, Unreachable};
+use crate::{resolver::Resolver, user::User};
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time,
+};
+use tuic_protocol::Address;
+
+const VERSION: u8 = 0x05;
+const PASSWORD_AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+/// RSV, FRAG, ATYP, the longest domain with its length and the port
+const MAX_UDP_HEADER_LEN: usize = 2 + 1 + 1 + 1 + 255 + 2;
+
+/// An upstream SOCKS5 proxy, optionally authenticated with a username and a password
+pub struct Socks5 {
+    server: SocketAddr,
+    auth: Option<(String, String)>,
+    /// Covers connecting to the proxy and the whole handshake
+    connect_timeout: Duration,
+    /// Resolves a domain the proxy binds UDP relays on
+    resolver: Arc<Resolver>,
+}
+
+impl Socks5 {
+    pub fn new(
+        server: SocketAddr,
+        auth: Option<(String, String)>,
+        connect_timeout: Duration,
+        resolver: Arc<Resolver>,
+    ) -> Self {
+        Self {
+            server,
+            auth,
+            connect_timeout,
+            resolver,
+        }
+    }
+
+    async fn request(&self, cmd: u8, addr: &Address) -> Result<(TcpStream, Address)> {
+        match time::timeout(self.connect_timeout, self.handshake(cmd, addr)).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("socks5 request to {} for {addr} timed out", self.server),
+            )),
+        }
+    }
+
+    /// Negotiates the authentication method and sends a request, returning the control stream and the bound address
+    async fn handshake(&self, cmd: u8, addr: &Address) -> Result<(TcpStream, Address)> {
+        let mut stream = TcpStream::connect(self.server).await?;
+        let _ = stream.set_nodelay(true);
+        log::debug!("[socks5] [{}] start handshake", self.server);
+
+        let methods: &[u8] = match self.auth {
+            Some(_) => &[METHOD_NO_AUTH, METHOD_PASSWORD],
+            None => &[METHOD_NO_AUTH],
+        };
+
+        let mut buf = vec![VERSION, methods.len() as u8];
+        buf.extend_from_slice(methods);
+        stream.write_all(&buf).await?;
+
+        let mut resp = [0; 2];
+        stream.read_exact(&mut resp).await?;
+        check_version(resp[0], VERSION)?;
+
+        match (resp[1], &self.auth) {
+            (METHOD_NO_AUTH, _) => {}
+            (METHOD_PASSWORD, Some((username, password))) => {
+                let mut buf = vec![PASSWORD_AUTH_VERSION];
+                write_bytes(&mut buf, username.as_bytes())?;
+                write_bytes(&mut buf, password.as_bytes())?;
+                stream.write_all(&buf).await?;
+
+                let mut resp = [0; 2];
+                stream.read_exact(&mut resp).await?;
+                check_version(resp[0], PASSWORD_AUTH_VERSION)?;
+
+                if resp[1] != REPLY_SUCCEEDED {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        "socks5 authentication failed",
+                    ));
+                }
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "socks5 no acceptable authentication method",
+                ))
+            }
+        }
+
+        let mut buf = vec![VERSION, cmd, 0x00];
+        write_address(&mut buf, addr)?;
+        stream.write_all(&buf).await?;
+
+        let mut resp = [0; 3];
+        stream.read_exact(&mut resp).await?;
+        check_version(resp[0], VERSION)?;
+
+        if resp[1] != REPLY_SUCCEEDED {
+            return Err(reply_error(resp[1]));
+        }
+
+        let bound_addr = read_address(&mut stream).await?;
+        log::debug!("[socks5] [{}] bound {bound_addr}", self.server);
+
+        Ok((stream, bound_addr))
+    }
+}
+
+impl Outbound for Socks5 {
+    fn connect_tcp(&self, addr: Address) -> BoxFuture<'_, Result<TcpStream>> {
+        Box::pin(async move {
+            let (stream, _) = self.request(CMD_CONNECT, &addr).await?;
+            Ok(stream)
+        })
+    }
+
+    fn bind_udp<'a>(
+        &'a self,
+        _user: &'a User,
+    ) -> BoxFuture<'a, Result<Box<dyn OutboundUdpSocket>>> {
+        Box::pin(async move {
+            let unspecified = Address::SocketAddress(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+            let (control, bound_addr) = self.request(CMD_UDP_ASSOCIATE, &unspecified).await?;
+
+            // an unspecified bound address means the relay shares the IP of the proxy
+            let relay_addr = match bound_addr {
+                Address::SocketAddress(addr) if addr.ip().is_unspecified() => {
+                    SocketAddr::new(self.server.ip(), addr.port())
+                }
+                Address::SocketAddress(addr) => addr,
+                Address::DomainAddress(domain, port) => self
+                    .resolver
+                    .resolve(&domain, port)
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, "no address resolved"))?,
+            };
+
+            let local_addr = match relay_addr {
+                SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            };
+
+            let socket = UdpSocket::bind(local_addr).await?;
+            socket.connect(relay_addr).await?;
+
+            Ok(Box::new(Socks5UdpSocket { socket, control }) as Box<dyn OutboundUdpSocket>)
+        })
+    }
+}
+
+/// A UDP association, which lasts as long as its control stream
+struct Socks5UdpSocket {
+    socket: UdpSocket,
+    control: TcpStream,
+}
+
+impl OutboundUdpSocket for Socks5UdpSocket {
+    fn send_to<'a>(&'a self, pkt: &'a [u8], addr: &'a Address) -> BoxFuture<'a, Result<Address>> {
+        Box::pin(async move {
+            let mut buf = Vec::with_capacity(MAX_UDP_HEADER_LEN + pkt.len());
+            buf.extend_from_slice(&[0x00, 0x00, 0x00]);
+            write_address(&mut buf, addr)?;
+            buf.extend_from_slice(pkt);
+
+            self.socket.send(&buf).await?;
+            Ok(addr.clone())
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(usize, Address)>> {
+        Box::pin(async move {
+            let mut raw = vec![0; MAX_UDP_HEADER_LEN + buf.len()];
+
+            loop {
+                let len = tokio::select! {
+                    res = self.socket.recv(&mut raw) => res?,
+                    res = self.control.readable() => {
+                        res?;
+
+                        match self.control.try_read(&mut [0; 1]) {
+                            Ok(0) => {
+                                return Err(Error::new(
+                                    ErrorKind::ConnectionAborted,
+                                    "socks5 UDP association closed",
+                                ))
+                            }
+                            Ok(_) => {}
+                            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                            Err(err) => return Err(err),
+                        }
+
+                        continue;
+                    }
+                };
+
+                // fragmented packets are not supported and dropped, as well as malformed ones
+                let (addr, payload) = match raw[..len] {
+                    [0x00, 0x00, 0x00, ref rest @ ..] => match parse_address(rest) {
+                        Some((addr, addr_len)) => (addr, &rest[addr_len..]),
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+
+                let len = payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&payload[..len]);
+
+                return Ok((len, addr));
+            }
+        })
+    }
+}
+
+fn check_version(ver: u8, expected: u8) -> Result<()> {
+    if ver == expected {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("socks5 unexpected version: {ver:#04x}"),
+        ))
+    }
+}
+
+/// Writes bytes prefixed by their length
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let len = u8::try_from(bytes.len()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "socks5 field longer than 255 bytes",
+        )
+    })?;
+
+    buf.push(len);
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn write_address(buf: &mut Vec<u8>, addr: &Address) -> Result<()> {
+    match addr {
+        Address::DomainAddress(domain, port) => {
+            buf.push(ATYP_DOMAIN);
+            write_bytes(buf, domain.as_bytes())?;
+            buf.extend_from_slice(&port.to_be_bytes());
+        }
+        Address::SocketAddress(SocketAddr::V4(addr)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Address::SocketAddress(SocketAddr::V6(addr)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+async fn read_address<R>(stream: &mut R) -> Result<Address>
+where
+    R: AsyncRead + Unpin,
+{
+    let addr = match stream.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            stream.read_exact(&mut ip).await?;
+            let port = stream.read_u16().await?;
+            Address::SocketAddress(SocketAddr::from((Ipv4Addr::from(ip), port)))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            stream.read_exact(&mut ip).await?;
+            let port = stream.read_u16().await?;
+            Address::SocketAddress(SocketAddr::from((Ipv6Addr::from(ip), port)))
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0; len as usize];
+            stream.read_exact(&mut domain).await?;
+            let port = stream.read_u16().await?;
+
+            let domain =
+                String::from_utf8(domain).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+            Address::DomainAddress(domain, port)
+        }
+        atyp => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("socks5 unsupported address type: {atyp:#04x}"),
+            ))
+        }
+    };
+
+    Ok(addr)
+}
+
+/// Returns the address and its length on the wire
+fn parse_address(buf: &[u8]) -> Option<(Address, usize)> {
+    let port = |pos: usize| Some(u16::from_be_bytes(buf.get(pos..pos + 2)?.try_into().ok()?));
+
+    match *buf.first()? {
+        ATYP_IPV4 => {
+            let ip: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+            let addr = SocketAddr::from((Ipv4Addr::from(ip), port(5)?));
+            Some((Address::SocketAddress(addr), 7))
+        }
+        ATYP_IPV6 => {
+            let ip: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+            let addr = SocketAddr::from((Ipv6Addr::from(ip), port(17)?));
+            Some((Address::SocketAddress(addr), 19))
+        }
+        ATYP_DOMAIN => {
+            let len = *buf.get(1)? as usize;
+            let domain = String::from_utf8(buf.get(2..2 + len)?.to_vec()).ok()?;
+            Some((Address::DomainAddress(domain, port(2 + len)?), 4 + len))
+        }
+        _ => None,
+    }
+}
+
+/// Lets the reply reach the client as the matching TUIC reply code
+fn reply_error(rep: u8) -> Error {
+    let msg = format!("socks5 request failed: {}", reply_message(rep));
+
+    match rep {
+        0x02 => Error::new(ErrorKind::PermissionDenied, msg),
+        0x03 | 0x04 => Error::new(ErrorKind::Other, Unreachable(msg)),
+        0x05 => Error::new(ErrorKind::ConnectionRefused, msg),
+        0x06 => Error::new(ErrorKind::TimedOut, msg),
+        _ => Error::new(ErrorKind::Other, msg),
+    }
+}
+
+fn reply_message(rep: u8) -> &'static str {
+    match rep {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        resolver::{IpPreference, Upstream},
+        traffic::Traffic,
+    };
+    use std::{collections::HashMap, net::IpAddr};
+    use tokio::net::TcpListener;
+
+    /// Never queries its upstream for the domains in `hosts`
+    fn resolver(hosts: HashMap<String, Vec<IpAddr>>) -> Arc<Resolver> {
+        let upstream = Upstream::Udp(SocketAddr::from(([127, 0, 0, 1], 53)));
+        Arc::new(Resolver::new(vec![upstream], IpPreference::PreferV4, hosts, 0).unwrap())
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_times_out() {
+        // accepts the connection but never replies
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks5 = Socks5::new(
+            listener.local_addr().unwrap(),
+            None,
+            Duration::from_millis(100),
+            resolver(HashMap::new()),
+        );
+
+        let addr = Address::DomainAddress(String::from("example.com"), 443);
+        let err = socks5.connect_tcp(addr).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    /// Fails a `CONNECT` to a domain with the reply code
+    async fn failed_request(rep: u8) -> Error {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks5 = Socks5::new(
+            listener.local_addr().unwrap(),
+            None,
+            Duration::from_secs(1),
+            resolver(HashMap::new()),
+        );
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[VERSION, METHOD_NO_AUTH]).await.unwrap();
+
+            // VER, CMD, RSV, ATYP, the length of the domain, the domain and the port
+            let mut req = vec![0; 4 + 1 + "example.com".len() + 2];
+            stream.read_exact(&mut req).await.unwrap();
+            stream.write_all(&[VERSION, rep, 0x00]).await.unwrap();
+        });
+
+        let addr = Address::DomainAddress(String::from("example.com"), 443);
+        socks5.connect_tcp(addr).await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn reply_codes_map_to_errors() {
+        let is_unreachable =
+            |err: &Error| err.get_ref().map_or(false, |err| err.is::<Unreachable>());
+
+        let err = failed_request(0x03).await;
+        assert!(is_unreachable(&err));
+
+        let err = failed_request(0x04).await;
+        assert!(is_unreachable(&err));
+
+        let err = failed_request(0x05).await;
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+
+        let err = failed_request(0x06).await;
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let err = failed_request(0x01).await;
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(!is_unreachable(&err));
+    }
+
+    #[tokio::test]
+    async fn domain_bound_address_is_resolved_by_the_resolver() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+
+        let hosts = HashMap::from([(
+            String::from("relay.test"),
+            vec![IpAddr::from([127, 0, 0, 1])],
+        )]);
+
+        let socks5 = Socks5::new(
+            listener.local_addr().unwrap(),
+            None,
+            Duration::from_secs(1),
+            resolver(hosts),
+        );
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[VERSION, METHOD_NO_AUTH]).await.unwrap();
+
+            // VER, CMD, RSV, ATYP, the unspecified IPv4 address and the port
+            let mut req = [0; 4 + 4 + 2];
+            stream.read_exact(&mut req).await.unwrap();
+
+            let mut resp = vec![VERSION, REPLY_SUCCEEDED, 0x00, ATYP_DOMAIN];
+            resp.push("relay.test".len() as u8);
+            resp.extend_from_slice(b"relay.test");
+            resp.extend_from_slice(&relay_port.to_be_bytes());
+            stream.write_all(&resp).await.unwrap();
+
+            // the association lasts as long as the control stream
+            let _ = stream.read(&mut [0; 1]).await;
+        });
+
+        let user = User::new(String::from("alice"), Traffic::default());
+        let socket = socks5.bind_udp(&user).await.unwrap();
+        let dst = Address::SocketAddress(SocketAddr::from(([192, 0, 2, 1], 53)));
+        socket.send_to(b"ping", &dst).await.unwrap();
+
+        let mut buf = [0; 64];
+        let len = relay.recv(&mut buf).await.unwrap();
+        assert!(buf[..len].ends_with(b"ping"));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::{config::Reloader, user::SharedUsers};
+use quinn::Endpoint;
+use std::{
+    fs, future,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::Notify, time};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads the users and the certificates on SIGHUP, when a watched file is modified, or when ACME renewed the certificate. Open connections are kept
+pub async fn watch(
+    reloader: &mut Reloader,
+    mut watched_files: Vec<PathBuf>,
+    renewed: &Notify,
+    endpoints: &[Endpoint],
+    users: &SharedUsers,
+) {
+    let mut hangup = Hangup::new();
+    let mut interval = time::interval(WATCH_INTERVAL);
+    let mut modified = modified_times(&watched_files);
+
+    loop {
+        tokio::select! {
+            () = hangup.recv() => log::info!("[reload] Received SIGHUP"),
+            () = renewed.notified() => log::info!("[reload] Certificate renewed"),
+            _ = interval.tick() => {
+                if modified_times(&watched_files) == modified {
+                    continue;
+                }
+
+                log::info!("[reload] Watched files modified");
+            }
+        }
+
+        match reloader.load() {
+            Ok(reloaded) => {
+                let mut new_users = reloaded.users;
+                new_users.carry_over(&users.load());
+                users.store(new_users);
+
+                for endpoint in endpoints {
+                    endpoint.set_server_config(Some(reloaded.server_config.clone()));
+                }
+
+                watched_files = reloaded.watched_files;
+
+                log::info!("[reload] Reloaded users and the certificates");
+            }
+            Err(err) => {
+                log::error!("[reload] Failed to reload, keeping the current configuration: {err}")
+            }
+        }
+
+        modified = modified_times(&watched_files);
+    }
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{self, SignalKind};
+
+            let signal = unix::signal(SignalKind::hangup())
+                .map_err(|err| log::error!("[reload] Failed to listen for SIGHUP: {err}"))
+                .ok();
+
+            Self { signal }
+        }
+
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+
+        future::pending().await
+    }
+}
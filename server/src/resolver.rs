@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+};
+use trust_dns_resolver::{
+    config::{LookupIpStrategy, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    error::ResolveError,
+    TokioAsyncResolver,
+};
+
+/// An upstream DNS server
+pub enum Upstream {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    /// DNS-over-HTTPS, with the server name to verify the certificate with
+    Https(SocketAddr, String),
+    /// DNS-over-TLS, with the server name to verify the certificate with
+    Tls(SocketAddr, String),
+}
+
+/// Which address families are resolved, and which comes first
+#[derive(Clone, Copy)]
+pub enum IpPreference {
+    PreferV4,
+    PreferV6,
+    V4Only,
+    V6Only,
+}
+
+/// Resolves domains of relayed connections and packets, caching records by their TTLs
+pub struct Resolver {
+    inner: TokioAsyncResolver,
+    hosts: HashMap<String, Vec<IpAddr>>,
+    preference: IpPreference,
+}
+
+impl Resolver {
+    /// Reads upstreams from the system configuration if none is set
+    pub fn new(
+        upstreams: Vec<Upstream>,
+        preference: IpPreference,
+        hosts: HashMap<String, Vec<IpAddr>>,
+        cache_size: usize,
+    ) -> std::result::Result<Self, ResolveError> {
+        let opts = ResolverOpts {
+            cache_size,
+            ip_strategy: match preference {
+                IpPreference::PreferV4 | IpPreference::PreferV6 => LookupIpStrategy::Ipv4AndIpv6,
+                IpPreference::V4Only => LookupIpStrategy::Ipv4Only,
+                IpPreference::V6Only => LookupIpStrategy::Ipv6Only,
+            },
+            ..Default::default()
+        };
+
+        let inner = if upstreams.is_empty() {
+            let (config, _) = trust_dns_resolver::system_conf::read_system_conf()?;
+            TokioAsyncResolver::tokio(config, opts)?
+        } else {
+            let mut name_servers = NameServerConfigGroup::new();
+
+            for upstream in upstreams {
+                let group = match upstream {
+                    Upstream::Udp(addr) => {
+                        let mut group =
+                            NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+                        group.retain(|ns| ns.protocol == Protocol::Udp);
+                        group
+                    }
+                    Upstream::Tcp(addr) => {
+                        let mut group =
+                            NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true);
+                        group.retain(|ns| ns.protocol == Protocol::Tcp);
+                        group
+                    }
+                    Upstream::Https(addr, name) => {
+                        NameServerConfigGroup::from_ips_https(&[addr.ip()], addr.port(), name, true)
+                    }
+                    Upstream::Tls(addr, name) => {
+                        NameServerConfigGroup::from_ips_tls(&[addr.ip()], addr.port(), name, true)
+                    }
+                };
+
+                name_servers.merge(group);
+            }
+
+            let config = ResolverConfig::from_parts(None, Vec::new(), name_servers);
+            TokioAsyncResolver::tokio(config, opts)?
+        };
+
+        let hosts = hosts
+            .into_iter()
+            .map(|(domain, ips)| (domain.to_ascii_lowercase(), ips))
+            .collect();
+
+        Ok(Self {
+            inner,
+            hosts,
+            preference,
+        })
+    }
+
+    /// Returns the addresses of a domain, the preferred address family first. Fails with `NotFound` if the lookup fails
+    pub async fn resolve(&self, domain: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let mut ips = match self.hosts.get(&domain.to_ascii_lowercase()) {
+            Some(ips) => ips.clone(),
+            None => self
+                .inner
+                .lookup_ip(domain)
+                .await
+                .map_err(|err| Error::new(ErrorKind::NotFound, err))?
+                .iter()
+                .collect(),
+        };
+
+        match self.preference {
+            IpPreference::PreferV4 => ips.sort_by_key(IpAddr::is_ipv6),
+            IpPreference::PreferV6 => ips.sort_by_key(IpAddr::is_ipv4),
+            IpPreference::V4Only => ips.retain(IpAddr::is_ipv4),
+            IpPreference::V6Only => ips.retain(IpAddr::is_ipv6),
+        }
+
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{cidr::Cidr, outbound::Outbound, user::User};
+use std::sync::Arc;
+use tuic_protocol::Address;
+
+/// Where a relayed connection or UDP packet is sent
+#[derive(Clone)]
+pub enum Route {
+    Outbound(Arc<dyn Outbound>),
+    Reject,
+}
+
+/// A rule matches when every condition set matches. Empty conditions match anything
+pub struct Rule {
+    pub domain_suffix: Vec<String>,
+    /// Only matches IP destinations, as domains are resolved after routing
+    pub cidr: Vec<Cidr>,
+    pub port: Vec<u16>,
+    pub user: Vec<String>,
+    pub route: Route,
+}
+
+impl Rule {
+    fn matches(&self, addr: &Address, user: &User) -> bool {
+        let (domain, ip, port) = match addr {
+            Address::DomainAddress(domain, port) => (Some(domain.as_str()), None, *port),
+            Address::SocketAddress(addr) => (None, Some(addr.ip()), addr.port()),
+        };
+
+        (self.domain_suffix.is_empty()
+            || domain.map_or(false, |domain| {
+                self.domain_suffix
+                    .iter()
+                    .any(|suffix| is_domain_suffix(domain, suffix))
+            }))
+            && (self.cidr.is_empty()
+                || ip.map_or(false, |ip| self.cidr.iter().any(|cidr| cidr.contains(ip))))
+            && (self.port.is_empty() || self.port.contains(&port))
+            && (self.user.is_empty() || self.user.iter().any(|name| name == user.name()))
+    }
+}
+
+/// Picks the route of a destination by the first matching rule
+pub struct Router {
+    rules: Vec<Rule>,
+    default: Route,
+}
+
+impl Router {
+    pub fn new(rules: Vec<Rule>, default: Route) -> Self {
+        Self { rules, default }
+    }
+
+    pub fn route(&self, addr: &Address, user: &User) -> &Route {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(addr, user))
+            .map_or(&self.default, |rule| &rule.route)
+    }
+}
+
+/// `example.com` is a suffix of both `example.com` and `www.example.com`, but not of `badexample.com`
+fn is_domain_suffix(domain: &str, suffix: &str) -> bool {
+    let domain = domain.trim_end_matches('.').as_bytes();
+    let suffix = suffix
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .as_bytes();
+
+    match domain.len().checked_sub(suffix.len()) {
+        Some(0) => domain.eq_ignore_ascii_case(suffix),
+        Some(head_len) => {
+            domain[head_len - 1] == b'.' && domain[head_len..].eq_ignore_ascii_case(suffix)
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        outbound::Socks5,
+        resolver::{IpPreference, Resolver, Upstream},
+        traffic::Traffic,
+    };
+    use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+    fn rule(domain_suffix: &[&str], cidr: &[&str], port: &[u16], route: Route) -> Rule {
+        Rule {
+            domain_suffix: domain_suffix.iter().map(|s| s.to_string()).collect(),
+            cidr: cidr.iter().map(|s| s.parse().unwrap()).collect(),
+            port: port.to_vec(),
+            user: Vec::new(),
+            route,
+        }
+    }
+
+    fn socks5() -> Arc<dyn Outbound> {
+        let upstream = Upstream::Udp(SocketAddr::from(([127, 0, 0, 1], 53)));
+        let resolver =
+            Resolver::new(vec![upstream], IpPreference::PreferV4, HashMap::new(), 0).unwrap();
+
+        Arc::new(Socks5::new(
+            SocketAddr::from(([127, 0, 0, 1], 1080)),
+            None,
+            Duration::from_secs(10),
+            Arc::new(resolver),
+        ))
+    }
+
+    fn is_outbound(route: &Route, outbound: &Arc<dyn Outbound>) -> bool {
+        matches!(route, Route::Outbound(route) if Arc::ptr_eq(route, outbound))
+    }
+
+    fn domain(domain: &str, port: u16) -> Address {
+        Address::DomainAddress(domain.to_owned(), port)
+    }
+
+    #[test]
+    fn domain_suffix() {
+        assert!(is_domain_suffix("example.com", "example.com"));
+        assert!(is_domain_suffix("www.example.com", "example.com"));
+        assert!(is_domain_suffix("a.b.example.com", ".example.com"));
+        assert!(is_domain_suffix("WWW.Example.COM.", "example.com"));
+        assert!(!is_domain_suffix("badexample.com", "example.com"));
+        assert!(!is_domain_suffix("example.com", "www.example.com"));
+        assert!(!is_domain_suffix("example.com.cn", "example.com"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let (upstream, default) = (socks5(), socks5());
+        let router = Router::new(
+            vec![
+                rule(&["example.com"], &[], &[], Route::Reject),
+                rule(&[], &[], &[443], Route::Outbound(upstream.clone())),
+            ],
+            Route::Outbound(default.clone()),
+        );
+        let user = User::new(String::from("alice"), Traffic::default());
+
+        assert!(matches!(
+            router.route(&domain("www.example.com", 443), &user),
+            Route::Reject
+        ));
+        assert!(is_outbound(
+            router.route(&domain("example.org", 443), &user),
+            &upstream
+        ));
+        assert!(is_outbound(
+            router.route(&domain("example.org", 80), &user),
+            &default
+        ));
+    }
+
+    #[test]
+    fn cidr_only_matches_ip_destinations() {
+        let router = Router::new(
+            vec![rule(&[], &["0.0.0.0/0", "::/0"], &[], Route::Reject)],
+            Route::Outbound(socks5()),
+        );
+        let user = User::new(String::from("alice"), Traffic::default());
+        let ip = Address::SocketAddress(SocketAddr::from(([93, 184, 216, 34], 443)));
+
+        assert!(matches!(router.route(&ip, &user), Route::Reject));
+        assert!(!matches!(
+            router.route(&domain("example.com", 443), &user),
+            Route::Reject
+        ));
+    }
+}
@@ -1,46 +1,294 @@
-use crate::connection::Connection;
+use crate::{
+    acme::Acme,
+    camouflage::{Camouflage, Site},
+    config::Reloader,
+    connection::{Connection, UdpRelayConfig, CODE_SHUTTING_DOWN},
+    metrics::{self, Metrics},
+    reload,
+    router::Router,
+    shutdown::{self, InFlight},
+    traffic,
+    user::{SharedUsers, Users},
+};
 
-use quinn::{Endpoint, ServerConfig};
+use quinn::{Endpoint, EndpointConfig, ServerConfig, TokioRuntime};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::{
+    future,
+    io::Result,
+    net::{SocketAddr, TcpListener as StdTcpListener, UdpSocket},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, watch, Notify},
+    time,
+};
+use tracing::{field::Empty, Instrument};
 
-use std::{collections::HashSet, io::Result, net::SocketAddr, sync::Arc, time::Duration};
+const TRAFFIC_ACCOUNTING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An address the server accepts QUIC connections on
+pub struct Listener {
+    pub addr: SocketAddr,
+    /// Sets `IPV6_V6ONLY` on an IPv6 socket, which is dual-stack otherwise
+    pub ipv6_only: bool,
+}
+
+impl Listener {
+    fn bind(&self, config: ServerConfig) -> Result<Endpoint> {
+        let socket = Socket::new(
+            Domain::for_address(self.addr),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+
+        if self.addr.is_ipv6() {
+            socket.set_only_v6(self.ipv6_only)?;
+        }
+
+        socket.bind(&SockAddr::from(self.addr))?;
+
+        Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            UdpSocket::from(socket),
+            TokioRuntime,
+        )
+    }
+}
 
 pub struct Server {
-    endpoint: Endpoint,
-    listen_addr: SocketAddr,
-    token: Arc<HashSet<[u8; 32]>>,
+    endpoints: Vec<Endpoint>,
+    listen_addrs: Vec<SocketAddr>,
+    users: SharedUsers,
+    reloader: Reloader,
+    watched_files: Vec<PathBuf>,
+    acme: Option<Acme>,
+    camouflage: Option<Arc<Camouflage>>,
+    router: Arc<Router>,
+    metrics: Arc<Metrics>,
+    metrics_listener: Option<TcpListener>,
     authentication_timeout: Duration,
-    max_pkt_size: usize,
+    require_session_bound_auth: bool,
+    udp_relay_config: UdpRelayConfig,
+    traffic_accounting_file: Option<PathBuf>,
+    drain_timeout: Duration,
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn init(
         config: ServerConfig,
-        listen_addr: SocketAddr,
-        token: HashSet<[u8; 32]>,
+        listeners: Vec<Listener>,
+        users: Users,
+        reloader: Reloader,
+        watched_files: Vec<PathBuf>,
+        acme: Option<Acme>,
+        camouflage: Option<Site>,
+        camouflage_alpn: Vec<Vec<u8>>,
+        router: Router,
         auth_timeout: Duration,
-        max_pkt_size: usize,
+        require_session_bound_auth: bool,
+        udp_relay_config: UdpRelayConfig,
+        traffic_accounting_file: Option<PathBuf>,
+        drain_timeout: Duration,
+        metrics_addr: Option<SocketAddr>,
     ) -> Result<Self> {
-        let endpoint = Endpoint::server(config, listen_addr)?;
+        let endpoints = listeners
+            .iter()
+            .map(|listener| listener.bind(config.clone()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let listen_addrs = endpoints
+            .iter()
+            .map(Endpoint::local_addr)
+            .collect::<Result<Vec<_>>>()?;
+
+        let camouflage = camouflage
+            .map(|site| Camouflage::new(site, camouflage_alpn).map(Arc::new))
+            .transpose()?;
+
+        let metrics_listener = metrics_addr
+            .map(|addr| {
+                let listener = StdTcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)
+            })
+            .transpose()?;
 
         Ok(Self {
-            endpoint,
-            listen_addr,
-            token: Arc::new(token),
+            endpoints,
+            listen_addrs,
+            users: SharedUsers::new(users),
+            reloader,
+            watched_files,
+            acme,
+            camouflage,
+            router: Arc::new(router),
+            metrics: Arc::new(Metrics::new()),
+            metrics_listener,
             authentication_timeout: auth_timeout,
-            max_pkt_size,
+            require_session_bound_auth,
+            udp_relay_config,
+            traffic_accounting_file,
+            drain_timeout,
         })
     }
 
-    pub async fn run(self) {
-        log::info!("Server started. Listening: {}", self.listen_addr);
+    pub async fn run(mut self) {
+        let listen_addrs = self
+            .listen_addrs
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect::<Vec<_>>();
+
+        log::info!("Server started. Listening: {}", listen_addrs.join(", "));
 
-        while let Some(conn) = self.endpoint.accept().await {
-            tokio::spawn(Connection::handle(
+        if let Some(path) = self.traffic_accounting_file.clone() {
+            tokio::spawn(traffic::persist_usage(
+                path,
+                self.users.clone(),
+                TRAFFIC_ACCOUNTING_INTERVAL,
+            ));
+        }
+
+        if let Some(listener) = self.metrics_listener.take() {
+            if let Ok(addr) = listener.local_addr() {
+                log::info!("[metrics] Serving metrics on http://{addr}/metrics");
+            }
+
+            let metrics = self.metrics.clone();
+
+            tokio::spawn(async move {
+                let err = metrics::serve(listener, metrics).await;
+                log::error!("[metrics] Failed to accept connections: {err}");
+            });
+        }
+
+        let renewed = Arc::new(Notify::new());
+
+        if let Some(acme) = self.acme.take() {
+            tokio::spawn(acme.run(renewed.clone()));
+        }
+
+        // accepting ends once all endpoints are closed
+        let (conn_tx, mut conn_rx) = mpsc::channel(1);
+
+        for endpoint in &self.endpoints {
+            let endpoint = endpoint.clone();
+            let conn_tx = conn_tx.clone();
+
+            tokio::spawn(async move {
+                while let Some(conn) = endpoint.accept().await {
+                    if conn_tx.send(conn).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        drop(conn_tx);
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let connections = InFlight::new();
+
+        let signal = async {
+            if let Err(err) = shutdown::signal().await {
+                log::error!("Failed to listen for shutdown signals: {err}");
+                future::pending::<()>().await;
+            }
+        };
+
+        // polled only while accepting, so a reload can't set the server config again after shutdown starts
+        let reload = reload::watch(
+            &mut self.reloader,
+            self.watched_files.clone(),
+            &renewed,
+            &self.endpoints,
+            &self.users,
+        );
+
+        tokio::pin!(signal);
+        tokio::pin!(reload);
+
+        loop {
+            let conn = tokio::select! {
+                conn = conn_rx.recv() => match conn {
+                    Some(conn) => conn,
+                    None => break,
+                },
+                () = &mut signal => break,
+                () = &mut reload => unreachable!(),
+            };
+
+            let span = tracing::info_span!(
+                "connection",
+                connection_id = Empty,
+                remote_addr = %conn.remote_address(),
+                user = Empty,
+            );
+
+            let handle = Connection::handle(
                 conn,
-                self.token.clone(),
+                self.users.load(),
+                self.router.clone(),
+                self.metrics.clone(),
                 self.authentication_timeout,
-                self.max_pkt_size,
-            ));
+                self.require_session_bound_auth,
+                self.udp_relay_config.clone(),
+                self.camouflage.clone(),
+                shutdown_rx.clone(),
+            );
+
+            let conn_guard = connections.enter();
+
+            let task = async move {
+                handle.await;
+                drop(conn_guard);
+            };
+
+            tokio::spawn(task.instrument(span));
         }
+
+        log::info!(
+            "Shutting down. Draining relayed TCP connections for up to {:?}",
+            self.drain_timeout
+        );
+
+        // refuse new connections. Open ones are closed once their relayed TCP connections are drained
+        for endpoint in &self.endpoints {
+            endpoint.set_server_config(None);
+        }
+
+        let _ = shutdown_tx.send(true);
+
+        if time::timeout(self.drain_timeout, connections.wait_idle())
+            .await
+            .is_err()
+        {
+            log::warn!("Drain timeout reached. Closing the remaining connections");
+        }
+
+        for endpoint in &self.endpoints {
+            endpoint.close(CODE_SHUTTING_DOWN, b"server shutting down");
+        }
+
+        for endpoint in &self.endpoints {
+            endpoint.wait_idle().await;
+        }
+
+        if let Some(path) = &self.traffic_accounting_file {
+            if let Err(err) = traffic::save_usage(path, &self.users.load()) {
+                log::warn!(
+                    "[traffic] Failed to save quota usage to {}: {err}",
+                    path.display()
+                );
+            }
+        }
+
+        log::info!("Server stopped");
     }
 }
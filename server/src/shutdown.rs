@@ -0,0 +1,72 @@
+use std::{
+    io::Result,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Notify;
+
+/// Waits for SIGTERM or SIGINT
+pub async fn signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{self, SignalKind};
+
+        let mut sigterm = unix::signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            _ = sigterm.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
+
+/// Counts tasks in flight, so that shutdown can wait for them to finish
+#[derive(Clone)]
+pub struct InFlight(Arc<InFlightInner>);
+
+struct InFlightInner {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self(Arc::new(InFlightInner {
+            count: AtomicUsize::new(0),
+            idle: Notify::new(),
+        }))
+    }
+
+    /// The task counts until the guard is dropped
+    pub fn enter(&self) -> InFlightGuard {
+        self.0.count.fetch_add(1, Ordering::AcqRel);
+        InFlightGuard(self.clone())
+    }
+
+    pub async fn wait_idle(&self) {
+        loop {
+            let idle = self.0.idle.notified();
+
+            if self.0.count.load(Ordering::Acquire) == 0 {
+                return;
+            }
+
+            idle.await;
+        }
+    }
+}
+
+pub struct InFlightGuard(InFlight);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0 .0.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0 .0.idle.notify_waiters();
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use bytes::{Bytes, BytesMut};
+use h3::{server::Connection as H3Connection, Error as H3Error};
+use http::{header, Method, Request, Response, StatusCode};
+use quinn::{Connecting, Endpoint};
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    fs::{self, File},
+    io::AsyncReadExt,
+};
+
+const NOT_FOUND_PAGE: &str =
+    "<html><head><title>404 Not Found</title></head><body><h1>Not Found</h1></body></html>";
+const CHUNK_SIZE: usize = 65536;
+
+/// The body of a response, with files sent in chunks rather than read into memory as a whole
+enum Body {
+    Page(Bytes),
+    File(File),
+}
+
+/// Serves the files of a directory over HTTP/3 to the connections relayed by the camouflage. The root must be canonical
+pub async fn serve(endpoint: Endpoint, root: PathBuf) {
+    let root = Arc::new(root);
+
+    while let Some(conn) = endpoint.accept().await {
+        let root = root.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(conn, &root).await {
+                log::debug!("[camouflage] {err}");
+            }
+        });
+    }
+}
+
+async fn serve_connection(conn: Connecting, root: &Path) -> Result<(), H3Error> {
+    let conn = match conn.await {
+        Ok(conn) => conn,
+        Err(_) => return Ok(()),
+    };
+
+    let mut conn = H3Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn)).await?;
+
+    while let Some((req, mut stream)) = conn.accept().await? {
+        let (resp, body) = respond(&req, root).await;
+
+        stream.send_response(resp).await?;
+
+        match body {
+            Some(Body::Page(page)) => stream.send_data(page).await?,
+            Some(Body::File(mut file)) => loop {
+                let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
+
+                // a failed read cuts the response short, as the length is already sent
+                match file.read_buf(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => stream.send_data(buf.freeze()).await?,
+                }
+            },
+            None => {}
+        }
+
+        stream.finish().await?;
+    }
+
+    Ok(())
+}
+
+/// Answers `GET` and `HEAD` requests with the file at the path, or `index.html` of a directory
+async fn respond(req: &Request<()>, root: &Path) -> (Response<()>, Option<Body>) {
+    let builder = Response::builder();
+
+    if *req.method() != Method::GET && *req.method() != Method::HEAD {
+        let resp = builder
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, "GET, HEAD")
+            .body(())
+            .unwrap();
+
+        return (resp, None);
+    }
+
+    let (status, content_type, len, body) = match open_file(root, req.uri().path()).await {
+        Some((content_type, len, file)) => (StatusCode::OK, content_type, len, Body::File(file)),
+        None => (
+            StatusCode::NOT_FOUND,
+            "text/html; charset=utf-8",
+            NOT_FOUND_PAGE.len() as u64,
+            Body::Page(Bytes::from_static(NOT_FOUND_PAGE.as_bytes())),
+        ),
+    };
+
+    let resp = builder
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, len)
+        .body(())
+        .unwrap();
+
+    if *req.method() == Method::HEAD {
+        (resp, None)
+    } else {
+        (resp, Some(body))
+    }
+}
+
+/// Paths reaching outside of the root, including through symlinks, are not found
+async fn open_file(root: &Path, path: &str) -> Option<(&'static str, u64, File)> {
+    let mut file_path = root.to_path_buf();
+
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => file_path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    let mut file_path = fs::canonicalize(&file_path).await.ok()?;
+
+    if fs::metadata(&file_path).await.ok()?.is_dir() {
+        file_path = fs::canonicalize(file_path.join("index.html")).await.ok()?;
+    }
+
+    if !file_path.starts_with(root) {
+        return None;
+    }
+
+    let file = File::open(&file_path).await.ok()?;
+    let metadata = file.metadata().await.ok()?;
+
+    if !metadata.is_file() {
+        return None;
+    }
+
+    Some((content_type(&file_path), metadata.len(), file))
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[tokio::test]
+    async fn files_outside_the_root_are_not_found() {
+        let dir = env::temp_dir().join(format!("tuic-static-site-{}", process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("index.html"), "index").unwrap();
+        std::fs::write(root.join("sub").join("page.txt"), "page").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub"), root.join("inner")).unwrap();
+
+        let root = std::fs::canonicalize(&root).unwrap();
+
+        let (content_type, len, _) = open_file(&root, "/").await.unwrap();
+        assert_eq!((content_type, len), ("text/html; charset=utf-8", 5));
+
+        let (content_type, len, _) = open_file(&root, "/inner/page.txt").await.unwrap();
+        assert_eq!((content_type, len), ("text/plain; charset=utf-8", 4));
+
+        assert!(open_file(&root, "/link.txt").await.is_none());
+        assert!(open_file(&root, "/../secret.txt").await.is_none());
+        assert!(open_file(&root, "/missing.txt").await.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
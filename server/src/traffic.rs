@@ -0,0 +1,402 @@
+use crate::user::{SharedUsers, Users};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{ErrorKind, Result},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::time;
+
+/// Rate limits and the byte quota of a user, shared by all of its connections
+#[derive(Default)]
+pub struct Traffic {
+    tcp_rate_limit: Option<RateLimiter>,
+    udp_rate_limit: Option<RateLimiter>,
+    quota: Option<Quota>,
+}
+
+impl Traffic {
+    pub fn new(
+        tcp_rate_limit: Option<u64>,
+        udp_rate_limit: Option<u64>,
+        quota: Option<Quota>,
+    ) -> Self {
+        Self {
+            tcp_rate_limit: tcp_rate_limit.map(RateLimiter::new),
+            udp_rate_limit: udp_rate_limit.map(RateLimiter::new),
+            quota,
+        }
+    }
+
+    /// Accounts `len` bytes of TCP traffic, returning how long the transfer should be paused
+    pub fn charge_tcp(&self, len: usize) -> Duration {
+        Self::charge(&self.tcp_rate_limit, &self.quota, len)
+    }
+
+    /// Accounts `len` bytes of UDP traffic, returning how long the transfer should be paused
+    pub fn charge_udp(&self, len: usize) -> Duration {
+        Self::charge(&self.udp_rate_limit, &self.quota, len)
+    }
+
+    pub fn is_quota_exhausted(&self) -> bool {
+        self.quota.as_ref().map_or(false, Quota::is_exhausted)
+    }
+
+    pub fn quota(&self) -> Option<&Quota> {
+        self.quota.as_ref()
+    }
+
+    pub fn has_same_limits(&self, other: &Traffic) -> bool {
+        let rate = |limiter: &Option<RateLimiter>| limiter.as_ref().map(|limiter| limiter.rate);
+        let quota = |quota: &Option<Quota>| quota.as_ref().map(|quota| (quota.limit, quota.period));
+
+        rate(&self.tcp_rate_limit) == rate(&other.tcp_rate_limit)
+            && rate(&self.udp_rate_limit) == rate(&other.udp_rate_limit)
+            && quota(&self.quota) == quota(&other.quota)
+    }
+
+    /// Continues the quota usage of `old`, if both count over the same period
+    pub fn carry_over(&self, old: &Traffic) {
+        if let (Some(quota), Some(old)) = (&self.quota, &old.quota) {
+            if quota.period == old.period {
+                let usage = *old.usage.lock();
+                *quota.usage.lock() = usage;
+            }
+        }
+    }
+
+    fn charge(limiter: &Option<RateLimiter>, quota: &Option<Quota>, len: usize) -> Duration {
+        if let Some(quota) = quota {
+            quota.add(len as u64);
+        }
+
+        limiter
+            .as_ref()
+            .map_or(Duration::ZERO, |limiter| limiter.charge(len))
+    }
+}
+
+/// A token bucket allowing bursts of up to one second of traffic
+pub struct RateLimiter {
+    rate: u64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    fn charge(&self, len: usize) -> Duration {
+        self.charge_at(len, Instant::now())
+    }
+
+    fn charge_at(&self, len: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock();
+        let (tokens, last) = &mut *bucket;
+
+        let rate = self.rate as f64;
+
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * rate).min(rate);
+        *last = now;
+        *tokens -= len as f64;
+
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / rate)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum QuotaPeriod {
+    Total,
+    Monthly,
+}
+
+/// A byte quota counting traffic in both directions
+pub struct Quota {
+    limit: u64,
+    period: QuotaPeriod,
+    usage: Mutex<QuotaUsage>,
+}
+
+#[derive(Clone, Copy)]
+struct QuotaUsage {
+    used: u64,
+    month: u32,
+}
+
+impl Quota {
+    pub fn new(limit: u64, period: QuotaPeriod) -> Self {
+        Self {
+            limit,
+            period,
+            usage: Mutex::new(QuotaUsage {
+                used: 0,
+                month: current_month(),
+            }),
+        }
+    }
+
+    fn add(&self, len: u64) {
+        let mut usage = self.usage.lock();
+        self.roll_over(&mut usage);
+        usage.used = usage.used.saturating_add(len);
+    }
+
+    fn is_exhausted(&self) -> bool {
+        let mut usage = self.usage.lock();
+        self.roll_over(&mut usage);
+        usage.used >= self.limit
+    }
+
+    fn roll_over(&self, usage: &mut QuotaUsage) {
+        if self.period == QuotaPeriod::Monthly {
+            let month = current_month();
+
+            if usage.month != month {
+                *usage = QuotaUsage { used: 0, month };
+            }
+        }
+    }
+
+    fn to_record(&self) -> UsageRecord {
+        let mut usage = self.usage.lock();
+        self.roll_over(&mut usage);
+
+        UsageRecord {
+            used: usage.used,
+            period: match self.period {
+                QuotaPeriod::Total => String::from("total"),
+                QuotaPeriod::Monthly => format_month(usage.month),
+            },
+        }
+    }
+
+    fn restore(&self, record: &UsageRecord) {
+        let mut usage = self.usage.lock();
+
+        let is_current = match self.period {
+            QuotaPeriod::Total => record.period == "total",
+            QuotaPeriod::Monthly => record.period == format_month(usage.month),
+        };
+
+        if is_current {
+            usage.used = record.used;
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct UsageRecord {
+    used: u64,
+    period: String,
+}
+
+/// Restores quota usage saved by `save_usage`. A missing file is not an error
+pub fn load_usage(path: &Path, users: &Users) -> Result<()> {
+    let records = read_usage(path)?;
+
+    for user in users.iter() {
+        if let (Some(quota), Some(record)) =
+            (user.traffic().quota(), records.get(&user.to_string()))
+        {
+            quota.restore(record);
+        }
+    }
+
+    Ok(())
+}
+
+/// Saves the quota usage of all users to `path`, replacing it atomically. Usage saved for users removed since is kept, so that it is restored if they are added back
+pub fn save_usage(path: &Path, users: &Users) -> Result<()> {
+    let mut records = read_usage(path)?;
+
+    records.extend(
+        users
+            .iter()
+            .filter_map(|user| Some((user.to_string(), user.traffic().quota()?.to_record()))),
+    );
+
+    let tmp_path = path.with_extension("tmp");
+    serde_json::to_writer(File::create(&tmp_path)?, &records)?;
+    fs::rename(tmp_path, path)
+}
+
+fn read_usage(path: &Path) -> Result<HashMap<String, UsageRecord>> {
+    match File::open(path) {
+        Ok(file) => Ok(serde_json::from_reader(file)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Saves the quota usage periodically
+pub async fn persist_usage(path: PathBuf, users: SharedUsers, interval: Duration) {
+    let mut interval = time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = save_usage(&path, &users.load()) {
+            log::warn!(
+                "[traffic] Failed to save quota usage to {}: {err}",
+                path.display()
+            );
+        }
+    }
+}
+
+/// The current month, counted since January 1970 in UTC
+fn current_month() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    month_of(secs)
+}
+
+/// Months since January 1970 in UTC of a Unix timestamp
+fn month_of(secs: u64) -> u32 {
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    ((year - 1970) * 12 + month - 1) as u32
+}
+
+fn format_month(month: u32) -> String {
+    format!("{:04}-{:02}", 1970 + month / 12, month % 12 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::User;
+    use std::{env, process, sync::Arc};
+
+    #[test]
+    fn rate_limiter_delays_until_refilled() {
+        let limiter = RateLimiter::new(100);
+        let start = limiter.bucket.lock().1;
+
+        assert_eq!(limiter.charge_at(100, start), Duration::ZERO);
+        assert_eq!(limiter.charge_at(50, start), Duration::from_millis(500));
+
+        // half a second refills the 50 bytes borrowed
+        let now = start + Duration::from_millis(500);
+        assert_eq!(limiter.charge_at(0, now), Duration::ZERO);
+        assert_eq!(limiter.charge_at(25, now), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn rate_limiter_bursts_at_most_one_second() {
+        let limiter = RateLimiter::new(100);
+        let start = limiter.bucket.lock().1;
+
+        let now = start + Duration::from_secs(10);
+        assert_eq!(limiter.charge_at(150, now), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn quota_is_exhausted_at_the_limit() {
+        let quota = Quota::new(100, QuotaPeriod::Total);
+
+        quota.add(99);
+        assert!(!quota.is_exhausted());
+
+        quota.add(1);
+        assert!(quota.is_exhausted());
+    }
+
+    #[test]
+    fn monthly_quota_rolls_over() {
+        let quota = Quota::new(100, QuotaPeriod::Monthly);
+        quota.add(100);
+        assert!(quota.is_exhausted());
+
+        quota.usage.lock().month -= 1;
+        assert!(!quota.is_exhausted());
+        assert_eq!(quota.usage.lock().used, 0);
+    }
+
+    #[test]
+    fn total_quota_never_rolls_over() {
+        let quota = Quota::new(100, QuotaPeriod::Total);
+        quota.add(100);
+
+        quota.usage.lock().month -= 1;
+        assert!(quota.is_exhausted());
+    }
+
+    #[test]
+    fn month_boundaries() {
+        let month = |secs| format_month(month_of(secs));
+
+        assert_eq!(month(0), "1970-01");
+        // 2023-12-31T23:59:59Z and 2024-01-01T00:00:00Z
+        assert_eq!(month(1704067199), "2023-12");
+        assert_eq!(month(1704067200), "2024-01");
+        assert_eq!(month_of(1704067200), month_of(1704067199) + 1);
+        // 2024-02-29T12:00:00Z and 2024-03-01T00:00:00Z
+        assert_eq!(month(1709208000), "2024-02");
+        assert_eq!(month(1709251200), "2024-03");
+        // 2023-02-28T23:59:59Z and 2023-03-01T00:00:00Z
+        assert_eq!(month(1677628799), "2023-02");
+        assert_eq!(month(1677628800), "2023-03");
+        // 2000-02-29T00:00:00Z and 2100-02-28T00:00:00Z
+        assert_eq!(month(951782400), "2000-02");
+        assert_eq!(month(4107456000), "2100-02");
+    }
+
+    #[test]
+    fn saving_keeps_usage_of_removed_users() {
+        let path = env::temp_dir().join(format!("tuic-usage-{}.json", process::id()));
+        let user = |name: &str, used| {
+            let quota = Quota::new(1000, QuotaPeriod::Total);
+            quota.add(used);
+            Arc::new(User::new(
+                name.to_owned(),
+                Traffic::new(None, None, Some(quota)),
+            ))
+        };
+
+        let mut users = Users::new();
+        users.insert(user("alice", 10));
+        users.insert(user("bob", 20));
+        save_usage(&path, &users).unwrap();
+
+        let mut users = Users::new();
+        users.insert(user("alice", 30));
+        save_usage(&path, &users).unwrap();
+
+        let mut users = Users::new();
+        users.insert(user("alice", 0));
+        users.insert(user("bob", 0));
+        load_usage(&path, &users).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let used = |name| {
+            let user = users.iter().find(|user| user.name() == name).unwrap();
+            user.traffic().quota().unwrap().usage.lock().used
+        };
+
+        assert_eq!(used("alice"), 30);
+        assert_eq!(used("bob"), 20);
+    }
+}
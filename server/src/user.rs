@@ -0,0 +1,207 @@
+use crate::{certificate, traffic::Traffic};
+use parking_lot::RwLock;
+use ring::constant_time;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter, Result as FmtResult},
+    sync::Arc,
+};
+use tuic_protocol::SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN;
+use uuid::Uuid;
+
+/// A named account that clients authenticate as
+pub struct User {
+    name: String,
+    traffic: Traffic,
+}
+
+impl User {
+    pub fn new(name: String, traffic: Traffic) -> Self {
+        Self { name, traffic }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+}
+
+impl Display for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Users indexed by their TUIC v4 token digests, TUIC v5 UUIDs and client certificate names
+#[derive(Default)]
+pub struct Users {
+    users: Vec<Arc<User>>,
+    tokens: HashMap<[u8; 32], Arc<User>>,
+    uuids: HashMap<Uuid, (String, Arc<User>)>,
+    certificate_names: HashMap<String, Arc<User>>,
+    /// Names of the users allowed on a server name (SNI)
+    server_name_users: HashMap<String, HashSet<String>>,
+    /// Names of the users allowed on an ALPN protocol
+    alpn_users: HashMap<Vec<u8>, HashSet<String>>,
+}
+
+impl Users {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, user: Arc<User>) {
+        self.users.push(user);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<User>> {
+        self.users.iter()
+    }
+
+    /// Returns `false` if the token is already taken by another user
+    pub fn insert_token(&mut self, token: &str, user: Arc<User>) -> bool {
+        let digest = *blake3::hash(token.as_bytes()).as_bytes();
+
+        if self.tokens.contains_key(&digest) {
+            return false;
+        }
+
+        self.tokens.insert(digest, user);
+        true
+    }
+
+    /// Returns `false` if the UUID is already taken by another user
+    pub fn insert_uuid(&mut self, uuid: Uuid, password: String, user: Arc<User>) -> bool {
+        if self.uuids.contains_key(&uuid) {
+            return false;
+        }
+
+        self.uuids.insert(uuid, (password, user));
+        true
+    }
+
+    /// Returns `false` if the name is already taken by another user
+    pub fn insert_certificate_name(&mut self, name: String, user: Arc<User>) -> bool {
+        if self.certificate_names.contains_key(&name) {
+            return false;
+        }
+
+        self.certificate_names.insert(name, user);
+        true
+    }
+
+    /// Only the named users can authenticate on connections to the server name, which can be a wildcard like in certificates
+    pub fn restrict_server_name(&mut self, server_name: &str, users: HashSet<String>) {
+        self.server_name_users
+            .insert(server_name.to_ascii_lowercase(), users);
+    }
+
+    /// Only the named users can authenticate on connections negotiating the ALPN protocol
+    pub fn restrict_alpn(&mut self, alpn: Vec<u8>, users: HashSet<String>) {
+        self.alpn_users.insert(alpn, users);
+    }
+
+    /// Checks the restrictions of the server name and the ALPN protocol of a connection, if there are any
+    pub fn is_allowed(&self, user: &User, server_name: Option<&str>, alpn: Option<&[u8]>) -> bool {
+        let by_server_name = server_name.and_then(|server_name| {
+            certificate::match_server_name(&self.server_name_users, server_name)
+        });
+        let by_alpn = alpn.and_then(|alpn| self.alpn_users.get(alpn));
+
+        by_server_name
+            .into_iter()
+            .chain(by_alpn)
+            .all(|users| users.contains(user.name()))
+    }
+
+    /// Compares the digest with every token in constant time, instead of looking it up
+    pub fn find_by_token(&self, digest: &[u8; 32]) -> Option<Arc<User>> {
+        self.tokens
+            .iter()
+            .find(|(token, _)| constant_time::verify_slices_are_equal(*token, digest).is_ok())
+            .map(|(_, user)| user.clone())
+    }
+
+    /// Finds the user whose token matches a digest bound to the TLS session with `keying_material`
+    pub fn find_by_session_bound_digest(
+        &self,
+        digest: &[u8; 32],
+        keying_material: &[u8; SESSION_BOUND_AUTH_KEYING_MATERIAL_LEN],
+    ) -> Option<Arc<User>> {
+        self.tokens
+            .iter()
+            .find(|(token, _)| {
+                let expected = tuic_protocol::session_bound_digest(token, keying_material);
+                constant_time::verify_slices_are_equal(&expected, digest).is_ok()
+            })
+            .map(|(_, user)| user.clone())
+    }
+
+    /// Returns the password and the user of a UUID
+    pub fn find_by_uuid(&self, uuid: &Uuid) -> Option<(&str, Arc<User>)> {
+        self.uuids
+            .get(uuid)
+            .map(|(password, user)| (password.as_str(), user.clone()))
+    }
+
+    /// Returns the user of the first name of a client certificate that belongs to one
+    pub fn find_by_certificate_names(&self, names: &[String]) -> Option<Arc<User>> {
+        names
+            .iter()
+            .find_map(|name| self.certificate_names.get(name))
+            .cloned()
+    }
+
+    /// Takes over users of `old` with the same name and limits, so that their traffic keeps being shared with open connections. Users with changed limits keep their quota usage
+    pub fn carry_over(&mut self, old: &Users) {
+        let old = old
+            .users
+            .iter()
+            .map(|user| (user.name(), user))
+            .collect::<HashMap<_, _>>();
+
+        let mut carried = HashMap::new();
+
+        for user in &mut self.users {
+            if let Some(old_user) = old.get(user.name()) {
+                if user.traffic().has_same_limits(old_user.traffic()) {
+                    *user = Arc::clone(old_user);
+                } else {
+                    user.traffic().carry_over(old_user.traffic());
+                }
+            }
+
+            carried.insert(user.name().to_owned(), user.clone());
+        }
+
+        for user in self
+            .tokens
+            .values_mut()
+            .chain(self.uuids.values_mut().map(|(_, user)| user))
+            .chain(self.certificate_names.values_mut())
+        {
+            *user = carried[user.name()].clone();
+        }
+    }
+}
+
+/// The current users, replaced as a whole on reload
+#[derive(Clone)]
+pub struct SharedUsers(Arc<RwLock<Arc<Users>>>);
+
+impl SharedUsers {
+    pub fn new(users: Users) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(users))))
+    }
+
+    pub fn load(&self) -> Arc<Users> {
+        self.0.read().clone()
+    }
+
+    pub fn store(&self, users: Users) {
+        *self.0.write() = Arc::new(users);
+    }
+}